@@ -0,0 +1,49 @@
+//! the example for how to decode hex string to message struct
+//!
+//! 转化过程 一个string 利用hex转换成 Vec<u8>
+//! u8数组再利用 bitcoin::deserialize 转换成相应的Address类型
+//! 要求 Address 实现 Decode和Encode方法 不然无法实现serialize 和 deserialize
+//!
+//! 从一个Address 类型转换为 hex string
+//! 先组装类型 然后利用 bitcoin库提供的serialize方法转换为数组 然后利用hex库的encode方法转为hex string
+//! serialize方法要求实现Encode trait
+//!
+//! hex_decode 0xxxxx---->Vec
+//! deserialize Vec -----> struct
+//!
+//! serialize struct ----> Vec
+//! hex_encode Vec ------> 0xxxxxxxx
+//!
+//! vec是在网络上传播的形式
+//!
+//! cargo run --example address
+
+use hex::decode as hex_decode;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin_p2p::message::address::Address;
+use bitcoin_p2p::ServiceFlags;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+
+fn decode_address() {
+    //test for address hex decode to structures
+    let from_sat = hex_decode("000000000000000000000000000000000000ffff7f000001208d").unwrap();
+    println!("The byte is {:?}", &from_sat);
+    let decode: Result<Address, _> = deserialize(&from_sat);
+    assert!(decode.is_ok());
+    let address = &decode.unwrap();
+    println!("The Address is {:?}", address);
+}
+
+fn encode_address() {
+    //test for address to encode hex
+    let s4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8333);
+    let a4 = Address::new(&s4, ServiceFlags::NONE);
+    let v4c = serialize(&a4);
+    let res = hex::encode(v4c);
+    println!("The hex string is {:?}", res);
+}
+
+fn main() {
+    decode_address();
+    encode_address();
+}
@@ -0,0 +1,58 @@
+//! 主要流程的拼装数据的流程
+//!
+//! 先解码一个真实的 version, 再按 example 里的顺序把一条完整的消息拼出来
+//! [https://en.bitcoin.it/wiki/Protocol_documentation#version]
+//!
+//! cargo run --example version
+
+use hex::decode as hex_decode;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin_p2p::message::address::Address;
+use bitcoin_p2p::message::command::CommandString;
+use bitcoin_p2p::message::version::VersionMessage;
+use bitcoin_p2p::message::sha_sha;
+use bitcoin_p2p::{RawMessage, Payload, NetworkParams, ServiceFlags};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::convert::TryFrom;
+
+fn main() {
+    // This message is from my satoshi node, morning of May 27 2014
+    let from_sat = hex_decode("721101000100000000000000e6e0845300000000010000000000000000000000000000000000ffff0000000000000100000000000000fd87d87eeb4364f22cf54dca59412db7208d47d920cffce83ee8102f5361746f7368693a302e392e39392f2c9f040001").unwrap();
+
+    let decode: VersionMessage = deserialize(&from_sat).unwrap();
+    println!("The version is {:#?}", decode);
+    assert_eq!(decode.version, 70002);
+    assert_eq!(decode.user_agent, "/Satoshi:0.9.99/".to_string());
+    assert_eq!(serialize(&decode), from_sat);
+
+    // 第一个是command 目标是编码成16进制的数组 形式和bitcoin Network protocol wiki上面表示的那样就行
+    let cs = CommandString::try_from("version").unwrap();
+    println!("The command is {:02x?}", serialize(&cs));
+
+    // 接下来是对payload 本身的编码
+    let remote = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 7)), 8333);
+    let version = VersionMessage::new(
+        70001,
+        ServiceFlags::NONE, //spv only
+        1415484102,
+        Address::new(&remote, ServiceFlags::NETWORK),
+        // sender is only dummy
+        Address::new(&remote, ServiceFlags::NETWORK),
+        0, //not used here
+        "/Bitcoin.org Example:0.9.3/".to_string(),
+        329107,
+        true,
+    );
+    let serialized_version = serialize(&version);
+    println!("The serialized_version is {:02x?}", &serialized_version);
+    println!("The len of serialized_version is {:?}", &serialized_version.len());
+
+    // Checksum is first 4 bytes of SHA256(SHA256(<payload>))
+    // 用 Verack 验证一下 空 payload 的 checksum 是 5df6e0e2
+    // [https://en.bitcoin.it/wiki/Protocol_documentation#verack]
+    println!("The checksum of verack is {:02x?}", sha_sha("".as_bytes()));
+
+    // 最后由 RawMessage 把 magic command length checksum payload 拼起来
+    let raw_version = RawMessage::new(&NetworkParams::mainnet(), cs, Payload::Version(version));
+    println!("The whole message is {:02x?}", raw_version.combine().unwrap());
+}
@@ -0,0 +1,897 @@
+//! Addresses of other nodes, for picking outbound connections
+//!
+//! 和 Bitcoin Core 的 addrman 一样分两张表
+//!     new     听说过但没连上过的地址, 1024 个桶, 桶由地址的网络组和来源的网络组决定
+//!             同一个来源网络组最多落进 64 个桶, 一个地址最多在 8 个桶里
+//!     tried   连上过的地址, 256 个桶, 桶由地址本身和它的网络组决定, 同一个网络组最多 8 个桶
+//! 每个桶 64 个位置, 位置满了就看原来那个是不是 "terrible" (太久没见, 一直连不上), 是就顶掉
+//!
+//! Bucket and position come from hashes keyed with a secret of our own, so a peer sending us addresses
+//! cannot predict where they land, and one network group can only fill a small part of the tables.
+//!
+//! 文件格式 (`save` / `load`)
+//!     "ADDR" + 格式版本(u32) + 网络 magic(u32) + key(32)
+//!     地址个数(var-int), 每个地址: addrv2 + 端口 + 服务 + 各个时间 + 尝试次数 + 来源 + 在 tried 里吗 + 所在的 new 桶
+//!     最后是前面所有字节的 SHA256(SHA256()) 的前四个字节
+//!
+//! Positions inside the buckets are not written down; with the same key they come out the same when loading.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use bitcoin::VarInt;
+use bitcoin::consensus::{serialize, Decodable, encode};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use crate::message::sha_sha;
+use crate::message::addrv2::{AddrV2, AddrV2Entry};
+use crate::message::services::ServiceFlags;
+use crate::netgroup::{addr_netgroup, addr_is_routable, name_netgroup};
+use crate::network::NetworkParams;
+use crate::store::StoreError;
+
+const FILE_MAGIC: &[u8; 4] = b"ADDR";
+const FORMAT_VERSION: u32 = 1;
+
+pub const NEW_BUCKET_COUNT: usize = 1024;
+pub const TRIED_BUCKET_COUNT: usize = 256;
+pub const BUCKET_SIZE: usize = 64;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+const NEW_BUCKETS_PER_ADDRESS: u32 = 8;
+
+/// Addresses not seen for this long are terrible
+const HORIZON: u32 = 30 * 24 * 60 * 60;
+/// Failed attempts before a never reached address is terrible
+const RETRIES: u32 = 3;
+/// Failed attempts in a row before an address not reached for `MIN_FAIL` is terrible
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL: u32 = 7 * 24 * 60 * 60;
+/// Addresses relayed by others are taken to be this much older than announced
+const TIME_PENALTY: u32 = 2 * 60 * 60;
+/// `connected` only updates the time of an address after this long
+const UPDATE_INTERVAL: u32 = 20 * 60;
+
+/// Where an address came from
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum AddrSource {
+    /// Received in `addr` or `addrv2` from the peer at this address
+    Peer(AddrV2),
+    /// Returned by the DNS seed of this name
+    DnsSeed(String),
+    /// Given by the user
+    Manual,
+}
+
+impl AddrSource {
+    /// The group buckets are picked by; a manual address is its own source
+    fn netgroup(&self, addr: &AddrV2) -> Vec<u8> {
+        match *self {
+            AddrSource::Peer(ref source) => addr_netgroup(source),
+            AddrSource::DnsSeed(ref name) => name_netgroup(name),
+            AddrSource::Manual => addr_netgroup(addr),
+        }
+    }
+}
+
+/// What the address manager knows about one address
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AddrInfo {
+    pub addr: AddrV2,
+    pub port: u16,
+    pub services: ServiceFlags,
+    /// Last time the node was seen, unix time
+    pub time: u32,
+    /// Where we first heard of the address
+    pub source: AddrSource,
+    /// Last connection attempt, 0 for never
+    pub last_try: u32,
+    /// Last successful connection, 0 for never
+    pub last_success: u32,
+    /// Failed attempts since the last success
+    pub attempts: u32,
+    tried: bool,
+    /// number of new buckets holding the address
+    new_refs: u32,
+}
+
+impl AddrInfo {
+    /// Where to connect to, for IPv4 and IPv6 addresses
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.addr.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+
+    /// Whether we ever connected to the address
+    pub fn is_tried(&self) -> bool {
+        self.tried
+    }
+
+    /// Not worth keeping: announced from the future, not seen for a month or failing again and again
+    pub fn is_terrible(&self, now: u32) -> bool {
+        // 一分钟之内刚试过的先留着
+        if self.last_try != 0 && self.last_try >= now.saturating_sub(60) {
+            return false;
+        }
+        if self.time > now + 10 * 60 {
+            return true;
+        }
+        if now.saturating_sub(self.time) > HORIZON {
+            return true;
+        }
+        if self.last_success == 0 && self.attempts >= RETRIES {
+            return true;
+        }
+        now.saturating_sub(self.last_success) > MIN_FAIL && self.attempts >= MAX_FAILURES
+    }
+
+    /// Relative chance of being selected: lower right after an attempt and after each failure
+    pub fn chance(&self, now: u32) -> f64 {
+        let mut chance = 1.0;
+        if now.saturating_sub(self.last_try) < 10 * 60 {
+            chance *= 0.01;
+        }
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+
+    fn key(&self) -> Vec<u8> {
+        address_key(&self.addr, self.port)
+    }
+
+    fn entry(&self) -> AddrV2Entry {
+        AddrV2Entry { time: self.time, services: self.services, addr: self.addr.clone(), port: self.port }
+    }
+}
+
+fn address_key(addr: &AddrV2, port: u16) -> Vec<u8> {
+    let mut key = serialize(addr);
+    key.extend_from_slice(&port.to_be_bytes());
+    key
+}
+
+/// New and tried addresses in their buckets
+///
+/// 用法
+///     let mut addrman = AddrMan::load("peers.dat", &network).unwrap_or_else(|_| AddrMan::new());
+///     addrman.add(&addrv2.0, &AddrSource::Peer(from), now);
+///     if let Some(remote) = addrman.select(false, now) {
+///         addrman.attempt(&remote, true, now);
+///         ... 连上了 addrman.good(&remote, now) ...
+///     }
+///     addrman.save("peers.dat", &network)?;
+///
+/// Times are unix times in seconds, passed in so the caller decides what "now" is.
+#[derive(Clone)]
+pub struct AddrMan {
+    key: [u8; 32],
+    entries: HashMap<u32, AddrInfo>,
+    ids: HashMap<(AddrV2, u16), u32>,
+    next_id: u32,
+    new_table: Vec<Option<u32>>,
+    tried_table: Vec<Option<u32>>,
+    new_count: TableCount,
+    tried_count: TableCount,
+}
+
+/// Entries in one of the tables: all of them, and the IPv4/IPv6 ones `select` can pick
+#[derive(Clone, Copy, Default, Debug)]
+struct TableCount {
+    all: usize,
+    ip: usize,
+}
+
+impl Default for AddrMan {
+    fn default() -> AddrMan {
+        AddrMan::with_key(rand::random())
+    }
+}
+
+impl AddrMan {
+    /// An empty address manager with a fresh random key
+    pub fn new() -> AddrMan {
+        AddrMan::default()
+    }
+
+    fn with_key(key: [u8; 32]) -> AddrMan {
+        AddrMan {
+            key,
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+            next_id: 0,
+            new_table: vec![None; NEW_BUCKET_COUNT * BUCKET_SIZE],
+            tried_table: vec![None; TRIED_BUCKET_COUNT * BUCKET_SIZE],
+            new_count: TableCount::default(),
+            tried_count: TableCount::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Addresses we never connected to
+    pub fn new_count(&self) -> usize {
+        self.new_count.all
+    }
+
+    /// Addresses we connected to at some point
+    pub fn tried_count(&self) -> usize {
+        self.tried_count.all
+    }
+
+    pub fn get(&self, remote: &SocketAddr) -> Option<&AddrInfo> {
+        self.ids.get(&socket_key(remote)).map(|id| &self.entries[id])
+    }
+
+    /// Add addresses heard of from `source`. Returns how many were new or landed in another bucket.
+    pub fn add(&mut self, entries: &[AddrV2Entry], source: &AddrSource, now: u32) -> usize {
+        entries.iter().filter(|entry| self.add_one(entry, source, now)).count()
+    }
+
+    /// Add an address given by the user
+    pub fn add_manual(&mut self, remote: SocketAddr, services: ServiceFlags, now: u32) -> bool {
+        let entry = AddrV2Entry { time: now, services, addr: AddrV2::from_ip(remote.ip()), port: remote.port() };
+        self.add_one(&entry, &AddrSource::Manual, now)
+    }
+
+    /// We are about to connect to `remote`. `count_failure` is false for attempts that say nothing
+    /// about the address, e.g. when our own network is down.
+    pub fn attempt(&mut self, remote: &SocketAddr, count_failure: bool, now: u32) {
+        if let Some(info) = self.info_mut(remote) {
+            info.last_try = now;
+            if count_failure {
+                info.attempts += 1;
+            }
+        }
+    }
+
+    /// The handshake with `remote` succeeded, move it to the tried table
+    pub fn good(&mut self, remote: &SocketAddr, now: u32) {
+        let id = match self.ids.get(&socket_key(remote)) {
+            Some(&id) => id,
+            None => return,
+        };
+        let info = self.entries.get_mut(&id).expect("ids and entries are kept in sync");
+        info.last_success = now;
+        info.last_try = now;
+        info.attempts = 0;
+        if !info.tried {
+            self.make_tried(id);
+        }
+    }
+
+    /// We are still connected to `remote`, it counts as seen now
+    pub fn connected(&mut self, remote: &SocketAddr, now: u32) {
+        if let Some(info) = self.info_mut(remote) {
+            if now.saturating_sub(info.time) > UPDATE_INTERVAL {
+                info.time = now;
+            }
+        }
+    }
+
+    /// The peer at `remote` told us its services in the handshake
+    pub fn set_services(&mut self, remote: &SocketAddr, services: ServiceFlags) {
+        if let Some(info) = self.info_mut(remote) {
+            info.services = services;
+        }
+    }
+
+    /// Pick an address to connect to: tried or new with even odds, favouring addresses that have not
+    /// failed recently. Only IPv4 and IPv6 addresses are picked.
+    pub fn select(&self, new_only: bool, now: u32) -> Option<SocketAddr> {
+        let mut rng = rand::thread_rng();
+        let tried = match (self.new_count.ip > 0, !new_only && self.tried_count.ip > 0) {
+            (false, false) => return None,
+            (true, true) => rng.gen::<bool>(),
+            (new, _) => !new,
+        };
+        let (table, bucket_count) = if tried {
+            (&self.tried_table, TRIED_BUCKET_COUNT)
+        } else {
+            (&self.new_table, NEW_BUCKET_COUNT)
+        };
+        // 和 Bitcoin Core 一样 随便挑一个桶, 从随便一个位置开始找第一个地址, 没选中就把门槛放宽一点
+        let mut factor = 1.0;
+        loop {
+            let bucket = &table[rng.gen_range(0, bucket_count) * BUCKET_SIZE..][..BUCKET_SIZE];
+            let start = rng.gen_range(0, BUCKET_SIZE);
+            let id = match (0..BUCKET_SIZE).find_map(|i| bucket[(start + i) % BUCKET_SIZE]) {
+                Some(id) => id,
+                None => continue,
+            };
+            let info = &self.entries[&id];
+            let remote = match info.socket_addr() {
+                Some(remote) => remote,
+                None => continue,
+            };
+            if rng.gen::<f64>() < factor * info.chance(now) {
+                return Some(remote);
+            }
+            factor *= 1.2;
+        }
+    }
+
+    /// Addresses to answer `getaddr` with: a random sample of at most `max_pct` percent of all
+    /// addresses and at most `max`, leaving out the terrible ones
+    pub fn addresses(&self, max: usize, max_pct: usize, now: u32) -> Vec<AddrV2Entry> {
+        let count = (self.entries.len() * max_pct / 100).min(max);
+        let mut infos: Vec<&AddrInfo> = self.entries.values().collect();
+        infos.shuffle(&mut rand::thread_rng());
+        infos.into_iter().filter(|info| !info.is_terrible(now)).take(count).map(AddrInfo::entry).collect()
+    }
+
+    /// Every known address, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &AddrInfo> {
+        self.entries.values()
+    }
+
+    fn info_mut(&mut self, remote: &SocketAddr) -> Option<&mut AddrInfo> {
+        let id = *self.ids.get(&socket_key(remote))?;
+        self.entries.get_mut(&id)
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut data = self.key.to_vec();
+        for part in parts {
+            data.extend_from_slice(part);
+        }
+        let hash = hmac_sha256::Hash::hash(&hmac_sha256::Hash::hash(&data));
+        let mut first = [0u8; 8];
+        first.copy_from_slice(&hash[..8]);
+        u64::from_le_bytes(first)
+    }
+
+    fn tried_bucket(&self, info: &AddrInfo) -> usize {
+        let inner = self.hash(&[&info.key()]) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash(&[&addr_netgroup(&info.addr), &inner.to_le_bytes()]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    fn new_bucket(&self, info: &AddrInfo, source_group: &[u8]) -> usize {
+        let inner = self.hash(&[&addr_netgroup(&info.addr), source_group]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.hash(&[source_group, &inner.to_le_bytes()]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Index into the new or tried table of `info` in `bucket`
+    fn slot(&self, info: &AddrInfo, new: bool, bucket: usize) -> usize {
+        let table = if new { b"N" } else { b"K" };
+        let position = self.hash(&[table, &(bucket as u32).to_le_bytes(), &info.key()]) % BUCKET_SIZE as u64;
+        bucket * BUCKET_SIZE + position as usize
+    }
+
+    fn add_one(&mut self, entry: &AddrV2Entry, source: &AddrSource, now: u32) -> bool {
+        if !addr_is_routable(&entry.addr) {
+            return false;
+        }
+        // 别人转告的地址 当它比宣称的旧一点
+        let penalty = match *source {
+            AddrSource::Peer(ref from) if *from != entry.addr => TIME_PENALTY,
+            _ => 0,
+        };
+
+        let id = match self.ids.get(&(entry.addr.clone(), entry.port)) {
+            Some(&id) => {
+                let info = self.entries.get_mut(&id).expect("ids and entries are kept in sync");
+                // 在线的节点一小时更新一次时间, 其它的一天一次
+                let online = now.saturating_sub(entry.time) < 24 * 60 * 60;
+                let update_interval = if online { 60 * 60 } else { 24 * 60 * 60 };
+                if entry.time != 0 && (info.time == 0 || info.time < entry.time.saturating_sub(update_interval + penalty)) {
+                    info.time = entry.time.saturating_sub(penalty);
+                }
+                info.services |= entry.services;
+                if entry.time == 0 || (info.time != 0 && entry.time <= info.time) {
+                    return false;
+                }
+                if info.tried || info.new_refs == NEW_BUCKETS_PER_ADDRESS {
+                    return false;
+                }
+                // 已经在 n 个桶里了, 再进一个桶的机会是 1/2^n
+                if info.new_refs > 0 && rand::thread_rng().gen_range(0, 1u32 << info.new_refs) != 0 {
+                    return false;
+                }
+                id
+            }
+            None => {
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                self.ids.insert((entry.addr.clone(), entry.port), id);
+                self.entries.insert(id, AddrInfo {
+                    addr: entry.addr.clone(),
+                    port: entry.port,
+                    services: entry.services,
+                    time: entry.time.saturating_sub(penalty),
+                    source: source.clone(),
+                    last_try: 0,
+                    last_success: 0,
+                    attempts: 0,
+                    tried: false,
+                    new_refs: 0,
+                });
+                id
+            }
+        };
+
+        let info = &self.entries[&id];
+        let bucket = self.new_bucket(info, &source.netgroup(&info.addr));
+        let slot = self.slot(info, true, bucket);
+        self.place_new(id, slot, now)
+    }
+
+    /// Put `id` into the new table at `slot`, pushing out a terrible or duplicate entry there.
+    /// An entry that ends up in no bucket at all is forgotten.
+    fn place_new(&mut self, id: u32, slot: usize, now: u32) -> bool {
+        match self.new_table[slot] {
+            Some(existing) if existing == id => return false,
+            Some(existing) => {
+                let other = &self.entries[&existing];
+                let refs = self.entries[&id].new_refs;
+                if other.is_terrible(now) || (other.new_refs > 1 && refs == 0) {
+                    self.clear_new(slot);
+                } else {
+                    if refs == 0 && !self.entries[&id].tried {
+                        self.forget(id);
+                    }
+                    return false;
+                }
+            }
+            None => {}
+        }
+        self.new_table[slot] = Some(id);
+        let info = self.entries.get_mut(&id).expect("ids and entries are kept in sync");
+        info.new_refs += 1;
+        if info.new_refs == 1 {
+            self.count(id, false, true);
+        }
+        true
+    }
+
+    fn clear_new(&mut self, slot: usize) {
+        let id = match self.new_table[slot].take() {
+            Some(id) => id,
+            None => return,
+        };
+        let info = self.entries.get_mut(&id).expect("ids and entries are kept in sync");
+        info.new_refs -= 1;
+        if info.new_refs == 0 {
+            self.count(id, false, false);
+            self.forget(id);
+        }
+    }
+
+    /// Keep `new_count` or `tried_count` in step with `id` entering or leaving a table
+    fn count(&mut self, id: u32, tried: bool, added: bool) {
+        let ip = self.entries[&id].addr.ip().is_some() as usize;
+        let count = if tried { &mut self.tried_count } else { &mut self.new_count };
+        if added {
+            count.all += 1;
+            count.ip += ip;
+        } else {
+            count.all -= 1;
+            count.ip -= ip;
+        }
+    }
+
+    fn forget(&mut self, id: u32) {
+        if let Some(info) = self.entries.remove(&id) {
+            self.ids.remove(&(info.addr, info.port));
+        }
+    }
+
+    /// Move `id` from the new table to the tried table. Whoever had its place in the tried table goes
+    /// back to the new table.
+    fn make_tried(&mut self, id: u32) {
+        let info = self.entries[&id].clone();
+        if info.new_refs > 0 {
+            for bucket in 0..NEW_BUCKET_COUNT {
+                let slot = self.slot(&info, true, bucket);
+                if self.new_table[slot] == Some(id) {
+                    self.new_table[slot] = None;
+                }
+            }
+            self.count(id, false, false);
+        }
+        let slot = self.slot(&info, false, self.tried_bucket(&info));
+        if let Some(evicted) = self.tried_table[slot].take() {
+            self.count(evicted, true, false);
+            let other = self.entries.get_mut(&evicted).expect("ids and entries are kept in sync");
+            other.tried = false;
+            other.new_refs = 0;
+            let other = other.clone();
+            let bucket = self.new_bucket(&other, &other.source.netgroup(&other.addr));
+            let new_slot = self.slot(&other, true, bucket);
+            self.clear_new(new_slot);
+            self.new_table[new_slot] = Some(evicted);
+            self.entries.get_mut(&evicted).expect("still there").new_refs = 1;
+            self.count(evicted, false, true);
+        }
+        self.tried_table[slot] = Some(id);
+        self.count(id, true, true);
+        let info = self.entries.get_mut(&id).expect("ids and entries are kept in sync");
+        info.tried = true;
+        info.new_refs = 0;
+    }
+
+    /// Write all addresses to `path`, replacing the file only once the new one is complete
+    pub fn save<P: AsRef<Path>>(&self, path: P, network: &NetworkParams) -> Result<(), StoreError> {
+        let path = path.as_ref();
+        let mut buckets: HashMap<u32, Vec<u16>> = HashMap::new();
+        for (slot, id) in self.new_table.iter().enumerate() {
+            if let Some(id) = id {
+                buckets.entry(*id).or_default().push((slot / BUCKET_SIZE) as u16);
+            }
+        }
+
+        let mut data = FILE_MAGIC.to_vec();
+        data.extend(serialize(&FORMAT_VERSION));
+        data.extend(serialize(&network.magic.to_u32()));
+        data.extend_from_slice(&self.key);
+        data.extend(serialize(&VarInt(self.entries.len() as u64)));
+        for (id, info) in &self.entries {
+            data.extend(serialize(&info.addr));
+            data.extend(serialize(&info.port));
+            data.extend(serialize(&info.services));
+            data.extend(serialize(&info.time));
+            data.extend(serialize(&info.last_try));
+            data.extend(serialize(&info.last_success));
+            data.extend(serialize(&info.attempts));
+            match info.source {
+                AddrSource::Manual => data.push(0),
+                AddrSource::Peer(ref from) => {
+                    data.push(1);
+                    data.extend(serialize(from));
+                }
+                AddrSource::DnsSeed(ref name) => {
+                    data.push(2);
+                    data.extend(serialize(name));
+                }
+            }
+            data.push(info.tried as u8);
+            let buckets = buckets.remove(id).unwrap_or_default();
+            data.extend(serialize(&VarInt(buckets.len() as u64)));
+            for bucket in buckets {
+                data.extend(serialize(&bucket));
+            }
+        }
+        let checksum = sha_sha(&data);
+        data.extend(checksum);
+
+        // 先写临时文件再改名, 中途崩溃也不会留下写了一半的文件
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Read the addresses `save` wrote for `network`
+    pub fn load<P: AsRef<Path>>(path: P, network: &NetworkParams) -> Result<AddrMan, StoreError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        if data.len() < 12 + 32 + 4 || &data[..4] != FILE_MAGIC {
+            return Err(StoreError::BadFormat);
+        }
+        let (body, checksum) = data.split_at(data.len() - 4);
+        if sha_sha(body)[..] != checksum[..] {
+            return Err(StoreError::BadFormat);
+        }
+        let mut d = Cursor::new(&body[4..]);
+        let version: u32 = decode(&mut d)?;
+        if version != FORMAT_VERSION {
+            return Err(StoreError::UnsupportedVersion(version));
+        }
+        let actual: u32 = decode(&mut d)?;
+        if actual != network.magic.to_u32() {
+            return Err(StoreError::WrongNetwork { expected: network.magic.to_u32(), actual });
+        }
+        let mut key = [0u8; 32];
+        d.read_exact(&mut key)?;
+
+        let mut addrman = AddrMan::with_key(key);
+        let VarInt(count) = decode(&mut d)?;
+        for _ in 0..count {
+            let addr: AddrV2 = decode(&mut d)?;
+            let port = decode(&mut d)?;
+            let services = decode(&mut d)?;
+            let time = decode(&mut d)?;
+            let last_try = decode(&mut d)?;
+            let last_success = decode(&mut d)?;
+            let attempts = decode(&mut d)?;
+            let source = match decode::<u8>(&mut d)? {
+                0 => AddrSource::Manual,
+                1 => AddrSource::Peer(decode(&mut d)?),
+                2 => AddrSource::DnsSeed(decode(&mut d)?),
+                _ => return Err(StoreError::BadFormat),
+            };
+            let tried = decode::<u8>(&mut d)? != 0;
+            let VarInt(bucket_count) = decode(&mut d)?;
+            if bucket_count > NEW_BUCKETS_PER_ADDRESS as u64 {
+                return Err(StoreError::BadFormat);
+            }
+            let mut buckets = Vec::new();
+            for _ in 0..bucket_count {
+                buckets.push(decode::<u16>(&mut d)?);
+            }
+            let info = AddrInfo { addr, port, services, time, source, last_try, last_success, attempts, tried: false, new_refs: 0 };
+            addrman.restore(info, tried, &buckets);
+        }
+        if d.position() as usize != body.len() - 4 {
+            return Err(StoreError::BadFormat);
+        }
+        Ok(addrman)
+    }
+
+    /// Put a loaded entry back where it was; if the place is taken, into the new bucket of its source
+    fn restore(&mut self, info: AddrInfo, tried: bool, buckets: &[u16]) {
+        if !addr_is_routable(&info.addr) || self.ids.contains_key(&(info.addr.clone(), info.port)) {
+            return;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.ids.insert((info.addr.clone(), info.port), id);
+        self.entries.insert(id, info.clone());
+
+        if tried {
+            let slot = self.slot(&info, false, self.tried_bucket(&info));
+            if self.tried_table[slot].is_none() {
+                self.tried_table[slot] = Some(id);
+                self.count(id, true, true);
+                self.entries.get_mut(&id).expect("just inserted").tried = true;
+                return;
+            }
+        }
+        let mut buckets: Vec<usize> = buckets.iter().map(|&bucket| bucket as usize)
+            .filter(|&bucket| bucket < NEW_BUCKET_COUNT)
+            .collect();
+        if buckets.is_empty() {
+            buckets.push(self.new_bucket(&info, &info.source.netgroup(&info.addr)));
+        }
+        for bucket in buckets.into_iter().take(NEW_BUCKETS_PER_ADDRESS as usize) {
+            let slot = self.slot(&info, true, bucket);
+            if self.new_table[slot].is_none() {
+                self.new_table[slot] = Some(id);
+                let entry = self.entries.get_mut(&id).expect("just inserted");
+                entry.new_refs += 1;
+                if entry.new_refs == 1 {
+                    self.count(id, false, true);
+                }
+            }
+        }
+        if self.entries[&id].new_refs == 0 {
+            self.forget(id);
+        }
+    }
+}
+
+impl fmt::Debug for AddrMan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AddrMan")
+            .field("new", &self.new_count.all)
+            .field("tried", &self.tried_count.all)
+            .finish()
+    }
+}
+
+fn socket_key(remote: &SocketAddr) -> (AddrV2, u16) {
+    (AddrV2::from_ip(remote.ip()), remote.port())
+}
+
+fn decode<T: Decodable>(d: &mut Cursor<&[u8]>) -> Result<T, StoreError> {
+    T::consensus_decode(d).map_err(|e| match e {
+        encode::Error::Io(e) if e.kind() != io::ErrorKind::UnexpectedEof => StoreError::Io(e),
+        _ => StoreError::BadFormat,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+    use crate::message::{MAINNET, TESTNET};
+
+    const NOW: u32 = 1_700_000_000;
+
+    fn ip(a: u8, b: u8, c: u8, d: u8) -> AddrV2 {
+        AddrV2::from_ip(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+    }
+
+    fn entry(addr: AddrV2, time: u32) -> AddrV2Entry {
+        AddrV2Entry { time, services: ServiceFlags::NETWORK, addr, port: 8333 }
+    }
+
+    fn remote(addr: &AddrV2) -> SocketAddr {
+        SocketAddr::new(addr.ip().unwrap(), 8333)
+    }
+
+    fn source() -> AddrSource {
+        AddrSource::Peer(ip(5, 6, 7, 8))
+    }
+
+    // 还没放进表里的地址, 用来算它会落在哪
+    fn info(addr: AddrV2) -> AddrInfo {
+        AddrInfo {
+            addr, port: 8333, services: ServiceFlags::NETWORK, time: NOW, source: source(),
+            last_try: 0, last_success: 0, attempts: 0, tried: false, new_refs: 0,
+        }
+    }
+
+    fn new_slot(addrman: &AddrMan, addr: &AddrV2) -> usize {
+        let info = info(addr.clone());
+        addrman.slot(&info, true, addrman.new_bucket(&info, &source().netgroup(addr)))
+    }
+
+    fn id(addrman: &AddrMan, addr: &AddrV2) -> Option<u32> {
+        addrman.ids.get(&(addr.clone(), 8333)).cloned()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bitcoin_p2p-{}-{}.dat", name, std::process::id()))
+    }
+
+    #[test]
+    fn new_placement() {
+        let mut addrman = AddrMan::with_key([7; 32]);
+        let addr = ip(1, 2, 3, 4);
+        assert_eq!(addrman.add(&[entry(addr.clone(), NOW)], &source(), NOW), 1);
+        assert_eq!(addrman.new_table[new_slot(&addrman, &addr)], id(&addrman, &addr));
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (1, 0));
+        // 别人转告的地址时间要减掉两个小时
+        assert_eq!(addrman.get(&remote(&addr)).unwrap().time, NOW - TIME_PENALTY);
+
+        // 一个来源网络组的地址最多落进 64 个桶
+        let buckets: HashSet<usize> = (11..=255u8).flat_map(|a| (0..4u8).map(move |b| ip(a, b, 1, 1)))
+            .map(|addr| new_slot(&addrman, &addr) / BUCKET_SIZE)
+            .collect();
+        assert!(buckets.len() <= NEW_BUCKETS_PER_SOURCE_GROUP as usize);
+        // 不可路由的地址不要
+        assert_eq!(addrman.add(&[entry(ip(192, 168, 1, 1), NOW)], &source(), NOW), 0);
+    }
+
+    #[test]
+    fn tried_placement() {
+        let mut addrman = AddrMan::with_key([7; 32]);
+        let addr = ip(1, 2, 3, 4);
+        addrman.add(&[entry(addr.clone(), NOW)], &source(), NOW);
+        addrman.good(&remote(&addr), NOW);
+
+        let tried = addrman.get(&remote(&addr)).unwrap().clone();
+        assert!(tried.is_tried());
+        assert_eq!(tried.last_success, NOW);
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (0, 1));
+        assert!(addrman.new_table.iter().all(Option::is_none));
+        let slot = addrman.slot(&tried, false, addrman.tried_bucket(&tried));
+        assert_eq!(addrman.tried_table[slot], id(&addrman, &addr));
+
+        assert_eq!(addrman.select(false, NOW), Some(remote(&addr)));
+        assert_eq!(addrman.select(true, NOW), None);
+
+        // 一个网络组最多 8 个 tried 桶
+        let buckets: HashSet<usize> = (0..=255u8).map(|c| addrman.tried_bucket(&info(ip(1, 2, c, 4)))).collect();
+        assert!(buckets.len() <= TRIED_BUCKETS_PER_GROUP as usize);
+    }
+
+    /// Two addresses from `source()` landing on the same new slot
+    fn colliding(addrman: &AddrMan) -> (AddrV2, AddrV2) {
+        let mut seen = HashMap::new();
+        for a in 11..=255u8 {
+            for b in 0..=255u8 {
+                let addr = ip(a, b, 1, 1);
+                if let Some(other) = seen.insert(new_slot(addrman, &addr), addr.clone()) {
+                    return (other, addr);
+                }
+            }
+        }
+        panic!("no collision");
+    }
+
+    #[test]
+    fn terrible_entry_is_replaced() {
+        let mut addrman = AddrMan::with_key([7; 32]);
+        let (old, young) = colliding(&addrman);
+        // 两个月没见过
+        addrman.add(&[entry(old.clone(), NOW - 60 * 24 * 60 * 60)], &source(), NOW);
+        assert!(addrman.get(&remote(&old)).unwrap().is_terrible(NOW));
+        assert_eq!(addrman.add(&[entry(young.clone(), NOW)], &source(), NOW), 1);
+        assert!(addrman.get(&remote(&old)).is_none());
+        assert_eq!(addrman.new_table[new_slot(&addrman, &young)], id(&addrman, &young));
+        assert_eq!(addrman.len(), 1);
+    }
+
+    #[test]
+    fn good_entry_is_kept() {
+        let mut addrman = AddrMan::with_key([7; 32]);
+        let (first, second) = colliding(&addrman);
+        addrman.add(&[entry(first.clone(), NOW)], &source(), NOW);
+        assert_eq!(addrman.add(&[entry(second.clone(), NOW)], &source(), NOW), 0);
+        assert!(addrman.get(&remote(&first)).is_some());
+        assert!(addrman.get(&remote(&second)).is_none());
+        assert_eq!(addrman.len(), 1);
+    }
+
+    #[test]
+    fn select_skips_other_networks() {
+        let mut addrman = AddrMan::with_key([7; 32]);
+        addrman.add(&[entry(AddrV2::TorV3([3; 32]), NOW)], &source(), NOW);
+        assert_eq!(addrman.new_count(), 1);
+        assert_eq!(addrman.select(false, NOW), None);
+
+        let addr = ip(1, 2, 3, 4);
+        addrman.add(&[entry(addr.clone(), NOW)], &source(), NOW);
+        for _ in 0..20 {
+            assert_eq!(addrman.select(true, NOW), Some(remote(&addr)));
+        }
+    }
+
+    fn filled() -> AddrMan {
+        let mut addrman = AddrMan::new();
+        for a in 11..31u8 {
+            addrman.add(&[entry(ip(a, 1, 2, 3), NOW)], &source(), NOW);
+        }
+        addrman.add(&[entry(ip(40, 1, 2, 3), NOW)], &AddrSource::DnsSeed("seed.example".to_owned()), NOW);
+        addrman.add_manual(remote(&ip(41, 1, 2, 3)), ServiceFlags::NETWORK, NOW);
+        addrman.good(&remote(&ip(11, 1, 2, 3)), NOW);
+        addrman.attempt(&remote(&ip(12, 1, 2, 3)), true, NOW);
+        addrman
+    }
+
+    #[test]
+    fn save_and_load() {
+        let addrman = filled();
+        let path = temp_path("addrman-roundtrip");
+        addrman.save(&path, &NetworkParams::mainnet()).unwrap();
+        let loaded = AddrMan::load(&path, &NetworkParams::mainnet()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.key, addrman.key);
+        assert_eq!((loaded.len(), loaded.new_count(), loaded.tried_count()),
+                   (addrman.len(), addrman.new_count(), addrman.tried_count()));
+        for info in addrman.iter() {
+            let remote = info.socket_addr().unwrap();
+            let other = loaded.get(&remote).unwrap();
+            assert_eq!(*other, *info);
+            // 同一个 key 位置也一样
+            let (new, tried) = (&loaded.new_table, &loaded.tried_table);
+            let id = loaded.ids[&(info.addr.clone(), info.port)];
+            if info.is_tried() {
+                assert_eq!(tried[loaded.slot(info, false, loaded.tried_bucket(info))], Some(id));
+            } else {
+                assert!(new.contains(&Some(id)));
+            }
+        }
+    }
+
+    #[test]
+    fn load_rejects_wrong_files() {
+        let path = temp_path("addrman-bad");
+        filled().save(&path, &NetworkParams::mainnet()).unwrap();
+        assert!(matches!(AddrMan::load(&path, &NetworkParams::testnet()),
+                         Err(StoreError::WrongNetwork { expected: TESTNET, actual: MAINNET })));
+
+        let good = fs::read(&path).unwrap();
+        let mut data = good.clone();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(AddrMan::load(&path, &NetworkParams::mainnet()), Err(StoreError::BadFormat)));
+
+        // 版本不对, checksum 是对的
+        let mut data = good[..good.len() - 4].to_vec();
+        data[4..8].copy_from_slice(&serialize(&2u32));
+        let checksum = sha_sha(&data);
+        data.extend(checksum);
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(AddrMan::load(&path, &NetworkParams::mainnet()), Err(StoreError::UnsupportedVersion(2))));
+
+        fs::write(&path, &good[..20]).unwrap();
+        assert!(matches!(AddrMan::load(&path, &NetworkParams::mainnet()), Err(StoreError::BadFormat)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+//! Block header chain: validates headers against the network rules and follows the chain with the most work
+//!
+//! 校验的内容和 Bitcoin Core 的 ContextualCheckBlockHeader 一样
+//!     父区块必须已知
+//!     nBits 必须等于按难度调整规则算出来的值
+//!     hash 必须满足 nBits 的目标
+//!     时间戳要大于前 11 个区块的中位数 且不能超过现在两小时以上
+//!     检查点高度上的 hash 必须对得上
+//!
+//! Every valid header is kept, also on side chains, so a branch that overtakes the current one
+//! in cumulative work becomes the best chain right away.
+
+use std::{fmt, error};
+use bitcoin::{BitcoinHash, BlockHeader};
+use bitcoin::util::uint::Uint256;
+use crate::message::inventory::BlockHash;
+use crate::network::NetworkParams;
+use crate::store::{HeaderStore, MemoryStore, ChainEvent, StoreError};
+
+/// Number of previous blocks whose median timestamp a new block must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+/// How far a block timestamp may be ahead of our clock, in seconds
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+/// How far the first block of a period may go back in time (BIP94)
+const MAX_TIMEWARP: u32 = 600;
+
+/// A validated header together with its position in the chain
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ChainEntry {
+    pub header: BlockHeader,
+    pub hash: BlockHash,
+    pub height: u32,
+    /// Sum of the work of this header and all its ancestors
+    pub chain_work: Uint256,
+}
+
+/// Reasons a header is rejected
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum HeaderError {
+    /// The previous header is not known
+    UnknownParent(BlockHash),
+    /// `bits` differs from what the difficulty adjustment requires
+    BadTarget {
+        /// Required compact target
+        expected: u32,
+        /// Compact target in the header
+        actual: u32,
+    },
+    /// The hash does not meet the target
+    BadProofOfWork(BlockHash),
+    /// The timestamp is not after the median of the previous blocks
+    TimeTooOld {
+        /// Median time of the previous blocks
+        median: u32,
+        /// Timestamp of the header
+        time: u32,
+    },
+    /// The timestamp is too far in the future
+    TimeTooNew {
+        /// Latest acceptable timestamp
+        max: u32,
+        /// Timestamp of the header
+        time: u32,
+    },
+    /// The first block of a difficulty period goes back too far in time (BIP94)
+    TimeWarp {
+        /// Timestamp of the previous header
+        previous: u32,
+        /// Timestamp of the header
+        time: u32,
+    },
+    /// The header conflicts with a checkpoint
+    CheckpointMismatch {
+        height: u32,
+        /// Checkpointed hash
+        expected: BlockHash,
+        /// Hash of the header
+        actual: BlockHash,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::UnknownParent(ref hash) => write!(f, "unknown previous block {}", hash),
+            HeaderError::BadTarget { expected, actual } => write!(f, "bad target: expected {:#010x}, actual {:#010x}", expected, actual),
+            HeaderError::BadProofOfWork(ref hash) => write!(f, "block {} does not meet its target", hash),
+            HeaderError::TimeTooOld { median, time } => write!(f, "timestamp {} not after median time {}", time, median),
+            HeaderError::TimeTooNew { max, time } => write!(f, "timestamp {} after {}", time, max),
+            HeaderError::TimeWarp { previous, time } => write!(f, "timestamp {} too far before previous block {}", time, previous),
+            HeaderError::CheckpointMismatch { height, ref expected, ref actual } =>
+                write!(f, "block {} at height {} conflicts with checkpoint {}", actual, height, expected),
+        }
+    }
+}
+
+impl error::Error for HeaderError {}
+
+/// What `HeaderChain::accept` did with a header
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Accepted {
+    /// The header was already known
+    Duplicate,
+    /// The header is now the tip of the best chain, possibly after switching branches
+    BestChain(Vec<ChainEvent>),
+    /// The header was stored on a branch with less work than the best chain
+    SideChain,
+}
+
+impl ChainEntry {
+    /// The entry of the genesis block of `params`
+    pub fn genesis(params: &NetworkParams) -> ChainEntry {
+        ChainEntry {
+            header: params.genesis_header,
+            hash: params.genesis_hash,
+            height: 0,
+            chain_work: params.genesis_header.work(),
+        }
+    }
+}
+
+/// All valid headers of one network, starting from its genesis block
+///
+/// 用法
+///     let mut chain = HeaderChain::new(NetworkParams::mainnet());
+///     chain.accept(header, now)?;
+///     GetHeaders::new(chain.locator(), Default::default())
+///
+/// `HeaderChain::with_store` keeps the headers somewhere else than in memory, see `FileStore`.
+pub struct HeaderChain<S = MemoryStore> {
+    params: NetworkParams,
+    store: S,
+}
+
+impl HeaderChain<MemoryStore> {
+    pub fn new(params: NetworkParams) -> HeaderChain<MemoryStore> {
+        let store = MemoryStore::new(ChainEntry::genesis(&params));
+        HeaderChain { params, store }
+    }
+}
+
+impl<S: HeaderStore> HeaderChain<S> {
+    /// Continue from the headers already in `store`, which must belong to the network of `params`
+    pub fn with_store(params: NetworkParams, store: S) -> HeaderChain<S> {
+        HeaderChain { params, store }
+    }
+
+    pub fn params(&self) -> &NetworkParams {
+        &self.params
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Tip of the chain with the most work
+    pub fn tip(&self) -> &ChainEntry {
+        self.store.tip()
+    }
+
+    /// Height of the best chain
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    /// Any known header, on the best chain or not
+    pub fn get_by_hash(&self, hash: &BlockHash) -> Option<&ChainEntry> {
+        self.store.get_by_hash(hash)
+    }
+
+    /// The header at `height` on the best chain
+    pub fn get_by_height(&self, height: u32) -> Option<&ChainEntry> {
+        self.store.get_by_height(height)
+    }
+
+    /// Whether `hash` is part of the best chain
+    pub fn is_active(&self, hash: &BlockHash) -> bool {
+        self.store.is_active(hash)
+    }
+
+    /// Block locator for the tip of the best chain
+    pub fn locator(&self) -> Vec<BlockHash> {
+        self.store.locator()
+    }
+
+    /// Block locator starting at `hash`, see `HeaderStore::locator_from`
+    pub fn locator_from(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        self.store.locator_from(hash)
+    }
+
+    /// Write the accepted headers through to the store
+    pub fn flush(&mut self) -> Result<(), StoreError> {
+        self.store.flush()
+    }
+
+    /// Validate `header` and add it to the chain. `now` is the current unix time, used for the future timestamp check.
+    pub fn accept(&mut self, header: BlockHeader, now: u32) -> Result<Accepted, HeaderError> {
+        let hash = header.bitcoin_hash();
+        if self.store.get_by_hash(&hash).is_some() {
+            return Ok(Accepted::Duplicate);
+        }
+        let prev = self.store.get_by_hash(&header.prev_blockhash).ok_or(HeaderError::UnknownParent(header.prev_blockhash))?;
+        let height = prev.height + 1;
+
+        let expected = self.next_bits(prev, &header);
+        if header.bits != expected {
+            return Err(HeaderError::BadTarget { expected, actual: header.bits });
+        }
+        if header.validate_pow(&header.target()).is_err() {
+            return Err(HeaderError::BadProofOfWork(hash));
+        }
+
+        let median = self.median_time_past(prev);
+        if header.time <= median {
+            return Err(HeaderError::TimeTooOld { median, time: header.time });
+        }
+        let max = now.saturating_add(MAX_FUTURE_BLOCK_TIME);
+        if header.time > max {
+            return Err(HeaderError::TimeTooNew { max, time: header.time });
+        }
+        if self.params.enforce_bip94 && height % self.params.difficulty_adjustment_interval() == 0
+            && header.time < prev.header.time.saturating_sub(MAX_TIMEWARP) {
+            return Err(HeaderError::TimeWarp { previous: prev.header.time, time: header.time });
+        }
+
+        if let Some(expected) = self.params.checkpoint(height) {
+            if expected != hash {
+                return Err(HeaderError::CheckpointMismatch { height, expected, actual: hash });
+            }
+        }
+
+        let entry = ChainEntry {
+            header,
+            hash,
+            height,
+            chain_work: prev.chain_work + header.work(),
+        };
+        let events = self.store.insert(entry);
+        if events.is_empty() {
+            Ok(Accepted::SideChain)
+        } else {
+            Ok(Accepted::BestChain(events))
+        }
+    }
+
+    fn parent<'a>(&'a self, entry: &'a ChainEntry) -> Option<&'a ChainEntry> {
+        if entry.height == 0 {
+            None
+        } else {
+            self.store.get_by_hash(&entry.header.prev_blockhash)
+        }
+    }
+
+    /// Median timestamp of `entry` and the 10 blocks before it
+    fn median_time_past(&self, entry: &ChainEntry) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut next = Some(entry);
+        while let Some(entry) = next {
+            if times.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            times.push(entry.header.time);
+            next = self.parent(entry);
+        }
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// The compact target the block after `prev` must carry, as in Bitcoin Core's GetNextWorkRequired
+    fn next_bits(&self, prev: &ChainEntry, header: &BlockHeader) -> u32 {
+        let params = &self.params;
+        let pow_limit_bits = BlockHeader::compact_target_from_u256(&params.pow_limit);
+        let interval = params.difficulty_adjustment_interval();
+        let height = prev.height + 1;
+
+        if height % interval != 0 {
+            if params.allow_min_difficulty_blocks {
+                // testnet: 20 分钟没出块就可以用最低难度
+                if header.time > prev.header.time + params.pow_target_spacing * 2 {
+                    return pow_limit_bits;
+                }
+                // 否则沿用最近一个不是最低难度的区块
+                let mut entry = prev;
+                while entry.height % interval != 0 && entry.header.bits == pow_limit_bits {
+                    entry = self.parent(entry).expect("the genesis block is at a period boundary");
+                }
+                return entry.header.bits;
+            }
+            return prev.header.bits;
+        }
+
+        if params.no_pow_retargeting {
+            return prev.header.bits;
+        }
+        let first = self.store.ancestor(prev, height - interval);
+        let timespan = params.pow_target_timespan as i64;
+        let actual = (prev.header.time as i64 - first.header.time as i64).clamp(timespan / 4, timespan * 4);
+        let base = if params.enforce_bip94 { first.header.target() } else { prev.header.target() };
+        let mut target = base.mul_u32(actual as u32) / Uint256::from_u64(timespan as u64).expect("fits");
+        if target > params.pow_limit {
+            target = params.pow_limit;
+        }
+        BlockHeader::compact_target_from_u256(&target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POW_LIMIT_BITS: u32 = 0x1d00ffff;
+
+    /// 不做校验直接放进 store 的链, 只有 `times` 和 `bits` 里给出的高度用指定的值
+    fn unchecked_chain(params: NetworkParams, tip: u32, times: &[(u32, u32)], bits: &[(u32, u32)]) -> HeaderChain {
+        let mut chain = HeaderChain::new(params);
+        for height in 1..=tip {
+            let prev = chain.tip().clone();
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash: prev.hash,
+                merkle_root: Default::default(),
+                time: times.iter().find(|t| t.0 == height).map_or(prev.header.time + 600, |t| t.1),
+                bits: bits.iter().find(|b| b.0 == height).map_or(prev.header.bits, |b| b.1),
+                nonce: 0,
+            };
+            let entry = ChainEntry { header, hash: header.bitcoin_hash(), height, chain_work: prev.chain_work + header.work() };
+            chain.store.insert(entry);
+        }
+        chain
+    }
+
+    fn next_header(chain: &HeaderChain, delay: u32) -> BlockHeader {
+        let tip = chain.tip();
+        BlockHeader { prev_blockhash: tip.hash, time: tip.header.time + delay, ..tip.header }
+    }
+
+    // 数据来自 Bitcoin Core 的 pow_tests
+    #[test]
+    fn mainnet_retarget() {
+        // 30240 到 32255 用了 1261130161..1262152739, 32256 的难度
+        let chain = unchecked_chain(NetworkParams::mainnet(), 32255, &[(30240, 1261130161), (32255, 1262152739)], &[]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 600)), 0x1d00d86a);
+
+        // 第一个周期太慢, 不能低于 pow_limit
+        let chain = unchecked_chain(NetworkParams::mainnet(), 2015, &[(2015, 1233061996)], &[]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 600)), POW_LIMIT_BITS);
+        // 周期中间沿用上一个区块的难度, 不管隔了多久
+        let chain = unchecked_chain(NetworkParams::mainnet(), 2014, &[], &[(2014, 0x1c0ffff0)]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 3600)), 0x1c0ffff0);
+    }
+
+    #[test]
+    fn mainnet_retarget_limits() {
+        // 太快: 最多变难 4 倍
+        let chain = unchecked_chain(NetworkParams::mainnet(), 68543, &[(66528, 1279008237), (68543, 1279297671)], &[(68543, 0x1c05a3f4)]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 600)), 0x1c0168fd);
+        // 太慢: 最多变容易 4 倍
+        let chain = unchecked_chain(NetworkParams::mainnet(), 46367, &[(44352, 1263163443), (46367, 1269211443)], &[(46367, 0x1c387f6f)]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 600)), 0x1d00e1fd);
+    }
+
+    #[test]
+    fn testnet_min_difficulty() {
+        let bits = 0x1c0fffff;
+        let chain = unchecked_chain(NetworkParams::testnet(), 2019, &[], &[(2016, bits), (2018, POW_LIMIT_BITS)]);
+        // 20 分钟以上没出块可以用最低难度
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 20 * 60 + 1)), POW_LIMIT_BITS);
+        // 否则跳过最低难度的区块, 用 2017 的难度
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 20 * 60)), bits);
+
+        // 往回找最多到周期的第一个区块为止
+        let chain = unchecked_chain(NetworkParams::testnet(), 2019, &[], &[(2016, POW_LIMIT_BITS)]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 60)), POW_LIMIT_BITS);
+
+        // mainnet 没有这条规则
+        let chain = unchecked_chain(NetworkParams::mainnet(), 2019, &[], &[(2016, bits), (2018, POW_LIMIT_BITS)]);
+        assert_eq!(chain.next_bits(chain.tip(), &next_header(&chain, 60)), POW_LIMIT_BITS);
+    }
+
+    // regtest 的目标很宽, 挖矿只要试几个 nonce
+    const REGTEST_BITS: u32 = 0x207fffff;
+    const NOW: u32 = 1_700_000_000;
+
+    fn mine(prev: &BlockHash, time: u32, bits: u32, valid: bool) -> BlockHeader {
+        let mut header = BlockHeader { version: 4, prev_blockhash: *prev, merkle_root: Default::default(), time, bits, nonce: 0 };
+        while header.validate_pow(&header.target()).is_ok() != valid {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    fn regtest_chain(blocks: u32) -> HeaderChain {
+        let mut chain = HeaderChain::new(NetworkParams::regtest());
+        for _ in 0..blocks {
+            let tip = chain.tip().clone();
+            let header = mine(&tip.hash, tip.header.time + 60, REGTEST_BITS, true);
+            assert!(matches!(chain.accept(header, NOW), Ok(Accepted::BestChain(_))));
+        }
+        chain
+    }
+
+    #[test]
+    fn accepts_valid_headers() {
+        let mut chain = regtest_chain(3);
+        assert_eq!(chain.height(), 3);
+        let header = chain.tip().header;
+        assert_eq!(chain.accept(header, NOW), Ok(Accepted::Duplicate));
+
+        let unknown = mine(&Default::default(), NOW, REGTEST_BITS, true);
+        assert_eq!(chain.accept(unknown, NOW), Err(HeaderError::UnknownParent(Default::default())));
+    }
+
+    #[test]
+    fn rejects_bad_bits_and_proof_of_work() {
+        let mut chain = regtest_chain(1);
+        let tip = chain.tip().clone();
+        let header = mine(&tip.hash, tip.header.time + 60, 0x207ffffe, true);
+        assert_eq!(chain.accept(header, NOW), Err(HeaderError::BadTarget { expected: REGTEST_BITS, actual: 0x207ffffe }));
+
+        let header = mine(&tip.hash, tip.header.time + 60, REGTEST_BITS, false);
+        assert_eq!(chain.accept(header, NOW), Err(HeaderError::BadProofOfWork(header.bitcoin_hash())));
+        assert_eq!(chain.height(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        let mut chain = regtest_chain(11);
+        let tip = chain.tip().clone();
+        // 后 11 个区块 (1..=11) 的时间中位数是第 6 个
+        let median = chain.get_by_height(6).unwrap().header.time;
+        let header = mine(&tip.hash, median, REGTEST_BITS, true);
+        assert_eq!(chain.accept(header, NOW), Err(HeaderError::TimeTooOld { median, time: median }));
+        let header = mine(&tip.hash, median + 1, REGTEST_BITS, true);
+        assert!(chain.accept(header, NOW).is_ok());
+
+        let tip = chain.tip().clone();
+        let max = NOW + MAX_FUTURE_BLOCK_TIME;
+        let header = mine(&tip.hash, max + 1, REGTEST_BITS, true);
+        assert_eq!(chain.accept(header, NOW), Err(HeaderError::TimeTooNew { max, time: max + 1 }));
+    }
+
+    #[test]
+    fn rejects_checkpoint_mismatch() {
+        let mut params = NetworkParams::regtest();
+        let expected = BlockHash::default();
+        params.checkpoints = vec![(2, expected)];
+        let mut chain = HeaderChain::new(params);
+        let genesis = chain.tip().hash;
+        let first = mine(&genesis, chain.tip().header.time + 60, REGTEST_BITS, true);
+        chain.accept(first, NOW).unwrap();
+
+        let second = mine(&first.bitcoin_hash(), first.time + 60, REGTEST_BITS, true);
+        assert_eq!(chain.accept(second, NOW), Err(HeaderError::CheckpointMismatch { height: 2, expected, actual: second.bitcoin_hash() }));
+    }
+}
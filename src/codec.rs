@@ -0,0 +1,412 @@
+//! tokio codec for the bitcoin wire protocol
+//!
+//! 用法
+//!     let framed = Framed::new(stream, MessageCodec::new(&NetworkParams::mainnet()));
+//!     framed 既是 RawMessage 的 Stream 也是 Sink
+//!
+//! A single `read` on the socket can return half a message or several messages at once, so the
+//! decoder keeps everything in the `BytesMut` buffer of `Framed` and only hands out complete frames.
+//! Bytes in front of the expected magic are treated as garbage and skipped until the next magic.
+//!
+//! A frame that fails to decode is dropped and reported as an error that is not `Error::is_fatal`,
+//! so the stream can keep being polled.
+//! Frames breaking the limits in `message::limits` are reported as `Error::Misbehavior`;
+//! an oversized length is caught from the header alone, before any buffer is grown for it.
+
+use std::collections::HashMap;
+use bytes::{Buf, BytesMut};
+use bitcoin::BitcoinHash;
+use bitcoin::consensus::{serialize, deserialize};
+use bitcoin_hashes::sha256d;
+use tokio_util::codec::{Decoder, Encoder};
+use log::debug;
+use crate::error::Error;
+use crate::message::{RawMessage, Payload, Magic, MessageHeader, HEADER_SIZE};
+use crate::message::inventory::{Inventory, MAX_INV_SIZE};
+use crate::message::limits;
+use crate::message::registry::MessageRegistry;
+use crate::network::NetworkParams;
+
+/// Most unanswered requests remembered; answers to the ones beyond fall back to `MessageCodec::witness`
+const MAX_REQUESTED: usize = MAX_INV_SIZE;
+
+/// Encoder/Decoder pair over `RawMessage` for one network
+///
+/// The codec remembers the entries of every `getdata` it encodes. A `block` or `tx` answering a plain
+/// MSG_BLOCK or MSG_TX must not carry witness data; one answering MSG_WITNESS_BLOCK, MSG_WITNESS_TX or MSG_WTX may.
+/// The transactions following a `merkleblock` count as requested without witness, like Bitcoin Core sends them.
+pub struct MessageCodec {
+    magic: Magic,
+    magic_bytes: Vec<u8>,
+    registry: MessageRegistry,
+    witness: bool,
+    /// hash -> requested with witness data, for the getdata entries not answered yet
+    requested: HashMap<sha256d::Hash, bool>,
+}
+
+impl MessageCodec {
+    pub fn new(network: &NetworkParams) -> Self {
+        MessageCodec::with_registry(network, MessageRegistry::default())
+    }
+
+    /// A codec that also decodes the message types registered in `registry`
+    pub fn with_registry(network: &NetworkParams, registry: MessageRegistry) -> Self {
+        let magic = network.magic;
+        MessageCodec {
+            magic,
+            magic_bytes: serialize(&magic.to_u32()),
+            registry,
+            witness: true,
+            requested: HashMap::new(),
+        }
+    }
+
+    /// Whether a `block` or `tx` we did not request may carry witness data.
+    /// Defaults to `true`; once it is `false`, an unrequested `block` or `tx` with witness data fails to decode.
+    /// Answers to our `getdata` follow the inventory type of their request instead.
+    pub fn set_witness(&mut self, witness: bool) {
+        self.witness = witness;
+    }
+
+    pub fn witness(&self) -> bool {
+        self.witness
+    }
+
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    pub fn registry(&self) -> &MessageRegistry {
+        &self.registry
+    }
+
+    fn request(&mut self, hash: sha256d::Hash, witness: bool) {
+        if self.requested.len() < MAX_REQUESTED || self.requested.contains_key(&hash) {
+            self.requested.insert(hash, witness);
+        }
+    }
+
+    /// 记下 getdata 里每一项要不要 witness
+    fn requesting(&mut self, inventory: &[Inventory]) {
+        for inv in inventory {
+            match *inv {
+                Inventory::Tx(hash) | Inventory::Block(hash) | Inventory::FilteredBlock(hash) => self.request(hash, false),
+                Inventory::WitnessTx(hash) | Inventory::WitnessBlock(hash) | Inventory::WTx(hash) => self.request(hash, true),
+                // cmpctblock 和不认识的类型 回的不是 block/tx
+                Inventory::CompactBlock(_) | Inventory::Unknown(..) => {}
+            }
+        }
+    }
+
+    /// 收到回应之后去掉对应的请求, witness 和请求的类型对不上就是 `Error::ProtocolViolation`
+    fn answered(&mut self, message: &RawMessage) -> Result<(), Error> {
+        let witness = match *message.payload() {
+            Payload::Tx(ref tx) => {
+                // MSG_TX/MSG_WITNESS_TX 按 txid 要, MSG_WTX 按 wtxid 要
+                let by_txid = self.requested.remove(&tx.txid());
+                let by_wtxid = self.requested.remove(&tx.bitcoin_hash());
+                by_txid.or(by_wtxid).unwrap_or(self.witness)
+            }
+            Payload::Block(ref block) => self.requested.remove(&block.bitcoin_hash()).unwrap_or(self.witness),
+            Payload::MerkleBlock(ref merkle_block) => {
+                if self.requested.remove(&merkle_block.header.bitcoin_hash()).is_some() {
+                    for txid in merkle_block.extract_matches().unwrap_or_default() {
+                        self.request(txid, false);
+                    }
+                }
+                return Ok(());
+            }
+            Payload::NotFound(ref notfound) => {
+                for inv in &notfound.0 {
+                    self.requested.remove(inv.hash());
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if !witness && message.payload().has_witness() {
+            return Err(Error::ProtocolViolation(format!("{} with witness data, requested without", message.command().as_str())));
+        }
+        Ok(())
+    }
+
+    /// 丢掉 magic 之前的垃圾数据, 返回 buffer 是否以 magic 开头
+    fn resync(&self, src: &mut BytesMut) -> bool {
+        match src.windows(self.magic_bytes.len()).position(|window| window == &self.magic_bytes[..]) {
+            Some(0) => true,
+            Some(pos) => {
+                debug!("skipping {} bytes of garbage before magic", pos);
+                src.advance(pos);
+                true
+            }
+            None => {
+                // 末尾可能是半个 magic 先留着
+                let keep = self.magic_bytes.len() - 1;
+                if src.len() > keep {
+                    let skip = src.len() - keep;
+                    debug!("skipping {} bytes of garbage, no magic found", skip);
+                    src.advance(skip);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = RawMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RawMessage>, Error> {
+        if !self.resync(src) || src.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let header: MessageHeader = match deserialize(&src[..HEADER_SIZE]) {
+            Ok(header) => header,
+            Err(e) => {
+                // 命令不合法 和 checksum 不对一样只跳过 magic
+                src.advance(self.magic_bytes.len());
+                return Err(e.into());
+            }
+        };
+        // 先检查长度再 reserve, 对方随便填个 4GB 也不会分配内存
+        // 没法跳过这么长的数据 连接只能放弃
+        limits::check_length(&header.command, header.length as usize)?;
+        let total = HEADER_SIZE + header.length as usize;
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+
+        match RawMessage::parse_inner(&src[..total], Some(self.magic), &self.registry, true) {
+            Ok((message, consumed)) => {
+                src.advance(consumed);
+                self.answered(&message)?;
+                Ok(Some(message))
+            }
+            Err(e @ Error::ChecksumMismatch { .. }) => {
+                // 可能是垃圾数据里碰巧出现了 magic, 只跳过 magic 本身 下次从后面重新找
+                src.advance(self.magic_bytes.len());
+                Err(e)
+            }
+            Err(e) => {
+                // checksum 对得上说明帧的边界没问题 整帧丢掉
+                src.advance(total);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Encoder for MessageCodec {
+    type Item = RawMessage;
+    type Error = Error;
+
+    fn encode(&mut self, item: RawMessage, dst: &mut BytesMut) -> Result<(), Error> {
+        let raw_bytes = item.combine()?;
+        if let Payload::GetData(ref getdata) = *item.payload() {
+            self.requesting(&getdata.0);
+        }
+        dst.extend_from_slice(&raw_bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Block, BlockHeader, OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::Hash;
+    use crate::message::command::CommandString;
+    use crate::message::getdata::GetData;
+    use crate::message::merkleblock::MerkleBlock;
+    use crate::message::notfound::NotFound;
+    use crate::relay::{TxRelay, without_witness};
+
+    fn getdata(n: u8) -> RawMessage {
+        let inventory = vec![Inventory::Tx(sha256d::Hash::from_inner([n; 32]))];
+        RawMessage::new(&NetworkParams::mainnet(), CommandString("getdata".to_owned()), Payload::GetData(GetData(inventory)))
+    }
+
+    fn decode_all(codec: &mut MessageCodec, src: &mut BytesMut) -> Vec<RawMessage> {
+        let mut messages = Vec::new();
+        while let Some(message) = codec.decode(src).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn split_frame() {
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let bytes = getdata(1).combine().unwrap();
+        let mut src = BytesMut::new();
+        // 一个字节一个字节地来, 最后一个字节到之前什么都不出
+        for &byte in &bytes[..bytes.len() - 1] {
+            src.extend_from_slice(&[byte]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None);
+        }
+        src.extend_from_slice(&bytes[bytes.len() - 1..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(getdata(1)));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn batched_frames() {
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let mut src = BytesMut::new();
+        for n in 1..=3 {
+            src.extend_from_slice(&getdata(n).combine().unwrap());
+        }
+        // 第四条只到一半
+        let fourth = getdata(4).combine().unwrap();
+        src.extend_from_slice(&fourth[..HEADER_SIZE + 3]);
+        assert_eq!(decode_all(&mut codec, &mut src), vec![getdata(1), getdata(2), getdata(3)]);
+        src.extend_from_slice(&fourth[HEADER_SIZE + 3..]);
+        assert_eq!(decode_all(&mut codec, &mut src), vec![getdata(4)]);
+    }
+
+    #[test]
+    fn resync_past_garbage() {
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[0x00, 0x11, 0xf9, 0xbe, 0x22, 0x33]);
+        src.extend_from_slice(&getdata(1).combine().unwrap());
+        assert_eq!(decode_all(&mut codec, &mut src), vec![getdata(1)]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn keeps_trailing_partial_magic() {
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let bytes = getdata(1).combine().unwrap();
+        let mut src = BytesMut::new();
+        // 垃圾后面跟着 magic 的前三个字节
+        src.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05]);
+        src.extend_from_slice(&bytes[..3]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(&src[..], &bytes[..3]);
+        src.extend_from_slice(&bytes[3..]);
+        assert_eq!(decode_all(&mut codec, &mut src), vec![getdata(1)]);
+    }
+
+    #[test]
+    fn skips_bad_checksum() {
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let mut src = BytesMut::new();
+        let mut bad = getdata(1).combine().unwrap();
+        *bad.last_mut().unwrap() ^= 1;
+        src.extend_from_slice(&bad);
+        src.extend_from_slice(&getdata(2).combine().unwrap());
+        match codec.decode(&mut src) {
+            Err(e @ Error::ChecksumMismatch { .. }) => assert!(!e.is_fatal()),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+        assert_eq!(decode_all(&mut codec, &mut src), vec![getdata(2)]);
+    }
+
+    fn segwit_tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![vec![0x30, 0x44], vec![0x02, 0x21]],
+            }],
+            output: vec![TxOut { value: 50_000, script_pubkey: Script::new() }],
+        }
+    }
+
+    /// 编码一个 getdata, 让 codec 记下请求
+    fn request(codec: &mut MessageCodec, inventory: Vec<Inventory>) {
+        let message = RawMessage::new(&NetworkParams::mainnet(), CommandString("getdata".to_owned()), Payload::GetData(GetData(inventory)));
+        codec.encode(message, &mut BytesMut::new()).unwrap();
+    }
+
+    fn receive(codec: &mut MessageCodec, payload: Payload) -> Result<Option<RawMessage>, Error> {
+        let message = RawMessage::new(&NetworkParams::mainnet(), payload.command(), payload);
+        codec.decode(&mut BytesMut::from(&message.combine().unwrap()[..]))
+    }
+
+    fn assert_rejected(result: Result<Option<RawMessage>, Error>) {
+        match result {
+            Err(Error::ProtocolViolation(_)) => {}
+            other => panic!("expected a protocol violation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tx_witness_follows_the_request() {
+        let tx = segwit_tx();
+        let stripped = without_witness(&tx);
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+
+        // MSG_TX 要的不能带 witness, 请求用掉了就没了
+        request(&mut codec, vec![Inventory::Tx(tx.txid())]);
+        assert_rejected(receive(&mut codec, Payload::Tx(tx.clone())));
+        request(&mut codec, vec![Inventory::Tx(tx.txid())]);
+        assert!(receive(&mut codec, Payload::Tx(stripped.clone())).unwrap().is_some());
+
+        request(&mut codec, vec![Inventory::WitnessTx(tx.txid())]);
+        assert!(receive(&mut codec, Payload::Tx(tx.clone())).unwrap().is_some());
+        request(&mut codec, vec![Inventory::WTx(tx.bitcoin_hash())]);
+        assert!(receive(&mut codec, Payload::Tx(tx.clone())).unwrap().is_some());
+        assert!(codec.requested.is_empty());
+
+        // 没要过的按 set_witness
+        assert!(receive(&mut codec, Payload::Tx(tx.clone())).unwrap().is_some());
+        codec.set_witness(false);
+        assert_rejected(receive(&mut codec, Payload::Tx(tx.clone())));
+        request(&mut codec, vec![Inventory::WitnessTx(tx.txid())]);
+        assert!(receive(&mut codec, Payload::Tx(tx.clone())).unwrap().is_some());
+
+        // notfound 之后请求也没了
+        request(&mut codec, vec![Inventory::WitnessTx(tx.txid())]);
+        receive(&mut codec, Payload::NotFound(NotFound(vec![Inventory::WitnessTx(tx.txid())]))).unwrap();
+        assert_rejected(receive(&mut codec, Payload::Tx(tx)));
+    }
+
+    #[test]
+    fn block_witness_follows_the_request() {
+        let tx = segwit_tx();
+        let header = BlockHeader { version: 1, prev_blockhash: Default::default(), merkle_root: tx.txid(), time: 0, bits: 0, nonce: 0 };
+        let block = Block { header, txdata: vec![tx.clone()] };
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+
+        request(&mut codec, vec![Inventory::Block(block.bitcoin_hash())]);
+        assert_rejected(receive(&mut codec, Payload::Block(block.clone())));
+        request(&mut codec, vec![Inventory::WitnessBlock(block.bitcoin_hash())]);
+        assert!(receive(&mut codec, Payload::Block(block)).unwrap().is_some());
+
+        // merkleblock 后面跟着的 tx 和 Bitcoin Core 一样不带 witness
+        let merkle_block = MerkleBlock { header, total_transactions: 1, hashes: vec![tx.txid()], flags: vec![1] };
+        request(&mut codec, vec![Inventory::FilteredBlock(header.bitcoin_hash())]);
+        assert!(receive(&mut codec, Payload::MerkleBlock(merkle_block)).unwrap().is_some());
+        assert_rejected(receive(&mut codec, Payload::Tx(tx.clone())));
+    }
+
+    #[test]
+    fn witness_peer_asking_for_plain_tx() {
+        // 对方提供 NODE_WITNESS, 但是用 MSG_TX 要, 回的就是不带 witness 的字节
+        let network = NetworkParams::mainnet();
+        let tx = segwit_tx();
+        let relay = TxRelay::new(&network);
+        relay.announce(tx.clone());
+
+        let mut theirs = MessageCodec::new(&network);
+        assert!(theirs.witness());
+        let getdata = GetData(vec![Inventory::Tx(tx.txid())]);
+        request(&mut theirs, getdata.0.clone());
+        let replies = relay.handle_getdata(&getdata);
+        assert_eq!(replies.len(), 1);
+        let bytes = replies[0].combine().unwrap();
+        assert_eq!(&bytes[HEADER_SIZE..], &serialize(&without_witness(&tx))[..]);
+        assert_ne!(&bytes[HEADER_SIZE..], &serialize(&tx)[..]);
+
+        let received = theirs.decode(&mut BytesMut::from(&bytes[..])).unwrap().unwrap();
+        assert_eq!(received.payload(), &Payload::Tx(without_witness(&tx)));
+    }
+}
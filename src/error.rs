@@ -0,0 +1,201 @@
+//! The error type shared by the whole crate
+//!
+//! 编码/解码, codec, 握手, keepalive 和同步都返回同一个 `Error`, 调用者可以直接按原因 match
+//!     单条消息有问题 (checksum 不对, 解码失败) 连接还能继续用, 见 `is_fatal`
+//!     其它的错误之后这个连接就不能再用了
+//!
+//! Errors that stand on their own, like an invalid block header or a broken header store, keep their
+//! own types and are wrapped here, so `source()` leads to the details.
+
+use std::{io, fmt, error};
+use bitcoin::consensus::encode;
+use crate::chain::HeaderError;
+use crate::handshake::HandshakeError;
+use crate::message::command::CommandError;
+use crate::message::limits::Misbehavior;
+use crate::store::StoreError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the socket failed
+    Io(io::Error),
+    /// A message could not be consensus encoded or decoded
+    Encode(encode::Error),
+    /// Not enough bytes yet; `needed` is the size of the whole message so far as it is known
+    ShortBuffer {
+        /// Bytes required
+        needed: usize,
+        /// Bytes available
+        available: usize,
+    },
+    /// The magic does not belong to any network we know, or not to the one we expected
+    UnknownMagic(u32),
+    /// The checksum in the header does not match the payload
+    ChecksumMismatch {
+        /// Checksum announced in the header
+        expected: [u8; 4],
+        /// Checksum calculated over the payload
+        actual: [u8; 4],
+    },
+    /// The peer broke one of the protocol limits; the caller should disconnect it
+    Misbehavior(Misbehavior),
+    /// The command of a message to send is not a valid command string
+    InvalidCommand(CommandError),
+    /// The address has no socket address, e.g. a Tor v2 onion address in an `addr` entry
+    UnsupportedAddress,
+    /// The peer was rejected during the handshake
+    Handshake(HandshakeError),
+    /// The peer sent an invalid block header
+    Header(HeaderError),
+    /// The header store or the address file failed
+    Store(StoreError),
+    /// The peer did not answer in time: handshake, pong or headers
+    Timeout,
+    /// The peer broke the protocol in a way that is not covered by a more specific error
+    ProtocolViolation(String),
+    /// The peer closed the connection
+    Disconnected,
+}
+
+impl Error {
+    /// `false` if the error only concerns a single message and the connection can still be used
+    pub fn is_fatal(&self) -> bool {
+        !matches!(*self, Error::Encode(_)
+            | Error::UnknownMagic(_)
+            | Error::ChecksumMismatch { .. }
+            | Error::InvalidCommand(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Encode(ref e) => write!(f, "encoding failed: {}", e),
+            Error::ShortBuffer { needed, available } =>
+                write!(f, "short buffer: need {} bytes, have {}", needed, available),
+            Error::UnknownMagic(magic) => write!(f, "unknown network magic: {:#010x}", magic),
+            Error::ChecksumMismatch { expected, actual } =>
+                write!(f, "checksum mismatch: expected {}, actual {}", hex::encode(expected), hex::encode(actual)),
+            Error::Misbehavior(ref e) => write!(f, "peer misbehaved: {}", e),
+            Error::InvalidCommand(ref e) => write!(f, "invalid command: {}", e),
+            Error::UnsupportedAddress => write!(f, "address cannot be connected to"),
+            Error::Handshake(ref e) => write!(f, "handshake failed: {}", e),
+            Error::Header(ref e) => write!(f, "invalid header: {}", e),
+            Error::Store(ref e) => write!(f, "{}", e),
+            Error::Timeout => write!(f, "timed out"),
+            Error::ProtocolViolation(ref reason) => write!(f, "protocol violation: {}", reason),
+            Error::Disconnected => write!(f, "peer disconnected"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Encode(ref e) => Some(e),
+            Error::InvalidCommand(ref e) => Some(e),
+            Error::Misbehavior(ref e) => Some(e),
+            Error::Handshake(ref e) => Some(e),
+            Error::Header(ref e) => Some(e),
+            Error::Store(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Error {
+        Error::Encode(e)
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(e: CommandError) -> Error {
+        Error::InvalidCommand(e)
+    }
+}
+
+impl From<Misbehavior> for Error {
+    fn from(e: Misbehavior) -> Error {
+        Error::Misbehavior(e)
+    }
+}
+
+impl From<HandshakeError> for Error {
+    fn from(e: HandshakeError) -> Error {
+        Error::Handshake(e)
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(e: HeaderError) -> Error {
+        Error::Header(e)
+    }
+}
+
+impl From<StoreError> for Error {
+    fn from(e: StoreError) -> Error {
+        Error::Store(e)
+    }
+}
+
+// Decodable/Encodable 只能返回 encode::Error, 尽量映射到 rust-bitcoin 已有的错误
+impl From<Error> for encode::Error {
+    fn from(e: Error) -> encode::Error {
+        match e {
+            Error::Io(e) => encode::Error::Io(e),
+            Error::Encode(e) => e,
+            Error::ShortBuffer { .. } => encode::Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Error::UnknownMagic(magic) => encode::Error::UnknownNetworkMagic(magic),
+            Error::ChecksumMismatch { expected, actual } => encode::Error::InvalidChecksum { expected, actual },
+            Error::Misbehavior(Misbehavior::OversizedMessage { size, max, .. }) =>
+                encode::Error::OversizedVectorAllocation { requested: size, max },
+            e => encode::Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::command::CommandString;
+
+    #[test]
+    fn fatal_errors() {
+        let command = CommandString("block".to_owned());
+        let cases = vec![
+            (Error::Io(io::ErrorKind::ConnectionReset.into()), true),
+            (Error::Encode(encode::Error::ParseFailed("bad")), false),
+            (Error::ShortBuffer { needed: 24, available: 3 }, true),
+            (Error::UnknownMagic(0x1234_5678), false),
+            (Error::ChecksumMismatch { expected: [1, 2, 3, 4], actual: [5, 6, 7, 8] }, false),
+            (Error::Misbehavior(Misbehavior::OversizedMessage { command, size: 4_000_001, max: 4_000_000 }), true),
+            (Error::InvalidCommand(CommandError::TooLong(13)), false),
+            (Error::UnsupportedAddress, true),
+            (Error::Handshake(HandshakeError::SelfConnection), true),
+            (Error::Header(HeaderError::UnknownParent(Default::default())), true),
+            (Error::Store(StoreError::BadFormat), true),
+            (Error::Timeout, true),
+            (Error::ProtocolViolation("unrequested block".to_owned()), true),
+            (Error::Disconnected, true),
+        ];
+        for (e, fatal) in cases {
+            // 新加的变体要在这里登记
+            match e {
+                Error::Io(_) | Error::Encode(_) | Error::ShortBuffer { .. } | Error::UnknownMagic(_)
+                | Error::ChecksumMismatch { .. } | Error::Misbehavior(_) | Error::InvalidCommand(_)
+                | Error::UnsupportedAddress | Error::Handshake(_) | Error::Header(_) | Error::Store(_)
+                | Error::Timeout | Error::ProtocolViolation(_) | Error::Disconnected => {}
+            }
+            assert_eq!(e.is_fatal(), fatal, "{}", e);
+        }
+    }
+}
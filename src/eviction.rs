@@ -0,0 +1,177 @@
+//! Choosing an inbound peer to disconnect when all inbound slots are taken
+//!
+//! 和 Bitcoin Core 的 SelectNodeToEvict 一样, 先保护几类难以伪造的节点, 剩下的里面再挑
+//!     网络组 (按随机 key 排序) 排最后的 4 个
+//!     ping 最小的 8 个
+//!     最近给我们发了交易的 4 个
+//!     不转发交易, 最近发了区块的 8 个 (block-relay-only)
+//!     最近发了区块的 4 个
+//!     剩下的一半按连接时长保护, 其中最多一半留给本机连进来的
+//!     最后在连接数最多的网络组里断开最新的那个
+//!
+//! An attacker would have to beat honest peers in all of these at once to push them out.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// What eviction looks at for one inbound peer
+#[derive(Clone, Debug)]
+pub struct EvictionCandidate {
+    pub address: SocketAddr,
+    /// When the connection was accepted
+    pub connected: Instant,
+    /// Fastest ping round trip, `None` before the first pong
+    pub min_ping: Option<Duration>,
+    /// When the peer last sent us a block or merkleblock; headers alone do not count
+    pub last_block: Option<Instant>,
+    /// When the peer last sent us a transaction
+    pub last_tx: Option<Instant>,
+    /// The peer asked for transactions in its `version`
+    pub relay_txs: bool,
+    /// The peer offers `ServiceFlags::NETWORK`
+    pub relevant_services: bool,
+    /// See `netgroup::netgroup`
+    pub netgroup: Vec<u8>,
+    /// The network group hashed with a key of our own, so peers cannot tell which groups get protected
+    pub keyed_netgroup: u64,
+    /// The peer connected from this machine
+    pub local: bool,
+}
+
+/// The peer to disconnect, `None` if every candidate is protected
+pub fn select_to_evict(mut candidates: Vec<EvictionCandidate>) -> Option<SocketAddr> {
+    protect(&mut candidates, 4, |c| c.keyed_netgroup, |_| true);
+    protect(&mut candidates, 8, |c| Reverse(c.min_ping.unwrap_or(Duration::from_secs(u64::MAX))), |_| true);
+    protect(&mut candidates, 4, |c| (c.last_tx, c.relay_txs, Reverse(c.connected)), |_| true);
+    protect(&mut candidates, 8, |c| (c.last_block, Reverse(c.connected)), |c| !c.relay_txs && c.relevant_services);
+    protect(&mut candidates, 4, |c| (c.last_block, c.relevant_services, Reverse(c.connected)), |_| true);
+
+    // 连得最久的 一半
+    let by_uptime = candidates.len() / 2;
+    let local = protect(&mut candidates, by_uptime / 2, |c| Reverse(c.connected), |c| c.local);
+    protect(&mut candidates, by_uptime - local, |c| Reverse(c.connected), |_| true);
+
+    // 连接最多的网络组, 一样多就看谁的连接最新
+    let mut groups: HashMap<&[u8], (usize, Instant)> = HashMap::new();
+    for c in &candidates {
+        let group = groups.entry(&c.netgroup[..]).or_insert((0, c.connected));
+        group.0 += 1;
+        group.1 = group.1.max(c.connected);
+    }
+    let (&group, _) = groups.iter().max_by_key(|&(_, &count_newest)| count_newest)?;
+    candidates.iter()
+        .filter(|c| &c.netgroup[..] == group)
+        .max_by_key(|c| c.connected)
+        .map(|c| c.address)
+}
+
+/// Sort by `key` and take up to `k` of the candidates matching `eligible` from the top end out of the running.
+/// Returns how many were taken.
+fn protect<K, F, P>(candidates: &mut Vec<EvictionCandidate>, k: usize, key: F, eligible: P) -> usize
+    where K: Ord, F: Fn(&EvictionCandidate) -> K, P: Fn(&EvictionCandidate) -> bool
+{
+    candidates.sort_by_key(|c| key(c));
+    let mut protected = 0;
+    let mut i = candidates.len();
+    while i > 0 && protected < k {
+        i -= 1;
+        if eligible(&candidates[i]) {
+            candidates.remove(i);
+            protected += 1;
+        }
+    }
+    protected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 40 个普通的节点, 各在各的网络组, 编号越大连得越晚, ping 越慢; 再加一个最新的 `newest`, 什么都不占
+    fn population() -> (Vec<EvictionCandidate>, SocketAddr, Instant) {
+        let start = Instant::now();
+        let candidate = |n: u8, keyed_netgroup: u64| EvictionCandidate {
+            address: SocketAddr::from(([10, n, 0, 1], 8333)),
+            connected: start + Duration::from_secs(n as u64),
+            min_ping: Some(Duration::from_millis(100 + n as u64)),
+            last_block: None,
+            last_tx: None,
+            relay_txs: true,
+            relevant_services: true,
+            netgroup: vec![n],
+            keyed_netgroup,
+            local: false,
+        };
+        let mut candidates: Vec<_> = (1..=40).map(|n| candidate(n, n as u64)).collect();
+        let mut newest = candidate(41, 0);
+        newest.min_ping = None;
+        let address = newest.address;
+        candidates.insert(0, newest);
+        (candidates, address, start)
+    }
+
+    fn evict_with<F: Fn(&mut EvictionCandidate)>(change: F) -> Option<SocketAddr> {
+        let (mut candidates, _, _) = population();
+        change(&mut candidates[0]);
+        select_to_evict(candidates)
+    }
+
+    #[test]
+    fn newest_unprotected_peer_goes() {
+        let (candidates, newest, _) = population();
+        assert_eq!(select_to_evict(candidates), Some(newest));
+    }
+
+    #[test]
+    fn each_protection_keeps_the_peer() {
+        let (_, newest, start) = population();
+        let recent = start + Duration::from_secs(100);
+        let kept = |evicted: Option<SocketAddr>| evicted.is_some() && evicted != Some(newest);
+
+        assert!(kept(evict_with(|c| c.keyed_netgroup = 1000)), "netgroup");
+        assert!(kept(evict_with(|c| c.min_ping = Some(Duration::from_millis(1)))), "ping");
+        assert!(kept(evict_with(|c| c.last_tx = Some(recent))), "tx");
+        assert!(kept(evict_with(|c| c.last_block = Some(recent))), "block");
+        assert!(kept(evict_with(|c| c.connected = start)), "longest connected");
+        assert!(kept(evict_with(|c| c.local = true)), "local");
+    }
+
+    #[test]
+    fn block_relay_only_peers_have_their_own_slots() {
+        let (_, newest, start) = population();
+        let with_blocks = |relay_txs: bool| {
+            let (mut candidates, _, _) = population();
+            // 四个更近发了区块的把普通的区块名额占满
+            for c in candidates.iter_mut().filter(|c| (20..24).contains(&c.netgroup[0])) {
+                c.last_block = Some(start + Duration::from_secs(200));
+            }
+            candidates[0].last_block = Some(start + Duration::from_secs(100));
+            candidates[0].relay_txs = relay_txs;
+            select_to_evict(candidates)
+        };
+        assert_eq!(with_blocks(true), Some(newest));
+        assert_ne!(with_blocks(false), Some(newest));
+        assert!(with_blocks(false).is_some());
+    }
+
+    #[test]
+    fn biggest_netgroup_loses_its_newest() {
+        let (mut candidates, newest, _) = population();
+        // 两个老节点在同一个网络组, 比最新的那个单独的组大
+        for c in candidates.iter_mut().filter(|c| c.netgroup[0] == 30 || c.netgroup[0] == 31) {
+            c.netgroup = vec![99];
+        }
+        let evicted = select_to_evict(candidates).unwrap();
+        assert_ne!(evicted, newest);
+        assert_eq!(evicted, SocketAddr::from(([10, 31, 0, 1], 8333)));
+    }
+
+    #[test]
+    fn nothing_to_evict_when_all_are_protected() {
+        let (candidates, _, _) = population();
+        assert_eq!(select_to_evict(candidates[..4].to_vec()), None);
+        assert_eq!(select_to_evict(Vec::new()), None);
+    }
+}
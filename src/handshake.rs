@@ -0,0 +1,505 @@
+//! version/verack handshake
+//!
+//! 流程
+//!     我们连出去的 (`handshake`) 先发 version, 别人连进来的 (`respond`) 等对方的 version 到了再发
+//!     等对方的 version 和 verack, 两者顺序不固定
+//!     收到对方的 version 之后检查 nonce/版本/服务, 没问题就回 verack
+//!     回 verack 之前先发 sendaddrv2 (BIP155), 对方在 verack 之前发了 sendaddrv2 就记下来
+//!     两个都收到就算握手成功, 整个过程有超时
+//!
+//! Anything else the peer sends during the handshake (sendheaders, sendcmpct, wtxidrelay ...) is skipped.
+//! Like Bitcoin Core, `required_services` only applies to peers we connect to; inbound peers may be light clients.
+
+use std::{fmt, error};
+use std::collections::HashSet;
+use std::net::{SocketAddr, IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use crate::error::Error;
+use crate::message::{RawMessage, Payload};
+use crate::message::address::Address;
+use crate::message::command::CommandString;
+use crate::message::services::ServiceFlags;
+use crate::message::version::{VersionMessage, PROTOCOL_VERSION, RELAY_VERSION, ADDRV2_VERSION};
+use crate::network::NetworkParams;
+
+/// What we announce and what we require from the other side
+#[derive(Clone, Debug)]
+pub struct HandshakeConfig {
+    /// Network the connection belongs to
+    pub network: NetworkParams,
+    /// Protocol version we announce
+    pub version: u32,
+    /// Services we offer
+    pub services: ServiceFlags,
+    /// Our user agent
+    pub user_agent: String,
+    /// Height of our best chain
+    pub start_height: i32,
+    /// Whether the peer should relay transactions to us before a filter is loaded
+    pub relay: bool,
+    /// Give up if the handshake is not done within this time
+    pub timeout: Duration,
+    /// Peers announcing a lower version are rejected
+    pub min_version: u32,
+    /// Peers we connect to not offering all of these services are rejected, e.g. `ServiceFlags::NETWORK | ServiceFlags::WITNESS`
+    /// to only keep full nodes that can hand out witness data
+    pub required_services: ServiceFlags,
+    /// Ask the peer for `addrv2` instead of `addr` (BIP155)
+    pub addrv2: bool,
+}
+
+impl HandshakeConfig {
+    pub fn new(network: &NetworkParams) -> HandshakeConfig {
+        HandshakeConfig {
+            network: network.clone(),
+            version: PROTOCOL_VERSION,
+            services: ServiceFlags::NONE,
+            user_agent: format!("/bitcoin_p2p:{}/", env!("CARGO_PKG_VERSION")),
+            start_height: 0,
+            relay: true,
+            timeout: Duration::from_secs(60),
+            // filterload 需要 BIP37
+            min_version: RELAY_VERSION,
+            required_services: ServiceFlags::NETWORK,
+            addrv2: true,
+        }
+    }
+}
+
+/// What we learned about the peer during the handshake
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PeerInfo {
+    /// Address we are connected to
+    pub address: SocketAddr,
+    /// The lower of our and the peer's protocol version, used for the rest of the connection
+    pub version: u32,
+    /// The `version` message the peer sent
+    pub remote: VersionMessage,
+    /// The peer sent `sendaddrv2`, addresses have to be sent to it as `addrv2`
+    pub addrv2: bool,
+    /// The peer connected to us
+    pub inbound: bool,
+}
+
+impl PeerInfo {
+    pub fn services(&self) -> ServiceFlags {
+        self.remote.services
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.remote.user_agent
+    }
+
+    pub fn start_height(&self) -> i32 {
+        self.remote.start_height
+    }
+}
+
+/// Reasons we reject a peer during the handshake
+///
+/// Failures of the connection itself are reported as `Error::Io`, `Error::Disconnected` and `Error::Timeout`.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum HandshakeError {
+    /// The peer echoed one of our own nonces, we are talking to ourselves
+    SelfConnection,
+    /// The peer's protocol version is lower than `HandshakeConfig::min_version`
+    ObsoleteVersion(u32),
+    /// The peer lacks some of `HandshakeConfig::required_services`
+    MissingServices {
+        /// Services we require
+        required: ServiceFlags,
+        /// Services the peer offers
+        offered: ServiceFlags,
+    },
+    /// The peer sent a message that is not allowed at this point, e.g. a second `version`
+    UnexpectedMessage(CommandString),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::SelfConnection => write!(f, "connected to ourselves"),
+            HandshakeError::ObsoleteVersion(version) => write!(f, "peer version {} is too old", version),
+            HandshakeError::MissingServices { required, offered } =>
+                write!(f, "peer offers services {}, missing {}", offered, required.difference(offered)),
+            HandshakeError::UnexpectedMessage(ref command) => write!(f, "unexpected {:?} during handshake", command.0),
+        }
+    }
+}
+
+impl error::Error for HandshakeError {}
+
+/// Nonces of our own `version` messages that are still in flight
+///
+/// Share one `Nonces` between all connections of a process: if a peer's `version` carries a nonce
+/// from this set, the connection loops back to ourselves.
+#[derive(Clone, Default, Debug)]
+pub struct Nonces(Arc<Mutex<HashSet<u64>>>);
+
+impl Nonces {
+    pub fn new() -> Nonces {
+        Nonces::default()
+    }
+
+    /// Pick a fresh random nonce and remember it
+    pub fn generate(&self) -> u64 {
+        let mut nonces = self.0.lock().unwrap();
+        loop {
+            let nonce = rand::random::<u64>();
+            if nonce != 0 && nonces.insert(nonce) {
+                return nonce;
+            }
+        }
+    }
+
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.0.lock().unwrap().contains(&nonce)
+    }
+
+    pub fn remove(&self, nonce: u64) {
+        self.0.lock().unwrap().remove(&nonce);
+    }
+}
+
+/// Run the handshake on a connection we opened
+///
+/// 用法
+///     let mut framed = Framed::new(stream, MessageCodec::new(&network));
+///     let info = handshake(&mut framed, remote, &config, &nonces).await?;
+pub async fn handshake<S>(framed: &mut S, remote: SocketAddr, config: &HandshakeConfig, nonces: &Nonces)
+    -> Result<PeerInfo, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+{
+    run(framed, remote, config, nonces, false).await
+}
+
+/// Run the handshake on a connection the peer opened: wait for its `version`, then answer with ours
+///
+/// 用法
+///     let (stream, remote) = listener.accept().await?;
+///     let mut framed = Framed::new(stream, MessageCodec::new(&network));
+///     let info = respond(&mut framed, remote, &config, &nonces).await?;
+pub async fn respond<S>(framed: &mut S, remote: SocketAddr, config: &HandshakeConfig, nonces: &Nonces)
+    -> Result<PeerInfo, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+{
+    run(framed, remote, config, nonces, true).await
+}
+
+async fn run<S>(framed: &mut S, remote: SocketAddr, config: &HandshakeConfig, nonces: &Nonces, inbound: bool)
+    -> Result<PeerInfo, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+{
+    let nonce = nonces.generate();
+    let result = tokio::time::timeout(config.timeout, exchange(framed, remote, config, nonces, nonce, inbound)).await;
+    nonces.remove(nonce);
+    match result {
+        Ok(result) => result,
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+async fn exchange<S>(framed: &mut S, remote: SocketAddr, config: &HandshakeConfig, nonces: &Nonces, nonce: u64,
+                     inbound: bool) -> Result<PeerInfo, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+{
+    if !inbound {
+        framed.send(version_message(remote, config, nonce)).await?;
+    }
+
+    let mut remote_version: Option<VersionMessage> = None;
+    let mut got_verack = false;
+    let mut addrv2 = false;
+    while remote_version.is_none() || !got_verack {
+        let message = match framed.next().await {
+            Some(Ok(message)) => message,
+            Some(Err(ref e)) if !e.is_fatal() => {
+                // 解码失败的消息 跳过
+                debug!("skipping message from {} during handshake: {}", remote, e);
+                continue;
+            }
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::Disconnected),
+        };
+
+        match message.payload() {
+            Payload::Version(version) => {
+                if remote_version.is_some() {
+                    return Err(HandshakeError::UnexpectedMessage(message.command().clone()).into());
+                }
+                // 连进来的节点不要求服务
+                let required = if inbound { ServiceFlags::NONE } else { config.required_services };
+                check_version(version, config, nonces, required)?;
+                if inbound {
+                    framed.send(version_message(remote, config, nonce)).await?;
+                }
+                if config.addrv2 && version.version >= ADDRV2_VERSION {
+                    framed.send(RawMessage::new(&config.network, CommandString("sendaddrv2".to_owned()), Payload::SendAddrV2)).await?;
+                }
+                framed.send(RawMessage::new(&config.network, CommandString("verack".to_owned()), Payload::Verack)).await?;
+                remote_version = Some(version.clone());
+            }
+            Payload::Verack => {
+                // 我们的 version 还没发出去 对方不可能已经回了 verack
+                if got_verack || (inbound && remote_version.is_none()) {
+                    return Err(HandshakeError::UnexpectedMessage(message.command().clone()).into());
+                }
+                got_verack = true;
+            }
+            Payload::SendAddrV2 => addrv2 = true,
+            _ => debug!("skipping {:?} from {} during handshake", message.command().0, remote),
+        }
+    }
+
+    let remote_version = remote_version.expect("loop only ends once the version arrived");
+    Ok(PeerInfo {
+        address: remote,
+        version: remote_version.version.min(config.version),
+        remote: remote_version,
+        addrv2,
+        inbound,
+    })
+}
+
+fn version_message(remote: SocketAddr, config: &HandshakeConfig, nonce: u64) -> RawMessage {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    // 和 Bitcoin Core 一样 自己的地址填空
+    let sender = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+    let version = VersionMessage::new(
+        config.version,
+        config.services,
+        timestamp,
+        Address::new(&remote, ServiceFlags::NONE),
+        Address::new(&sender, config.services),
+        nonce,
+        config.user_agent.clone(),
+        config.start_height,
+        config.relay,
+    );
+    RawMessage::new(&config.network, CommandString("version".to_owned()), Payload::Version(version))
+}
+
+fn check_version(version: &VersionMessage, config: &HandshakeConfig, nonces: &Nonces, required: ServiceFlags)
+    -> Result<(), HandshakeError>
+{
+    if nonces.contains(version.nonce) {
+        return Err(HandshakeError::SelfConnection);
+    }
+    if version.version < config.min_version {
+        return Err(HandshakeError::ObsoleteVersion(version.version));
+    }
+    if !version.services.has(required) {
+        return Err(HandshakeError::MissingServices { required, offered: version.services });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures::channel::mpsc;
+    use tokio::task::JoinHandle;
+
+    /// 内存里的一头连接, 另一头写的从这里读
+    struct End {
+        rx: mpsc::UnboundedReceiver<RawMessage>,
+        tx: mpsc::UnboundedSender<RawMessage>,
+    }
+
+    fn pair() -> (End, End) {
+        let (a_tx, b_rx) = mpsc::unbounded();
+        let (b_tx, a_rx) = mpsc::unbounded();
+        (End { rx: a_rx, tx: a_tx }, End { rx: b_rx, tx: b_tx })
+    }
+
+    impl Stream for End {
+        type Item = Result<RawMessage, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|message| message.map(Ok))
+        }
+    }
+
+    impl Sink<RawMessage> for End {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: RawMessage) -> Result<(), Error> {
+            self.tx.unbounded_send(item).map_err(|_| Error::Disconnected)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl End {
+        /// 对面那头: 发一条消息
+        fn push(&mut self, payload: Payload) {
+            self.tx.unbounded_send(RawMessage::new(&NetworkParams::mainnet(), payload.command(), payload)).unwrap();
+        }
+
+        /// 对面那头: 收一条消息
+        async fn pop(&mut self) -> Payload {
+            self.rx.next().await.expect("a message").payload().clone()
+        }
+    }
+
+    fn remote() -> SocketAddr {
+        "10.0.0.1:8333".parse().unwrap()
+    }
+
+    fn config() -> HandshakeConfig {
+        let mut config = HandshakeConfig::new(&NetworkParams::mainnet());
+        config.timeout = Duration::from_secs(5);
+        config
+    }
+
+    fn version(version: u32, services: ServiceFlags, nonce: u64) -> Payload {
+        let address = Address::new(&remote(), ServiceFlags::NONE);
+        Payload::Version(VersionMessage::new(version, services, 0, address.clone(), address, nonce, "/test/".to_owned(), 0, true))
+    }
+
+    fn start(end: End, config: HandshakeConfig, nonces: Nonces, inbound: bool) -> JoinHandle<Result<PeerInfo, Error>> {
+        tokio::spawn(async move {
+            let mut end = end;
+            if inbound {
+                respond(&mut end, remote(), &config, &nonces).await
+            } else {
+                handshake(&mut end, remote(), &config, &nonces).await
+            }
+        })
+    }
+
+    fn rejected(result: Result<PeerInfo, Error>) -> HandshakeError {
+        match result {
+            Err(Error::Handshake(e)) => e,
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn verack_before_version() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        assert!(matches!(theirs.pop().await, Payload::Version(_)));
+        theirs.push(Payload::Verack);
+        theirs.push(version(70015, ServiceFlags::NETWORK, 1));
+        // 70016 之前的节点不认识 sendaddrv2
+        assert_eq!(theirs.pop().await, Payload::Verack);
+
+        let info = task.await.unwrap().unwrap();
+        assert_eq!((info.version, info.addrv2, info.inbound), (70015, false, false));
+    }
+
+    #[tokio::test]
+    async fn version_before_verack() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        assert!(matches!(theirs.pop().await, Payload::Version(_)));
+        theirs.push(version(PROTOCOL_VERSION + 1, ServiceFlags::NETWORK, 1));
+        theirs.push(Payload::SendAddrV2);
+        theirs.push(Payload::Verack);
+        // sendaddrv2 要在 verack 之前
+        assert_eq!(theirs.pop().await, Payload::SendAddrV2);
+        assert_eq!(theirs.pop().await, Payload::Verack);
+
+        let info = task.await.unwrap().unwrap();
+        assert_eq!((info.version, info.addrv2), (PROTOCOL_VERSION, true));
+    }
+
+    #[tokio::test]
+    async fn inbound_answers_after_version() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), true);
+        theirs.push(version(PROTOCOL_VERSION, ServiceFlags::NETWORK, 1));
+        assert!(matches!(theirs.pop().await, Payload::Version(_)));
+        assert_eq!(theirs.pop().await, Payload::SendAddrV2);
+        assert_eq!(theirs.pop().await, Payload::Verack);
+        theirs.push(Payload::Verack);
+        assert!(task.await.unwrap().unwrap().inbound);
+    }
+
+    #[tokio::test]
+    async fn self_connection() {
+        let (outbound, inbound) = pair();
+        let nonces = Nonces::new();
+        let mut config = config();
+        config.timeout = Duration::from_millis(200);
+        let ours = start(outbound, config.clone(), nonces.clone(), false);
+        let theirs = start(inbound, config, nonces.clone(), true);
+        assert_eq!(rejected(theirs.await.unwrap()), HandshakeError::SelfConnection);
+        assert!(ours.await.unwrap().is_err());
+        // 结束之后 nonce 都删掉了
+        assert!(nonces.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn obsolete_version() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        theirs.push(version(209, ServiceFlags::NETWORK, 1));
+        assert_eq!(rejected(task.await.unwrap()), HandshakeError::ObsoleteVersion(209));
+    }
+
+    #[tokio::test]
+    async fn missing_services_only_outbound() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        theirs.push(version(PROTOCOL_VERSION, ServiceFlags::WITNESS, 1));
+        let expected = HandshakeError::MissingServices { required: ServiceFlags::NETWORK, offered: ServiceFlags::WITNESS };
+        assert_eq!(rejected(task.await.unwrap()), expected);
+
+        // 连进来的轻节点可以什么服务都没有
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), true);
+        theirs.push(version(PROTOCOL_VERSION, ServiceFlags::NONE, 1));
+        theirs.push(Payload::Verack);
+        assert_eq!(task.await.unwrap().unwrap().services(), ServiceFlags::NONE);
+    }
+
+    #[tokio::test]
+    async fn unexpected_messages() {
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        theirs.push(version(PROTOCOL_VERSION, ServiceFlags::NETWORK, 1));
+        theirs.push(version(PROTOCOL_VERSION, ServiceFlags::NETWORK, 1));
+        assert_eq!(rejected(task.await.unwrap()), HandshakeError::UnexpectedMessage(CommandString("version".to_owned())));
+
+        // 我们还没发 version, 对方不能先 verack
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), true);
+        theirs.push(Payload::Verack);
+        assert_eq!(rejected(task.await.unwrap()), HandshakeError::UnexpectedMessage(CommandString("verack".to_owned())));
+
+        let (ours, mut theirs) = pair();
+        let task = start(ours, config(), Nonces::new(), false);
+        theirs.push(Payload::Verack);
+        theirs.push(Payload::Verack);
+        assert_eq!(rejected(task.await.unwrap()), HandshakeError::UnexpectedMessage(CommandString("verack".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let (ours, _theirs) = pair();
+        let mut config = config();
+        config.timeout = Duration::from_millis(50);
+        assert!(matches!(start(ours, config, Nonces::new(), false).await.unwrap(), Err(Error::Timeout)));
+
+        let (ours, theirs) = pair();
+        drop(theirs);
+        assert!(matches!(start(ours, HandshakeConfig::new(&NetworkParams::mainnet()), Nonces::new(), true).await.unwrap(), Err(Error::Disconnected)));
+    }
+}
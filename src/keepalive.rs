@@ -0,0 +1,348 @@
+//! ping/pong keepalive and round-trip times (BIP31)
+//!
+//! 节点长时间收不到消息会断开连接 (Bitcoin Core 是 20 分钟)
+//!     每隔 `interval` 发一个 ping, 带随机 nonce
+//!     对方的 ping 原样回 pong
+//!     对方的 pong 用来计算往返时间
+//!     `timeout` 之内没收到 pong 就放弃这个连接
+//!
+//! One `Keepalive` belongs to one connection, so its `PingStats` are the statistics of that peer.
+
+use std::time::Duration;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::time::Instant;
+use log::debug;
+use crate::error::Error;
+use crate::message::{RawMessage, Payload, Magic};
+use crate::message::command::CommandString;
+use crate::network::NetworkParams;
+
+/// How often to ping and how long to wait for the pong
+#[derive(Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// Time between two pings
+    pub interval: Duration,
+    /// Give up on the peer if a pong takes longer than this
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    // 和 Bitcoin Core 的 PING_INTERVAL / TIMEOUT_INTERVAL 一样
+    fn default() -> KeepaliveConfig {
+        KeepaliveConfig {
+            interval: Duration::from_secs(2 * 60),
+            timeout: Duration::from_secs(20 * 60),
+        }
+    }
+}
+
+/// Round-trip times measured with ping/pong
+#[derive(PartialEq, Eq, Clone, Default, Debug)]
+pub struct PingStats {
+    last: Option<Duration>,
+    min: Option<Duration>,
+    total: Duration,
+    samples: u32,
+}
+
+impl PingStats {
+    /// Round-trip time of the most recent ping
+    pub fn last(&self) -> Option<Duration> {
+        self.last
+    }
+
+    /// Fastest round-trip time so far
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// Mean of all round-trip times so far
+    pub fn average(&self) -> Option<Duration> {
+        if self.samples == 0 {
+            None
+        } else {
+            Some(self.total / self.samples)
+        }
+    }
+
+    /// Number of pongs received
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    fn record(&mut self, rtt: Duration) {
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.total += rtt;
+        self.samples += 1;
+    }
+}
+
+/// Keepalive state of one connection
+///
+/// 用法
+///     let mut keepalive = Keepalive::new(&network, KeepaliveConfig::default());
+///     while let Some(message) = keepalive.next(&mut framed).await? {
+///         // ping 和 pong 不会出现在这里
+///     }
+///     info!("rtt {:?}", keepalive.stats().average());
+///
+/// Callers running their own event loop can drive `poll`, `handle` and `deadline` directly instead of `next`.
+#[derive(Debug)]
+pub struct Keepalive {
+    magic: Magic,
+    config: KeepaliveConfig,
+    /// nonce and send time of the ping waiting for its pong
+    pending: Option<(u64, Instant)>,
+    next_ping: Instant,
+    stats: PingStats,
+}
+
+impl Keepalive {
+    /// The first ping goes out on the first `poll`
+    pub fn new(network: &NetworkParams, config: KeepaliveConfig) -> Keepalive {
+        Keepalive {
+            magic: network.magic,
+            config,
+            pending: None,
+            next_ping: Instant::now(),
+            stats: PingStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PingStats {
+        &self.stats
+    }
+
+    /// The next moment `poll` has something to do
+    pub fn deadline(&self) -> Instant {
+        match self.pending {
+            Some((_, sent)) => sent + self.config.timeout,
+            None => self.next_ping,
+        }
+    }
+
+    /// Returns the ping to send if one is due, or `Error::Timeout` if the pending pong is overdue
+    pub fn poll(&mut self, now: Instant) -> Result<Option<RawMessage>, Error> {
+        match self.pending {
+            Some((_, sent)) if now >= sent + self.config.timeout => Err(Error::Timeout),
+            Some(_) => Ok(None),
+            None if now >= self.next_ping => {
+                // 0 在 BIP31 里有特殊含义 不用
+                let nonce = loop {
+                    let nonce = rand::random::<u64>();
+                    if nonce != 0 {
+                        break nonce;
+                    }
+                };
+                self.pending = Some((nonce, now));
+                Ok(Some(RawMessage::with_magic(self.magic, CommandString("ping".to_owned()), Payload::Ping(nonce))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Handle a message received at `now`: answers a ping with a pong and records the round-trip time of a pong.
+    /// Returns the reply to send, if any.
+    pub fn handle(&mut self, message: &RawMessage, now: Instant) -> Option<RawMessage> {
+        match *message.payload() {
+            Payload::Ping(nonce) => Some(RawMessage::with_magic(self.magic, CommandString("pong".to_owned()), Payload::Pong(nonce))),
+            Payload::Pong(nonce) => {
+                match self.pending {
+                    Some((expected, sent)) if expected == nonce => {
+                        self.stats.record(now - sent);
+                        self.pending = None;
+                        self.next_ping = sent + self.config.interval;
+                    }
+                    // 迟到的或者对方乱发的 pong 不管
+                    _ => debug!("ignoring pong with unexpected nonce {:#x}", nonce),
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Wait for the next message other than ping and pong, sending pings and answering the peer's pings meanwhile.
+    /// Returns `Ok(None)` once the peer has closed the connection.
+    /// Errors that are not `Error::is_fatal` only concern a single message, `next` can be called again.
+    pub async fn next<S>(&mut self, framed: &mut S) -> Result<Option<RawMessage>, Error>
+        where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+    {
+        loop {
+            if let Some(ping) = self.poll(Instant::now())? {
+                framed.send(ping).await?;
+            }
+            let message = match tokio::time::timeout_at(self.deadline(), framed.next()).await {
+                // 到点了 回去发 ping 或者判断超时
+                Err(_) => continue,
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => return Err(e),
+                Ok(None) => return Ok(None),
+            };
+            match message.payload() {
+                Payload::Ping(_) | Payload::Pong(_) => {
+                    if let Some(reply) = self.handle(&message, Instant::now()) {
+                        framed.send(reply).await?;
+                    }
+                }
+                _ => return Ok(Some(message)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig { interval: Duration::from_secs(60), timeout: Duration::from_secs(10) }
+    }
+
+    fn ping_nonce(message: Option<RawMessage>) -> u64 {
+        match message.map(|message| message.payload().clone()) {
+            Some(Payload::Ping(nonce)) => nonce,
+            other => panic!("expected a ping, got {:?}", other),
+        }
+    }
+
+    fn pong(nonce: u64) -> RawMessage {
+        RawMessage::new(&NetworkParams::mainnet(), CommandString("pong".to_owned()), Payload::Pong(nonce))
+    }
+
+    #[test]
+    fn ping_after_the_interval() {
+        let mut keepalive = Keepalive::new(&NetworkParams::mainnet(), config());
+        let start = Instant::now();
+        let nonce = ping_nonce(keepalive.poll(start).unwrap());
+        assert_ne!(nonce, 0);
+        // 等 pong 的时候不再发 ping
+        assert!(keepalive.poll(start + Duration::from_secs(5)).unwrap().is_none());
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(10));
+
+        assert!(keepalive.handle(&pong(nonce), start + Duration::from_secs(1)).is_none());
+        // 下一个 ping 从上一个发出去的时候算
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(60));
+        assert!(keepalive.poll(start + Duration::from_secs(59)).unwrap().is_none());
+        assert_ne!(ping_nonce(keepalive.poll(start + Duration::from_secs(60)).unwrap()), 0);
+    }
+
+    #[test]
+    fn pong_nonce_must_match() {
+        let mut keepalive = Keepalive::new(&NetworkParams::mainnet(), config());
+        let start = Instant::now();
+        let nonce = ping_nonce(keepalive.poll(start).unwrap());
+
+        // 对不上的 pong 不算, 还在等
+        keepalive.handle(&pong(nonce.wrapping_add(1)), start + Duration::from_secs(1));
+        assert_eq!(keepalive.stats().samples(), 0);
+        assert_eq!(keepalive.deadline(), start + Duration::from_secs(10));
+
+        keepalive.handle(&pong(nonce), start + Duration::from_secs(2));
+        assert_eq!(keepalive.stats().samples(), 1);
+        // 同一个 nonce 再来一次就是过期的了
+        keepalive.handle(&pong(nonce), start + Duration::from_secs(3));
+        assert_eq!(keepalive.stats().samples(), 1);
+        assert_eq!(keepalive.stats().last(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn ping_is_answered() {
+        let mut keepalive = Keepalive::new(&NetworkParams::mainnet(), config());
+        let ping = RawMessage::new(&NetworkParams::mainnet(), CommandString("ping".to_owned()), Payload::Ping(42));
+        let reply = keepalive.handle(&ping, Instant::now()).expect("a pong");
+        assert_eq!(*reply.payload(), Payload::Pong(42));
+        assert_eq!(reply.magic(), NetworkParams::mainnet().magic);
+    }
+
+    #[test]
+    fn stats() {
+        let mut keepalive = Keepalive::new(&NetworkParams::mainnet(), config());
+        assert_eq!(*keepalive.stats(), PingStats::default());
+        assert_eq!((keepalive.stats().last(), keepalive.stats().min(), keepalive.stats().average()), (None, None, None));
+
+        let mut now = Instant::now();
+        for &ms in [300u64, 100, 200].iter() {
+            let nonce = ping_nonce(keepalive.poll(now).unwrap());
+            now += Duration::from_millis(ms);
+            keepalive.handle(&pong(nonce), now);
+            now += Duration::from_secs(60);
+        }
+        let stats = keepalive.stats();
+        assert_eq!(stats.samples(), 3);
+        assert_eq!(stats.last(), Some(Duration::from_millis(200)));
+        assert_eq!(stats.min(), Some(Duration::from_millis(100)));
+        assert_eq!(stats.average(), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn overdue_pong_times_out() {
+        let mut keepalive = Keepalive::new(&NetworkParams::mainnet(), config());
+        let start = Instant::now();
+        keepalive.poll(start).unwrap();
+        assert!(keepalive.poll(start + Duration::from_millis(9_999)).unwrap().is_none());
+        assert!(matches!(keepalive.poll(start + Duration::from_secs(10)), Err(Error::Timeout)));
+    }
+
+    /// 不回 pong 的对端, 发出去的消息记下来
+    #[derive(Default)]
+    struct Silent {
+        incoming: VecDeque<RawMessage>,
+        sent: Vec<Payload>,
+    }
+
+    impl Stream for Silent {
+        type Item = Result<RawMessage, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.incoming.pop_front() {
+                Some(message) => Poll::Ready(Some(Ok(message))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl Sink<RawMessage> for Silent {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: RawMessage) -> Result<(), Error> {
+            self.sent.push(item.payload().clone());
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn next_gives_up_on_a_silent_peer() {
+        let params = NetworkParams::mainnet();
+        let config = KeepaliveConfig { interval: Duration::from_millis(50), timeout: Duration::from_millis(100) };
+        let mut keepalive = Keepalive::new(&params, config);
+        let mut peer = Silent::default();
+        // 对方的 ping 回了 pong, 别的消息交给调用者
+        peer.incoming.push_back(RawMessage::new(&params, CommandString("ping".to_owned()), Payload::Ping(7)));
+        peer.incoming.push_back(RawMessage::new(&params, CommandString("verack".to_owned()), Payload::Verack));
+        assert_eq!(*keepalive.next(&mut peer).await.unwrap().unwrap().payload(), Payload::Verack);
+        assert!(matches!(peer.sent[..], [Payload::Ping(_), Payload::Pong(7)]), "{:?}", peer.sent);
+
+        let start = Instant::now();
+        let e = keepalive.next(&mut peer).await.unwrap_err();
+        assert!(matches!(e, Error::Timeout) && e.is_fatal());
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(peer.sent.len(), 2);
+    }
+}
@@ -0,0 +1,68 @@
+//! use this project to send p2p_message to my own full_node.
+//! because I didn't send right message via murmel.
+//! I could not add to extern type in murmel's NetworkMessage enum, and i didn't get right value from bitcoin Network。
+//! In this project I will use tokios not mio in murmel. And I will send message to bitcoin Testnet.
+//! You can see the example in [https://bitcoin.org/en/developer-examples#retrieving-a-merkleblock].
+//! The code is writen in python.
+//!
+//! Here is dependency library
+//! bitcoin_hashes for hash [https://crates.io/crates/bitcoin_hashes]
+//! tokios for tcp connections
+//! rust_bitcoin for de/serialization, parsing and executing on data structures and network messages
+//!
+//! 模块
+//!     error       整个 crate 共用的错误类型
+//!     message     消息类型和 RawMessage 的编码/解码
+//!     codec       tokio 的 Encoder/Decoder, 配合 Framed 使用
+//!     network     各个网络的参数
+//!     handshake   version/verack 握手
+//!     keepalive   ping/pong 保活和往返时间
+//!     chain       区块头的校验和最长链
+//!     store       区块头的存储 内存或者文件
+//!     sync        getheaders/headers 同步区块头
+//!     relay       发送自己的交易 回应 getdata
+//!     peer        一个连接 读写各一个 task
+//!     manager     保持多个出站连接 断了退避重连
+//!     netgroup    IP 地址的网络组
+//!     eviction    入站名额满了的时候挑一个断开
+//!     listener    接受别人连进来
+//!     addrman     别的节点的地址 new/tried 两张表
+//!     seeds       从 DNS seed 找节点
+//!
+//! The binary in `src/main.rs` and the programs in `examples/` show how the pieces fit together.
+
+pub mod error;
+pub mod message;
+pub mod codec;
+pub mod network;
+pub mod handshake;
+pub mod keepalive;
+pub mod chain;
+pub mod store;
+pub mod sync;
+pub mod relay;
+pub mod peer;
+pub mod manager;
+pub mod netgroup;
+pub mod eviction;
+pub mod listener;
+pub mod addrman;
+pub mod seeds;
+
+pub use crate::error::Error;
+pub use crate::message::{RawMessage, Payload, Magic};
+pub use crate::message::services::ServiceFlags;
+pub use crate::message::registry::{MessagePayload, MessageRegistry, CustomPayload};
+pub use crate::codec::MessageCodec;
+pub use crate::network::NetworkParams;
+pub use crate::handshake::{handshake, respond, HandshakeConfig, HandshakeError, Nonces, PeerInfo};
+pub use crate::keepalive::{Keepalive, KeepaliveConfig, PingStats};
+pub use crate::chain::{HeaderChain, ChainEntry, HeaderError, Accepted};
+pub use crate::store::{HeaderStore, MemoryStore, FileStore, ChainEvent, StoreError};
+pub use crate::sync::sync_headers;
+pub use crate::relay::TxRelay;
+pub use crate::peer::{Peer, PeerConfig, DisconnectReason};
+pub use crate::manager::{PeerManager, ManagerConfig, PeerEvent, PeerState, PeerStatus, Connector, TcpConnector};
+pub use crate::listener::{Listener, ListenerConfig};
+pub use crate::addrman::{AddrMan, AddrInfo, AddrSource};
+pub use crate::seeds::{bootstrap, Resolver, SystemResolver, StaticResolver};
@@ -0,0 +1,502 @@
+//! Accepting connections from other nodes
+//!
+//! 流程
+//!     IPv4 和 IPv6 的地址上各监听一个 socket
+//!     有人连进来先看名额, 满了先断开握手最久的那个, 没有在握手的就用 `eviction` 挑一个已经连上的断开, 挑不出来就拒绝新的连接
+//!     握手用 `respond`, 等对方先发 version
+//!     握手之后和 `PeerManager` 一样, 所有连接的消息都放进同一个 `PeerEvent` 队列
+//!
+//! On systems with dual-stack sockets `[::]` also accepts IPv4 connections. Binding `0.0.0.0` to the same port
+//! then fails with "address in use", which is fine, the IPv6 socket covers it.
+
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use futures::future::{AbortHandle, Abortable};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use log::{debug, info};
+use crate::error::Error;
+use crate::eviction::{select_to_evict, EvictionCandidate};
+use crate::handshake::{Nonces, PeerInfo};
+use crate::manager::PeerEvent;
+use crate::message::Payload;
+use crate::message::services::ServiceFlags;
+use crate::netgroup::{netgroup, is_local};
+use crate::peer::{Peer, PeerConfig, PeerMonitor, DisconnectReason};
+
+/// Where to listen and how many peers to let in
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    /// How each connection is run
+    pub peer: PeerConfig,
+    /// Addresses to bind, by default `[::]` and `0.0.0.0` on the given port
+    pub addresses: Vec<SocketAddr>,
+    /// Inbound connections to keep, counting those still in the handshake
+    pub max_inbound: usize,
+    /// Events waiting to be taken before the connections stop reading
+    pub event_queue: usize,
+}
+
+impl ListenerConfig {
+    pub fn new(peer: PeerConfig, port: u16) -> ListenerConfig {
+        ListenerConfig {
+            peer,
+            addresses: vec![
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+            ],
+            // Bitcoin Core 的 125 个连接减去 11 个出站的
+            max_inbound: 114,
+            event_queue: 256,
+        }
+    }
+}
+
+enum Slot {
+    Handshaking(AbortHandle),
+    Connected {
+        info: PeerInfo,
+        sender: mpsc::Sender<Payload>,
+        monitor: PeerMonitor,
+    },
+}
+
+struct Inbound {
+    /// tells a later connection from the same address apart
+    id: u64,
+    slot: Slot,
+    connected: Instant,
+    netgroup: Vec<u8>,
+    last_block: Option<Instant>,
+    last_tx: Option<Instant>,
+}
+
+struct State {
+    peers: BTreeMap<SocketAddr, Inbound>,
+    next_id: u64,
+    accept_loops: Vec<AbortHandle>,
+    shutdown: bool,
+}
+
+struct Shared {
+    config: ListenerConfig,
+    nonces: Nonces,
+    events: mpsc::Sender<PeerEvent>,
+    /// key of `EvictionCandidate::keyed_netgroup`
+    netgroup_key: RandomState,
+    state: Mutex<State>,
+}
+
+/// Closes everything once the last `Listener` is gone
+struct Inner {
+    shared: Arc<Shared>,
+    local: Vec<SocketAddr>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.shared.shutdown();
+    }
+}
+
+/// Handle on the sockets accepting inbound connections; clones control the same listener
+///
+/// 用法
+///     let config = ListenerConfig::new(PeerConfig::new(&network), 18333);
+///     let (listener, mut events) = Listener::bind(config, nonces.clone()).await?;
+///     while let Some(event) = events.next().await {
+///         ...
+///     }
+///
+/// Inbound peers that fail the handshake are dropped without an event. Share the `Nonces` with the
+/// `PeerManager` of the same process to notice connections to ourselves.
+#[derive(Clone)]
+pub struct Listener {
+    inner: Arc<Inner>,
+}
+
+impl Listener {
+    /// Bind the configured addresses and start accepting. Addresses that cannot be bound are skipped
+    /// as long as at least one of them works; must be called from within a tokio runtime.
+    pub async fn bind(config: ListenerConfig, nonces: Nonces) -> Result<(Listener, mpsc::Receiver<PeerEvent>), Error> {
+        let mut sockets = Vec::new();
+        let mut first_error = None;
+        for address in &config.addresses {
+            match TcpListener::bind(address).await {
+                Ok(socket) => {
+                    info!("listening on {}", socket.local_addr()?);
+                    sockets.push(socket);
+                }
+                Err(e) => {
+                    info!("cannot listen on {}: {}", address, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        if sockets.is_empty() {
+            let e = first_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"));
+            return Err(e.into());
+        }
+
+        let (shared, events_rx) = Shared::new(config, nonces);
+        let mut local = Vec::new();
+        for socket in sockets {
+            local.push(socket.local_addr()?);
+            let (abort, registration) = AbortHandle::new_pair();
+            shared.state.lock().unwrap().accept_loops.push(abort);
+            tokio::spawn(Abortable::new(accept_loop(shared.clone(), socket), registration));
+        }
+        Ok((Listener { inner: Arc::new(Inner { shared, local }) }, events_rx))
+    }
+
+    /// The addresses actually bound, with the ports picked by the system if port 0 was asked for
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.inner.local
+    }
+
+    /// Inbound connections, including those still in the handshake
+    pub fn count(&self) -> usize {
+        self.inner.shared.state.lock().unwrap().peers.len()
+    }
+
+    /// The inbound peers whose handshake is done
+    pub fn connected(&self) -> Vec<PeerInfo> {
+        let state = self.inner.shared.state.lock().unwrap();
+        state.peers.values().filter_map(|peer| match peer.slot {
+            Slot::Connected { ref info, .. } => Some(info.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    /// Send `payload` to one inbound peer, waiting while its outbound queue is full
+    pub async fn send(&self, address: &SocketAddr, payload: Payload) -> Result<(), Error> {
+        let sender = {
+            let state = self.inner.shared.state.lock().unwrap();
+            match state.peers.get(address).map(|peer| &peer.slot) {
+                Some(Slot::Connected { sender, .. }) => sender.clone(),
+                _ => return Err(Error::Disconnected),
+            }
+        };
+        let mut sender = sender;
+        sender.send(payload).await.map_err(|_| Error::Disconnected)
+    }
+
+    /// Close the connection from `address`
+    pub fn disconnect(&self, address: &SocketAddr) -> bool {
+        let removed = self.inner.shared.state.lock().unwrap().peers.remove(address);
+        match removed {
+            Some(peer) => {
+                peer.slot.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop accepting and close all inbound connections
+    pub fn shutdown(&self) {
+        self.inner.shared.shutdown();
+    }
+}
+
+impl Slot {
+    fn close(&self) {
+        match *self {
+            Slot::Handshaking(ref abort) => abort.abort(),
+            Slot::Connected { ref monitor, .. } => monitor.disconnect(),
+        }
+    }
+}
+
+impl Shared {
+    fn new(config: ListenerConfig, nonces: Nonces) -> (Arc<Shared>, mpsc::Receiver<PeerEvent>) {
+        let (events, events_rx) = mpsc::channel(config.event_queue.max(1));
+        let state = State { peers: BTreeMap::new(), next_id: 0, accept_loops: Vec::new(), shutdown: false };
+        (Arc::new(Shared { config, nonces, events, netgroup_key: RandomState::new(), state: Mutex::new(state) }), events_rx)
+    }
+
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        for abort in state.accept_loops.drain(..) {
+            abort.abort();
+        }
+        for (_, peer) in std::mem::take(&mut state.peers) {
+            peer.slot.close();
+        }
+    }
+
+    fn keyed_netgroup(&self, netgroup: &[u8]) -> u64 {
+        self.netgroup_key.hash_one(netgroup)
+    }
+
+    /// Make room for `remote` if needed and start the handshake
+    fn accepted<T>(self: &Arc<Shared>, stream: T, remote: SocketAddr)
+        where T: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown || state.peers.contains_key(&remote) {
+            return;
+        }
+        if state.peers.len() >= self.config.max_inbound {
+            // 还在握手的先让位, 不然开一堆不说话的连接就能把所有人挡在外面
+            let handshaking = state.peers.iter()
+                .filter(|(_, peer)| matches!(peer.slot, Slot::Handshaking(_)))
+                .min_by_key(|(_, peer)| peer.connected)
+                .map(|(address, _)| *address);
+            if let Some(victim) = handshaking {
+                debug!("dropping handshake with {} to make room for {}", victim, remote);
+                if let Some(peer) = state.peers.remove(&victim) {
+                    peer.slot.close();
+                }
+            }
+        }
+        if state.peers.len() >= self.config.max_inbound {
+            let candidates = state.peers.iter().filter_map(|(address, peer)| match peer.slot {
+                Slot::Connected { ref info, ref monitor, .. } => Some(EvictionCandidate {
+                    address: *address,
+                    connected: peer.connected,
+                    min_ping: monitor.ping_stats().min(),
+                    last_block: peer.last_block,
+                    last_tx: peer.last_tx,
+                    relay_txs: info.remote.relay,
+                    relevant_services: info.services().has(ServiceFlags::NETWORK),
+                    netgroup: peer.netgroup.clone(),
+                    keyed_netgroup: self.keyed_netgroup(&peer.netgroup),
+                    local: is_local(&address.ip()),
+                }),
+                Slot::Handshaking(_) => None,
+            }).collect();
+            match select_to_evict(candidates) {
+                Some(victim) => {
+                    info!("evicting {} to make room for {}", victim, remote);
+                    if let Some(peer) = state.peers.remove(&victim) {
+                        peer.slot.close();
+                    }
+                }
+                None => {
+                    info!("no inbound slot left for {}", remote);
+                    return;
+                }
+            }
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        let (abort, registration) = AbortHandle::new_pair();
+        state.peers.insert(remote, Inbound {
+            id,
+            slot: Slot::Handshaking(abort),
+            connected: Instant::now(),
+            netgroup: netgroup(&remote.ip()),
+            last_block: None,
+            last_tx: None,
+        });
+        tokio::spawn(Abortable::new(run(self.clone(), stream, remote, id), registration));
+    }
+
+    /// Forget the connection `id` from `address`, unless it was replaced in the meantime
+    fn remove(&self, address: &SocketAddr, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.peers.get(address).is_some_and(|peer| peer.id == id) {
+            state.peers.remove(address);
+        }
+    }
+}
+
+async fn accept_loop(shared: Arc<Shared>, mut socket: TcpListener) {
+    loop {
+        match socket.accept().await {
+            Ok((stream, remote)) => {
+                debug!("inbound connection from {}", remote);
+                shared.accepted(stream, remote);
+            }
+            Err(e) => {
+                // 比如文件描述符用完了 过一会再试
+                debug!("accept failed: {}", e);
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Run the handshake with `remote` and pass on its messages until the connection ends
+async fn run<T>(shared: Arc<Shared>, stream: T, remote: SocketAddr, id: u64)
+    where T: AsyncRead + AsyncWrite + Send + Unpin + 'static
+{
+    let mut events = shared.events.clone();
+    let mut peer = match Peer::accept(stream, remote, &shared.config.peer, &shared.nonces).await {
+        Ok(peer) => peer,
+        Err(e) => {
+            debug!("handshake with {} failed: {}", remote, e);
+            shared.remove(&remote, id);
+            return;
+        }
+    };
+
+    let info = peer.info().clone();
+    {
+        let mut state = shared.state.lock().unwrap();
+        match state.peers.get_mut(&remote) {
+            Some(inbound) if inbound.id == id => {
+                inbound.slot = Slot::Connected { info: info.clone(), sender: peer.sender(), monitor: peer.monitor() };
+            }
+            _ => return,
+        }
+    }
+    info!("accepted {} ({})", remote, info.user_agent());
+    let mut listening = events.send(PeerEvent::Connected(info)).await.is_ok();
+    while listening {
+        let message = match peer.next().await {
+            Some(message) => message,
+            None => break,
+        };
+        {
+            let mut state = shared.state.lock().unwrap();
+            if let Some(inbound) = state.peers.get_mut(&remote).filter(|inbound| inbound.id == id) {
+                match message.payload() {
+                    // headers 不算, 和 Bitcoin Core 一样只看真的送来的区块
+                    Payload::Block(_) | Payload::MerkleBlock(_) => inbound.last_block = Some(Instant::now()),
+                    Payload::Tx(_) => inbound.last_tx = Some(Instant::now()),
+                    _ => {}
+                }
+            }
+        }
+        listening = events.send(PeerEvent::Message(remote, message)).await.is_ok();
+    }
+    // 没人收事件了也要把自己删掉, 不然一直占着一个名额
+    // 连接自己结束的时候原因已经有了, 这时再 disconnect 不能把它换成 Requested
+    if peer.disconnect_reason().is_none() {
+        peer.disconnect();
+    }
+    let reason = peer.disconnect_reason().unwrap_or(DisconnectReason::Closed);
+    shared.remove(&remote, id);
+    if listening {
+        let _ = events.send(PeerEvent::Disconnected(remote, reason)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkParams;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+    use tokio_util::codec::Framed;
+    use crate::codec::MessageCodec;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use futures::SinkExt;
+    use crate::handshake::{handshake, HandshakeConfig};
+    use crate::message::RawMessage;
+    use crate::message::headers::Headers;
+
+    fn listener(max_inbound: usize) -> (Arc<Shared>, mpsc::Receiver<PeerEvent>) {
+        let mut config = ListenerConfig::new(PeerConfig::new(&NetworkParams::mainnet()), 0);
+        config.max_inbound = max_inbound;
+        Shared::new(config, Nonces::new())
+    }
+
+    fn address(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, n, 0, 1], 8333))
+    }
+
+    fn peers(shared: &Shared) -> Vec<SocketAddr> {
+        shared.state.lock().unwrap().peers.keys().cloned().collect()
+    }
+
+    /// 对面连进来, 先不说话
+    fn connect(shared: &Arc<Shared>, n: u8) -> UnixStream {
+        let (local, remote) = UnixStream::pair().unwrap();
+        shared.accepted(local, address(n));
+        remote
+    }
+
+    /// 对面连进来并且完成握手
+    async fn connect_and_handshake(shared: &Arc<Shared>, events: &mut mpsc::Receiver<PeerEvent>, n: u8)
+        -> Framed<UnixStream, MessageCodec>
+    {
+        let mut framed = Framed::new(connect(shared, n), MessageCodec::new(&NetworkParams::mainnet()));
+        let mut config = HandshakeConfig::new(&NetworkParams::mainnet());
+        config.required_services = ServiceFlags::NONE;
+        handshake(&mut framed, "10.0.0.100:8333".parse().unwrap(), &config, &Nonces::new()).await.unwrap();
+        match events.recv().await {
+            Some(PeerEvent::Connected(info)) => assert_eq!(info.address, address(n)),
+            other => panic!("expected a connection, got {:?}", other),
+        }
+        framed
+    }
+
+    async fn closed(remote: &mut UnixStream) -> bool {
+        let mut buf = [0u8; 1024];
+        loop {
+            match tokio::time::timeout(Duration::from_secs(2), remote.read(&mut buf)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => return true,
+                Ok(Ok(_)) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oldest_handshake_makes_room() {
+        let (shared, mut events) = listener(3);
+        let _connected = connect_and_handshake(&shared, &mut events, 1).await;
+        let mut first = connect(&shared, 2);
+        let _second = connect(&shared, 3);
+        assert_eq!(peers(&shared), vec![address(1), address(2), address(3)]);
+
+        // 满了: 握手最久的让位, 已经连上的不动
+        let _third = connect(&shared, 4);
+        assert_eq!(peers(&shared), vec![address(1), address(3), address(4)]);
+        assert!(closed(&mut first).await);
+    }
+
+    #[tokio::test]
+    async fn full_listener_refuses_when_nobody_can_be_evicted() {
+        let (shared, mut events) = listener(2);
+        let first = connect_and_handshake(&shared, &mut events, 1).await;
+        let _second = connect_and_handshake(&shared, &mut events, 2).await;
+
+        // 只有两个连接, 都受保护, 新来的被拒绝
+        let mut third = connect(&shared, 3);
+        assert!(closed(&mut third).await);
+        assert_eq!(peers(&shared), vec![address(1), address(2)]);
+
+        // 对方断开, 原因是 Closed, 名额空出来
+        let first = first.into_inner();
+        first.shutdown(std::net::Shutdown::Write).unwrap();
+        match events.recv().await {
+            Some(PeerEvent::Disconnected(from, DisconnectReason::Closed)) => assert_eq!(from, address(1)),
+            other => panic!("expected a disconnect, got {:?}", other),
+        }
+        assert_eq!(peers(&shared), vec![address(2)]);
+        let _fourth = connect(&shared, 4);
+        assert_eq!(peers(&shared), vec![address(2), address(4)]);
+    }
+
+    #[tokio::test]
+    async fn only_blocks_count_as_block_relay() {
+        let (shared, mut events) = listener(2);
+        let mut remote = connect_and_handshake(&shared, &mut events, 1).await;
+        let last_block = || shared.state.lock().unwrap().peers[&address(1)].last_block;
+
+        let block = genesis_block(Network::Bitcoin);
+        let headers = Payload::Headers(Headers(vec![block.header]));
+        remote.send(RawMessage::new(&NetworkParams::mainnet(), headers.command(), headers)).await.unwrap();
+        assert!(matches!(events.recv().await, Some(PeerEvent::Message(..))));
+        assert_eq!(last_block(), None);
+
+        let block = Payload::Block(block);
+        remote.send(RawMessage::new(&NetworkParams::mainnet(), block.command(), block)).await.unwrap();
+        assert!(matches!(events.recv().await, Some(PeerEvent::Message(..))));
+        assert!(last_block().is_some());
+    }
+}
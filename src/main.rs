@@ -1,68 +1,75 @@
-//! use this project to send p2p_message to my own full_node.
-//! because I didn't send right message via murmel.
-//! I could not add to extern type in murmel's NetworkMessage enum, and i didn't get right value from bitcoin Network。
-//! In this project I will use tokios not mio in murmel. And I will send message to bitcoin Testnet.
-//! You can see the example in [https://bitcoin.org/en/developer-examples#retrieving-a-merkleblock].
-//! The code is writen in python.
+//! Connects to one full node, syncs the block headers, loads a bloom filter and asks for the newest block filtered,
+//! following [https://bitcoin.org/en/developer-examples#retrieving-a-merkleblock].
 //!
-//! Here is dependency library
-//! bitcoin_hashes for hash [https://crates.io/crates/bitcoin_hashes]
-//! tokios for tcp connections
-//! rust_bitcoin for de/serialization, parsing and executing on data structures and network messages
-
-pub mod message;
-
-use hex::decode as hex_decode;
-use crate::message::address::Address;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr, TcpStream};
-use crate::message::version::VersionMessage;
-use crate::message::command::CommandString;
-use crate::message::{RawMessage, Payload, Magic};
-use crate::message::filterload::FilterLoad;
-use crate::message::getdata::GetData;
-use std::io::{Write, Read};
-use std::thread::sleep;
-use std::time;
-use log::info;
-
-const IO_BUFFER_SIZE: usize = 1024 * 1024;
-
-fn main() {
-    simple_logger::init().unwrap();
-    let remote = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 7)), 8333);
-    //组装一个version
-    let version_message = VersionMessage {
-        version: 70001,
-        services: 0,
-        timestamp: 1415484102,
-        receiver: Address::new(&remote, 1),
-        // sender is only dummy
-        sender: Address::new(&remote, 1),
-        nonce: 0,
-        bytes: 0x1b,
-        user_agent: "/Bitcoin.org Example:0.9.3/".to_string(),
-        start_height: 329107,
-        relay: true,
-    };
-
-    let raw_version = RawMessage::new(Magic::Main,
-                                      CommandString("version".to_owned()),
-                                      Payload::Version(version_message),
-    );
-    let vec_version = raw_version.combine();
-    info!("vec_version {:02x?}", &vec_version);
+//! The node is picked from the address book in `peers.dat`, which learns from the `addr` messages
+//! of the node and is saved again on exit. On the first start the book is filled from the DNS seeds.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitcoin_p2p::{handshake, HandshakeConfig, Nonces, PeerInfo, ServiceFlags};
+use bitcoin_p2p::message::command::CommandString;
+use bitcoin_p2p::{RawMessage, Payload};
+use bitcoin_p2p::message::filterload::{FilterLoad, BloomFlags};
+use bitcoin_p2p::message::addrv2::{AddrV2, AddrV2List};
+use bitcoin_p2p::message::getdata::GetData;
+use bitcoin_p2p::message::inventory::Inventory;
+use bitcoin_p2p::{MessageCodec, Error};
+use bitcoin_p2p::NetworkParams;
+use bitcoin_p2p::{Keepalive, KeepaliveConfig};
+use bitcoin_p2p::{sync_headers, HeaderChain, FileStore, ChainEvent};
+use bitcoin_p2p::TxRelay;
+use bitcoin_p2p::{AddrMan, AddrSource};
+use bitcoin_p2p::{bootstrap, SystemResolver};
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
+use futures::SinkExt;
+use log::{debug, info, error};
+
+const PEERS_FILE: &str = "peers.dat";
+/// Addresses tried before giving up
+const CONNECT_TRIES: usize = 10;
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = simple_logger::init() {
+        eprintln!("Failed to set up logging: {}", e);
+    }
+    match run().await {
+        Ok(()) => info!("Terminated."),
+        Err(e) => error!("Terminated: {}", e),
+    }
+}
 
+async fn run() -> Result<(), Error> {
+    let network = NetworkParams::mainnet();
+    //上次存下来的地址 没有的话从空的开始
+    let mut addrman = AddrMan::load(PEERS_FILE, &network).unwrap_or_else(|e| {
+        info!("Starting with an empty address book: {}", e);
+        AddrMan::new()
+    });
+    info!("{} known addresses, {} tried", addrman.len(), addrman.tried_count());
+    let result = session(&network, &mut addrman).await;
+    //出错了也把学到的地址存下来
+    match addrman.save(PEERS_FILE, &network) {
+        Ok(()) => info!("Saved {} addresses", addrman.len()),
+        Err(e) => error!("Failed to save the address book: {}", e),
+    }
+    result
+}
 
-    //组装一个Verack Verack没有值
-    let raw_verack = RawMessage::new(Magic::Main,
-                                     CommandString("verack".to_owned()),
-                                     Payload::Verack);
-    let vec_verack = raw_verack.combine();
-    info!("vec_verack {:02x?}", &vec_verack);
+async fn session(network: &NetworkParams, addrman: &mut AddrMan) -> Result<(), Error> {
+    let network = network.clone();
+    //version 和 verack 由 handshake 负责
+    let mut config = HandshakeConfig::new(&network);
+    config.user_agent = "/Bitcoin.org Example:0.9.3/".to_string();
+    config.start_height = 329107;
+    //filterload 只有开了 bloom 的节点才接受
+    config.required_services = ServiceFlags::NETWORK | ServiceFlags::BLOOM;
+    let nonces = Nonces::new();
 
     //组装一个filterload
-
-    //因为没有搞清楚机理  自己根据固定数据拼装一个filterload
+    //和 example 里的数据一样
     //send("filterload",
     //"02"  # ........ Filter bytes: 2
     //+ "b50f" # ....... Filter: 1010 1101 1111 0000
@@ -70,67 +77,145 @@ fn main() {
     //+ "00000000" # ... nTweak: 0/none
     //+ "00" # ......... nFlags: BLOOM_UPDATE_NONE
     //)
-    let filterload_hex = "02b50f0b0000000000000000";
-    let filterload_vec = hex_decode(filterload_hex).unwrap();
-    info!("filterload_vec {:?}", filterload_vec);
-    let raw_filterload = RawMessage::new(Magic::Main,
+    //自己的地址可以用 BloomFilter::new(...).insert_pubkey(...) 生成
+    let filterload = FilterLoad {
+        filter: vec![0xb5, 0x0f],
+        n_hash_funcs: 11,
+        n_tweak: 0,
+        n_flags: BloomFlags::None,
+    };
+    info!("filterload {:?}", filterload);
+    let raw_filterload = RawMessage::new(&network,
                                          CommandString("filterload".to_owned()),
-                                         Payload::FilterLoad(FilterLoad(filterload_vec)));
-    let vec_filterload = raw_filterload.combine();
+                                         Payload::FilterLoad(filterload));
+    let vec_filterload = raw_filterload.combine()?;
     info!("vec_verack {:02x?}", &vec_filterload);
 
-    //组装一个getdata 为了快速实现 也采用上述方法
+    //第一次启动 地址簿是空的, 问 DNS seed 要提供这些服务的节点
+    if addrman.is_empty() {
+        let added = bootstrap(&SystemResolver, &network, config.required_services, addrman, unix_time()).await;
+        info!("Got {} addresses from the DNS seeds", added);
+    }
+    let (mut framed, peer) = connect(addrman, &config, &nonces).await?;
+    let remote = peer.address;
+    info!("Handshake done, peer version {} agent {} services {}", peer.version, peer.user_agent(), peer.services());
+    //顺便要一些别的节点的地址
+    framed.send(RawMessage::new(&network, CommandString("getaddr".to_owned()), Payload::GetAddr)).await?;
+
+    //ping/pong 由 keepalive 处理 连接不会因为太久没消息被节点断开
+    let mut keepalive = Keepalive::new(&network, KeepaliveConfig::default());
+
+    //先同步区块头 知道最新的区块是哪个
+    //区块头存在文件里 下次启动从上次的位置继续
+    let store = FileStore::open("headers.dat", &network)?;
+    let mut chain = HeaderChain::with_store(network.clone(), store);
+    info!("Loaded headers up to height {}", chain.height());
+    let on_event = |event: &ChainEvent| match event {
+        ChainEvent::Connected(entry) => debug!("connected {} at {}", entry.hash, entry.height),
+        ChainEvent::Disconnected(entry) => info!("reorg: disconnected {} at {}", entry.hash, entry.height),
+    };
+    let added = sync_headers(&mut framed, &mut keepalive, &mut chain, Duration::from_secs(60), on_event).await?;
+    info!("Synced {} headers, tip {} at height {}", added, chain.tip().hash, chain.height());
+
+    //组装一个getdata
     //send("getdata",
     //     "01" # ................................. Number of inventories: 1
     //    + "03000000" # ........................... Inventory type: filtered block
     //    + "a4deb66c0d726b0aefb03ed51be407fb"
     //   + "ad7331c6e8f9eef231b7000000000000" # ... Block header hash
     //)
-    let getdata_hex = "0103000000a4deb66c0d726b0aefb03ed51be407fbad7331c6e8f9eef231b7000000000000";
-    let getdata_vec = hex_decode(getdata_hex).unwrap();
-    info!("getdata_vec {:?}", getdata_vec);
-    let raw_getdata = RawMessage::new(Magic::Main,
+    //以前这里手动填 hash, 现在直接要最新的区块
+    let getdata = GetData(vec![Inventory::FilteredBlock(chain.tip().hash)]);
+    info!("getdata {:?}", getdata);
+    let raw_getdata = RawMessage::new(&network,
                                       CommandString("getdata".to_owned()),
-                                      Payload::GetData(GetData(getdata_vec)));
-    let vec_getdata = raw_getdata.combine();
-    info!("vec_verack {:02x?}", &vec_getdata);
-
-    match TcpStream::connect("192.168.1.7:8333") {
-        Ok(mut stream) => {
-            info!("Successfully connected to server in port 8333");
-
-            stream.write(&vec_version).unwrap();
-            info!("Sent version, awaiting reply...");
-
-            sleep(time::Duration::from_secs(1));
-
-            stream.write(&vec_verack).unwrap();
-            info!("Sent vec_verack, awaiting reply...");
-
-            stream.write(&vec_filterload).unwrap();
-            info!("Sent vec_filterload, awaiting reply...");
-
-            stream.write(&vec_getdata).unwrap();
-            info!("Sent vec_getdata, awaiting reply...");
-
-            let mut iobuf = vec!(0u8; IO_BUFFER_SIZE);
-            match stream.read(iobuf.as_mut_slice()) {
-                Ok(len) => {
-                    info!("{}", &len);
-                    info!("{:02x?}", iobuf[0..len].to_vec())
-                }
-                Err(e) => {
-                    info!("Failed to receive data: {}", e);
+                                      Payload::GetData(getdata));
+
+    framed.send(raw_filterload).await?;
+    info!("Sent vec_filterload, awaiting reply...");
+
+    framed.send(raw_getdata).await?;
+    info!("Sent vec_getdata, awaiting reply...");
+
+    //自己的交易用 relay.announce(tx) 发出去, 对方的 getdata 由 relay 回答
+    let relay = TxRelay::new(&network);
+
+    //codec 负责拆包 半条消息和粘在一起的消息都能正确处理
+    let source = AddrSource::Peer(AddrV2::from_ip(remote.ip()));
+    let result = 'receive: loop {
+        match keepalive.next(&mut framed).await {
+            Ok(Some(message)) => {
+                info!("received {:?}", message);
+                match message.payload() {
+                    Payload::MerkleBlock(merkle_block) => match merkle_block.extract_matches() {
+                        Ok(txids) => info!("matched transactions {:?}", txids),
+                        Err(e) => info!("Invalid merkleblock: {}", e),
+                    },
+                    Payload::GetData(getdata) => {
+                        for reply in relay.handle_getdata(getdata) {
+                            if let Err(e) = framed.send(reply).await {
+                                break 'receive Err(e);
+                            }
+                        }
+                    }
+                    Payload::Addr(addr) => {
+                        let added = addrman.add(&AddrV2List::from(addr.clone()).0, &source, unix_time());
+                        info!("{} new addresses", added);
+                    }
+                    Payload::AddrV2(addrv2) => {
+                        let added = addrman.add(&addrv2.0, &source, unix_time());
+                        info!("{} new addresses", added);
+                    }
+                    _ => {}
                 }
             }
+            Ok(None) => {
+                info!("Peer closed the connection");
+                break Ok(());
+            }
+            //单条消息解析失败 连接还能用
+            Err(ref e) if !e.is_fatal() => info!("Failed to parse reply: {}", e),
+            Err(e) => break Err(e),
         }
-        Err(e) => {
-            info!("Failed to connect: {}", e);
+    };
+    info!("ping stats {:?}", keepalive.stats());
+    addrman.connected(&remote, unix_time());
+    result
+}
+
+/// Connect to addresses picked from `addrman` until one completes the handshake
+async fn connect(addrman: &mut AddrMan, config: &HandshakeConfig, nonces: &Nonces)
+    -> Result<(Framed<TcpStream, MessageCodec>, PeerInfo), Error>
+{
+    for _ in 0..CONNECT_TRIES {
+        let remote = match addrman.select(false, unix_time()) {
+            Some(remote) => remote,
+            None => break,
+        };
+        addrman.attempt(&remote, true, unix_time());
+        match tokio::time::timeout(Duration::from_secs(30), connect_to(remote, config, nonces)).await {
+            Ok(Ok((framed, peer))) => {
+                addrman.good(&remote, unix_time());
+                addrman.set_services(&remote, peer.services());
+                return Ok((framed, peer));
+            }
+            Ok(Err(e)) => info!("Failed to connect to {}: {}", remote, e),
+            Err(_) => info!("Failed to connect to {}: timed out", remote),
         }
     }
-    info!("Terminated.");
+    Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, "no peer to connect to")))
 }
 
+async fn connect_to(remote: SocketAddr, config: &HandshakeConfig, nonces: &Nonces)
+    -> Result<(Framed<TcpStream, MessageCodec>, PeerInfo), Error>
+{
+    let stream = TcpStream::connect(&remote).await?;
+    info!("Successfully connected to server {}", remote);
+    let mut framed = Framed::new(stream, MessageCodec::new(&config.network));
+    let peer = handshake(&mut framed, remote, config, nonces).await?;
+    Ok((framed, peer))
+}
 
-
-
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+}
@@ -0,0 +1,809 @@
+//! Keeping a number of outbound connections alive
+//!
+//! 流程
+//!     候选地址交给 `PeerManager`, 它一直保持 `target_outbound` 个连接
+//!     连不上或者断开的地址等一段时间再试, 等待时间每次翻倍 (有上限) 再加随机抖动
+//!     握手被拒 (版本太旧, 缺服务, 连到自己) 或者违反协议的地址不再重连, 除非重新 `add`
+//!     `add` 的地址不够凑满名额的时候从 `AddrMan` 里挑, 挑出来的地址断了就换下一个, 不退避重连
+//!     对方发来的 addr/addrv2 放进 `AddrMan`, 连上的地址移到 tried 表
+//!
+//! Messages of all connections arrive on one `PeerEvent` channel, tagged with the address they came from.
+//! Connections are opened through the `Connector` trait, TCP unless `PeerManager::with_connector` says otherwise.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::StreamExt;
+use futures::future::{AbortHandle, Abortable};
+use rand::Rng;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use log::{debug, info};
+use crate::addrman::{AddrMan, AddrSource};
+use crate::error::Error;
+use crate::handshake::{Nonces, PeerInfo};
+use crate::message::{RawMessage, Payload};
+use crate::message::addrv2::{AddrV2, AddrV2List};
+use crate::peer::{Peer, PeerConfig, DisconnectReason};
+
+/// How often the manager looks for missing connections when nothing else wakes it up
+const TICK: Duration = Duration::from_secs(1);
+/// Picks from the address manager per round, addresses already known to the manager are skipped
+const SELECT_TRIES: usize = 100;
+
+pub type ConnectFuture<'a> = Pin<Box<dyn Future<Output = Result<Peer, Error>> + Send + 'a>>;
+
+/// Opens a connection to an address and runs the handshake on it
+pub trait Connector: Send + Sync {
+    fn connect<'a>(&'a self, address: SocketAddr, config: &'a PeerConfig, nonces: &'a Nonces) -> ConnectFuture<'a>;
+}
+
+/// Plain TCP, see `Peer::connect`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    fn connect<'a>(&'a self, address: SocketAddr, config: &'a PeerConfig, nonces: &'a Nonces) -> ConnectFuture<'a> {
+        Box::pin(Peer::connect(address, config, nonces))
+    }
+}
+
+/// What the manager aims for
+#[derive(Clone, Debug)]
+pub struct ManagerConfig {
+    /// How each connection is run
+    pub peer: PeerConfig,
+    /// Number of outbound connections to keep
+    pub target_outbound: usize,
+    /// Wait after the first failure
+    pub min_backoff: Duration,
+    /// Longest wait between two attempts at the same address
+    pub max_backoff: Duration,
+    /// Give up on a TCP connect plus handshake after this time
+    pub connect_timeout: Duration,
+    /// Events waiting to be taken before the connections stop reading
+    pub event_queue: usize,
+}
+
+impl ManagerConfig {
+    pub fn new(peer: PeerConfig) -> ManagerConfig {
+        ManagerConfig {
+            peer,
+            target_outbound: 8,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10 * 60),
+            connect_timeout: Duration::from_secs(30),
+            event_queue: 256,
+        }
+    }
+}
+
+/// What happened on one of the connections
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The handshake with a peer is done
+    Connected(PeerInfo),
+    /// A message from a connected peer, other than ping and pong
+    Message(SocketAddr, RawMessage),
+    /// A connection ended or could not be established
+    Disconnected(SocketAddr, DisconnectReason),
+}
+
+/// Where an address stands
+#[derive(Clone, Debug)]
+pub enum PeerState {
+    /// Not connected, the next attempt is due at `retry_at`
+    Waiting {
+        retry_at: Instant,
+        /// Failed attempts in a row
+        failures: u32,
+    },
+    /// Connecting or in the middle of the handshake
+    Connecting,
+    Connected(PeerInfo),
+    /// Rejected in the handshake or misbehaved, not tried again
+    Dropped(DisconnectReason),
+}
+
+/// One entry of `PeerManager::list`
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+    pub address: SocketAddr,
+    pub state: PeerState,
+}
+
+enum Slot {
+    Waiting(Instant),
+    Connecting(AbortHandle),
+    Connected {
+        info: PeerInfo,
+        sender: mpsc::Sender<Payload>,
+        abort: AbortHandle,
+    },
+    Dropped(DisconnectReason),
+}
+
+struct Candidate {
+    slot: Slot,
+    failures: u32,
+    /// tells the task of an earlier `add` of the same address apart
+    generation: u64,
+    /// given through `add` rather than picked from the address manager
+    manual: bool,
+}
+
+struct State {
+    candidates: BTreeMap<SocketAddr, Candidate>,
+    target: usize,
+    next_generation: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    config: ManagerConfig,
+    nonces: Nonces,
+    connector: Box<dyn Connector>,
+    events: mpsc::Sender<PeerEvent>,
+    state: Mutex<State>,
+    // 锁的顺序: 先 state 后 addrman
+    addrman: Mutex<AddrMan>,
+}
+
+/// Handle on the connection manager; clones control the same manager
+///
+/// 用法
+///     let addrman = AddrMan::load("peers.dat", &network).unwrap_or_else(|_| AddrMan::new());
+///     let (manager, mut events) = PeerManager::start(ManagerConfig::new(PeerConfig::new(&network)), Nonces::new(), addrman);
+///     manager.add(remote);
+///     while let Some(event) = events.next().await {
+///         match event {
+///             PeerEvent::Message(from, message) => ...,
+///             ...
+///         }
+///     }
+///     manager.addrman().save("peers.dat", &network)?;
+///
+/// The manager stops, closing all connections, on `shutdown` or when the last handle is dropped.
+#[derive(Clone)]
+pub struct PeerManager {
+    shared: Arc<Shared>,
+    // 只有一个 sender, 克隆出来的 sender 各自占一个位置, 就合并不了唤醒了
+    wake: Arc<Mutex<mpsc::Sender<()>>>,
+}
+
+impl PeerManager {
+    /// Start the manager task. Must be called from within a tokio runtime.
+    /// Outbound connections beyond the added addresses are picked from `addrman`.
+    pub fn start(config: ManagerConfig, nonces: Nonces, addrman: AddrMan) -> (PeerManager, mpsc::Receiver<PeerEvent>) {
+        PeerManager::with_connector(config, nonces, addrman, TcpConnector)
+    }
+
+    /// Like `start`, opening connections through `connector`
+    pub fn with_connector<C>(config: ManagerConfig, nonces: Nonces, addrman: AddrMan, connector: C)
+        -> (PeerManager, mpsc::Receiver<PeerEvent>)
+        where C: Connector + 'static
+    {
+        let (events, events_rx) = mpsc::channel(config.event_queue.max(1));
+        let (wake, wake_rx) = mpsc::channel(1);
+        let state = State {
+            candidates: BTreeMap::new(),
+            target: config.target_outbound,
+            next_generation: 0,
+            shutdown: false,
+        };
+        let shared = Arc::new(Shared {
+            config,
+            nonces,
+            connector: Box::new(connector),
+            events,
+            state: Mutex::new(state),
+            addrman: Mutex::new(addrman),
+        });
+        tokio::spawn(drive(shared.clone(), wake_rx));
+        (PeerManager { shared, wake: Arc::new(Mutex::new(wake)) }, events_rx)
+    }
+
+    /// Add a candidate address, or make a dropped or waiting one eligible right away
+    pub fn add(&self, address: SocketAddr) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            let generation = state.next_generation;
+            match state.candidates.get_mut(&address) {
+                Some(candidate) => {
+                    if let Slot::Waiting(_) | Slot::Dropped(_) = candidate.slot {
+                        candidate.slot = Slot::Waiting(Instant::now());
+                        candidate.failures = 0;
+                    }
+                    candidate.manual = true;
+                }
+                None => {
+                    state.next_generation += 1;
+                    let candidate = Candidate { slot: Slot::Waiting(Instant::now()), failures: 0, generation, manual: true };
+                    state.candidates.insert(address, candidate);
+                }
+            }
+        }
+        self.wake();
+    }
+
+    /// Forget an address, closing its connection if there is one. No `Disconnected` event follows.
+    pub fn remove(&self, address: &SocketAddr) -> bool {
+        let removed = self.shared.state.lock().unwrap().candidates.remove(address);
+        match removed {
+            Some(candidate) => {
+                candidate.slot.abort();
+                self.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// All known addresses and where they stand, ordered by address
+    pub fn list(&self) -> Vec<PeerStatus> {
+        let state = self.shared.state.lock().unwrap();
+        state.candidates.iter().map(|(address, candidate)| {
+            let state = match candidate.slot {
+                Slot::Waiting(retry_at) => PeerState::Waiting { retry_at, failures: candidate.failures },
+                Slot::Connecting(_) => PeerState::Connecting,
+                Slot::Connected { ref info, .. } => PeerState::Connected(info.clone()),
+                Slot::Dropped(ref reason) => PeerState::Dropped(reason.clone()),
+            };
+            PeerStatus { address: *address, state }
+        }).collect()
+    }
+
+    /// The peers we are connected to
+    pub fn connected(&self) -> Vec<PeerInfo> {
+        let state = self.shared.state.lock().unwrap();
+        state.candidates.values().filter_map(|candidate| match candidate.slot {
+            Slot::Connected { ref info, .. } => Some(info.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    /// Change the number of outbound connections to keep. Extra connections are not closed.
+    pub fn set_target(&self, target: usize) {
+        self.shared.state.lock().unwrap().target = target;
+        self.wake();
+    }
+
+    /// Send `payload` to one connected peer, waiting while its outbound queue is full
+    pub async fn send(&self, address: &SocketAddr, payload: Payload) -> Result<(), Error> {
+        let sender = {
+            let state = self.shared.state.lock().unwrap();
+            match state.candidates.get(address).map(|candidate| &candidate.slot) {
+                Some(Slot::Connected { sender, .. }) => sender.clone(),
+                _ => return Err(Error::Disconnected),
+            }
+        };
+        let mut sender = sender;
+        sender.send(payload).await.map_err(|_| Error::Disconnected)
+    }
+
+    /// Send `payload` to every connected peer, returns to how many it went
+    pub async fn broadcast(&self, payload: Payload) -> usize {
+        let senders: Vec<_> = {
+            let state = self.shared.state.lock().unwrap();
+            state.candidates.values().filter_map(|candidate| match candidate.slot {
+                Slot::Connected { ref sender, .. } => Some(sender.clone()),
+                _ => None,
+            }).collect()
+        };
+        let mut sent = 0;
+        for mut sender in senders {
+            if sender.send(payload.clone()).await.is_ok() {
+                sent += 1;
+            }
+        }
+        sent
+    }
+
+    /// The address book the manager picks from and adds heard of addresses to, e.g. for saving it.
+    /// Connections wait while the guard is held.
+    pub fn addrman(&self) -> MutexGuard<'_, AddrMan> {
+        self.shared.addrman.lock().unwrap()
+    }
+
+    /// Close all connections and stop the manager
+    pub fn shutdown(&self) {
+        self.shared.shutdown();
+    }
+
+    fn wake(&self) {
+        // 已经有一个没处理的唤醒就够了
+        let _ = self.wake.lock().unwrap().try_send(());
+    }
+}
+
+impl Slot {
+    fn abort(&self) {
+        match *self {
+            Slot::Connecting(ref abort) | Slot::Connected { ref abort, .. } => abort.abort(),
+            _ => {}
+        }
+    }
+}
+
+impl Shared {
+    fn shutdown(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.shutdown = true;
+        for candidate in state.candidates.values() {
+            candidate.slot.abort();
+        }
+    }
+
+    /// Wait before the next attempt after `failures` failures in a row:
+    /// `min_backoff` doubled for each failure, capped at `max_backoff`, of which a random part up to a half is taken off
+    fn backoff(&self, failures: u32) -> Duration {
+        let min = self.config.min_backoff.as_millis() as u64;
+        let max = self.config.max_backoff.as_millis() as u64;
+        let exponent = failures.saturating_sub(1).min(32);
+        let delay = min.saturating_mul(1 << exponent).min(max).max(1);
+        let jitter = rand::thread_rng().gen_range(0, delay / 2 + 1);
+        Duration::from_millis(delay - jitter)
+    }
+
+    /// Start connecting to addresses that are due until `target` connections are up or on the way,
+    /// then to addresses picked from the address manager
+    fn fill(self: &Arc<Shared>) {
+        let mut state = self.state.lock().unwrap();
+        if state.shutdown {
+            return;
+        }
+        let active = state.candidates.values()
+            .filter(|candidate| matches!(candidate.slot, Slot::Connecting(_) | Slot::Connected { .. }))
+            .count();
+        if active >= state.target {
+            return;
+        }
+        let now = Instant::now();
+        let mut due: Vec<(Instant, SocketAddr)> = state.candidates.iter().filter_map(|(address, candidate)| match candidate.slot {
+            Slot::Waiting(retry_at) if retry_at <= now => Some((retry_at, *address)),
+            _ => None,
+        }).collect();
+        due.sort_by(|a, b| a.0.into_std().cmp(&b.0.into_std()).then(a.1.cmp(&b.1)));
+        let mut wanted = state.target - active;
+        let time = unix_time();
+        let mut addrman = self.addrman.lock().unwrap();
+        for (_, address) in due.into_iter().take(wanted) {
+            addrman.attempt(&address, true, time);
+            self.connect(state.candidates.get_mut(&address).expect("collected from the map"), address);
+            wanted -= 1;
+        }
+
+        // 和 Bitcoin Core 一样挑不到合适的就多挑几次, 已经在名单上的 (连着的, 等着重连的, 被丢掉的) 跳过
+        for _ in 0..SELECT_TRIES {
+            if wanted == 0 {
+                break;
+            }
+            let address = match addrman.select(false, time) {
+                Some(address) => address,
+                None => break,
+            };
+            if state.candidates.contains_key(&address) {
+                continue;
+            }
+            addrman.attempt(&address, true, time);
+            let generation = state.next_generation;
+            state.next_generation += 1;
+            let candidate = state.candidates.entry(address)
+                .or_insert(Candidate { slot: Slot::Waiting(now), failures: 0, generation, manual: false });
+            self.connect(candidate, address);
+            wanted -= 1;
+        }
+    }
+
+    fn connect(self: &Arc<Shared>, candidate: &mut Candidate, address: SocketAddr) {
+        let (abort, registration) = AbortHandle::new_pair();
+        candidate.slot = Slot::Connecting(abort.clone());
+        debug!("connecting to {}", address);
+        tokio::spawn(Abortable::new(run(self.clone(), address, candidate.generation, abort), registration));
+    }
+
+    /// The task of `generation` at `address` is done
+    fn finished(&self, address: SocketAddr, generation: u64, reason: &DisconnectReason) {
+        let mut state = self.state.lock().unwrap();
+        let candidate = match state.candidates.get_mut(&address) {
+            Some(candidate) if candidate.generation == generation => candidate,
+            _ => return,
+        };
+        if is_permanent(reason) {
+            info!("dropping {}: {}", address, reason);
+            candidate.slot = Slot::Dropped(reason.clone());
+        } else if !candidate.manual {
+            // 下次从 addrman 里重新挑
+            debug!("{}: {}", address, reason);
+            state.candidates.remove(&address);
+        } else {
+            candidate.failures += 1;
+            let wait = self.backoff(candidate.failures);
+            debug!("{}: {}, retrying in {:?}", address, reason, wait);
+            candidate.slot = Slot::Waiting(Instant::now() + wait);
+        }
+    }
+
+    /// Put the addresses of an `addr` or `addrv2` from `from` into the address manager
+    fn learn(&self, from: SocketAddr, message: &RawMessage) {
+        let converted;
+        let entries = match *message.payload() {
+            Payload::AddrV2(ref addrv2) => &addrv2.0,
+            Payload::Addr(ref addr) => {
+                converted = AddrV2List::from(addr.clone());
+                &converted.0
+            }
+            _ => return,
+        };
+        let source = AddrSource::Peer(AddrV2::from_ip(from.ip()));
+        let added = self.addrman.lock().unwrap().add(entries, &source, unix_time());
+        debug!("{} sent {} addresses, {} added", from, entries.len(), added);
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+}
+
+/// Peers rejected in the handshake or caught breaking the protocol are not tried again
+fn is_permanent(reason: &DisconnectReason) -> bool {
+    match *reason {
+        DisconnectReason::Error(ref e) => matches!(**e, Error::Handshake(_) | Error::Misbehavior(_) | Error::ProtocolViolation(_)),
+        _ => false,
+    }
+}
+
+async fn drive(shared: Arc<Shared>, mut wake: mpsc::Receiver<()>) {
+    loop {
+        // 所有 PeerManager 都没了 wake 就关了
+        if let Ok(None) = tokio::time::timeout(TICK, wake.recv()).await {
+            shared.shutdown();
+        }
+        if shared.state.lock().unwrap().shutdown {
+            return;
+        }
+        shared.fill();
+    }
+}
+
+/// Connect to `address` and pass on its messages until the connection ends
+async fn run(shared: Arc<Shared>, address: SocketAddr, generation: u64, abort: AbortHandle) {
+    let mut events = shared.events.clone();
+    let connect = shared.connector.connect(address, &shared.config.peer, &shared.nonces);
+    let mut peer = match tokio::time::timeout(shared.config.connect_timeout, connect).await {
+        Ok(Ok(peer)) => peer,
+        Ok(Err(e)) => return fail(&shared, address, generation, &mut events, e).await,
+        Err(_) => return fail(&shared, address, generation, &mut events, Error::Timeout).await,
+    };
+
+    let info = peer.info().clone();
+    {
+        let mut state = shared.state.lock().unwrap();
+        match state.candidates.get_mut(&address) {
+            Some(candidate) if candidate.generation == generation => {
+                candidate.slot = Slot::Connected { info: info.clone(), sender: peer.sender(), abort };
+                candidate.failures = 0;
+            }
+            _ => return,
+        }
+    }
+    {
+        let mut addrman = shared.addrman.lock().unwrap();
+        addrman.good(&address, unix_time());
+        addrman.set_services(&address, info.services());
+    }
+    info!("connected to {} ({})", address, info.user_agent());
+    let mut listening = events.send(PeerEvent::Connected(info)).await.is_ok();
+    while listening {
+        match peer.next().await {
+            Some(message) => {
+                shared.learn(address, &message);
+                listening = events.send(PeerEvent::Message(address, message)).await.is_ok();
+            }
+            None => break,
+        }
+    }
+    shared.addrman.lock().unwrap().connected(&address, unix_time());
+    // 没人收事件了也要走 finished, 不然这个地址一直占着一个 outbound 名额
+    // 连接自己结束的时候原因已经有了, 这时再 disconnect 不能把它换成 Requested
+    if peer.disconnect_reason().is_none() {
+        peer.disconnect();
+    }
+    let reason = peer.disconnect_reason().unwrap_or(DisconnectReason::Closed);
+    shared.finished(address, generation, &reason);
+    if listening {
+        let _ = events.send(PeerEvent::Disconnected(address, reason)).await;
+    }
+}
+
+async fn fail(shared: &Shared, address: SocketAddr, generation: u64, events: &mut mpsc::Sender<PeerEvent>, e: Error) {
+    let reason = DisconnectReason::Error(Arc::new(e));
+    shared.finished(address, generation, &reason);
+    let _ = events.send(PeerEvent::Disconnected(address, reason)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkParams;
+    use std::collections::HashMap;
+    use std::io;
+    use bitcoin::consensus::serialize;
+    use futures::future;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use tokio_util::codec::Framed;
+    use crate::codec::MessageCodec;
+    use crate::handshake::HandshakeError;
+    use crate::message::{MessageHeader, MAINNET};
+    use crate::message::addr::Addr;
+    use crate::message::address::Address;
+    use crate::message::command::CommandString;
+    use crate::message::services::ServiceFlags;
+    use crate::message::version::{VersionMessage, PROTOCOL_VERSION};
+
+    #[derive(Clone, Copy)]
+    enum Answer {
+        Refuse,
+        Reject,
+        Accept,
+        Hang,
+    }
+
+    /// 不走网络的 connector, 按地址给固定的结果
+    #[derive(Clone, Default)]
+    struct FakeConnector(Arc<Fake>);
+
+    #[derive(Default)]
+    struct Fake {
+        answers: Mutex<HashMap<SocketAddr, Answer>>,
+        attempts: Mutex<Vec<(SocketAddr, Instant)>>,
+        /// 接受的连接对面那一头, 写进去就是对方发的消息
+        remotes: Mutex<Vec<UnixStream>>,
+    }
+
+    impl FakeConnector {
+        fn answer(&self, address: SocketAddr, answer: Answer) {
+            self.0.answers.lock().unwrap().insert(address, answer);
+        }
+
+        fn attempts(&self) -> Vec<(SocketAddr, Instant)> {
+            self.0.attempts.lock().unwrap().clone()
+        }
+
+        fn remote(&self) -> UnixStream {
+            self.0.remotes.lock().unwrap().pop().expect("an accepted connection")
+        }
+    }
+
+    impl Connector for FakeConnector {
+        fn connect<'a>(&'a self, address: SocketAddr, config: &'a PeerConfig, _: &'a Nonces) -> ConnectFuture<'a> {
+            self.0.attempts.lock().unwrap().push((address, Instant::now()));
+            let answer = self.0.answers.lock().unwrap().get(&address).cloned().unwrap_or(Answer::Refuse);
+            match answer {
+                Answer::Refuse => Box::pin(future::ready(Err(Error::Io(io::ErrorKind::ConnectionRefused.into())))),
+                Answer::Reject => Box::pin(future::ready(Err(Error::Handshake(HandshakeError::ObsoleteVersion(209))))),
+                Answer::Hang => Box::pin(future::pending()),
+                Answer::Accept => {
+                    let (local, remote) = UnixStream::pair().unwrap();
+                    self.0.remotes.lock().unwrap().push(remote);
+                    let peer = Peer::spawn(Framed::new(local, MessageCodec::new(&config.handshake.network)), info(address), config);
+                    Box::pin(future::ready(Ok(peer)))
+                }
+            }
+        }
+    }
+
+    fn info(address: SocketAddr) -> PeerInfo {
+        let remote = VersionMessage::new(PROTOCOL_VERSION, ServiceFlags::NETWORK, 0, Address::new(&address, ServiceFlags::NONE),
+                                         Address::new(&address, ServiceFlags::NETWORK), 1, "/test/".to_owned(), 0, true);
+        PeerInfo { address, version: PROTOCOL_VERSION, remote, addrv2: false, inbound: false }
+    }
+
+    fn address(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 8333))
+    }
+
+    fn config() -> ManagerConfig {
+        let mut config = ManagerConfig::new(PeerConfig::new(&NetworkParams::mainnet()));
+        config.min_backoff = Duration::from_millis(20);
+        config.max_backoff = Duration::from_millis(100);
+        config
+    }
+
+    fn state(manager: &PeerManager, address: SocketAddr) -> PeerState {
+        manager.list().into_iter().find(|status| status.address == address).expect("a known address").state
+    }
+
+    fn generation(manager: &PeerManager, address: SocketAddr) -> u64 {
+        manager.shared.state.lock().unwrap().candidates[&address].generation
+    }
+
+    async fn until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    async fn disconnected(events: &mut mpsc::Receiver<PeerEvent>) -> (SocketAddr, DisconnectReason) {
+        match events.recv().await {
+            Some(PeerEvent::Disconnected(address, reason)) => (address, reason),
+            other => panic!("expected a disconnect, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn backoff_doubles_up_to_the_limit() {
+        let (manager, _events) = PeerManager::with_connector(config(), Nonces::new(), AddrMan::new(), FakeConnector::default());
+        for &(failures, full) in &[(1, 20), (2, 40), (3, 80), (4, 100), (40, 100)] {
+            let full = Duration::from_millis(full);
+            let waits: Vec<Duration> = (0..100).map(|_| manager.shared.backoff(failures)).collect();
+            // 抖动最多扣掉一半
+            assert!(waits.iter().all(|wait| *wait >= full / 2 && *wait <= full), "{} failures: {:?}", failures, waits);
+            assert!(waits.iter().any(|wait| *wait != waits[0]), "no jitter after {} failures", failures);
+        }
+    }
+
+    #[tokio::test]
+    async fn refused_address_is_retried_with_backoff() {
+        let connector = FakeConnector::default();
+        let (manager, mut events) = PeerManager::with_connector(config(), Nonces::new(), AddrMan::new(), connector.clone());
+        manager.add(address(1));
+        for failures in 1..=4 {
+            let (from, reason) = disconnected(&mut events).await;
+            assert_eq!(from, address(1));
+            assert!(matches!(reason, DisconnectReason::Error(ref e) if matches!(**e, Error::Io(_))));
+            let retry_at = match state(&manager, address(1)) {
+                PeerState::Waiting { retry_at, failures: f } => {
+                    assert_eq!(f, failures);
+                    retry_at
+                }
+                other => panic!("expected waiting, got {:?}", other),
+            };
+            let attempts = connector.attempts();
+            assert_eq!(attempts.len(), failures as usize);
+            let full = Duration::from_millis((20 << (failures - 1)).min(100));
+            let wait = retry_at - attempts[attempts.len() - 1].1;
+            assert!(wait >= full / 2 && wait <= full + Duration::from_millis(50), "{} failures: {:?}", failures, wait);
+
+            tokio::time::delay_until(retry_at).await;
+            manager.wake();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_address_is_dropped_until_added_again() {
+        let connector = FakeConnector::default();
+        connector.answer(address(2), Answer::Reject);
+        let (manager, mut events) = PeerManager::with_connector(config(), Nonces::new(), AddrMan::new(), connector.clone());
+        manager.add(address(2));
+        let (_, reason) = disconnected(&mut events).await;
+        assert!(matches!(reason, DisconnectReason::Error(ref e) if matches!(**e, Error::Handshake(HandshakeError::ObsoleteVersion(209)))));
+        assert!(matches!(state(&manager, address(2)), PeerState::Dropped(_)));
+
+        manager.wake();
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(connector.attempts().len(), 1);
+
+        manager.add(address(2));
+        disconnected(&mut events).await;
+        assert_eq!(connector.attempts().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn connection_ends_with_its_own_reason() {
+        let connector = FakeConnector::default();
+        connector.answer(address(3), Answer::Accept);
+        connector.answer(address(4), Answer::Accept);
+        let mut config = config();
+        config.target_outbound = 1;
+        let (manager, mut events) = PeerManager::with_connector(config, Nonces::new(), AddrMan::new(), connector.clone());
+
+        // 对方断开: 原因是 Closed 不是 Requested, 之后还会重连
+        manager.add(address(3));
+        assert!(matches!(events.recv().await, Some(PeerEvent::Connected(ref info)) if info.address == address(3)));
+        assert_eq!(manager.connected().len(), 1);
+        // 只关对方写的那一半, 不然我们的 ping 可能先碰到 broken pipe
+        let remote = connector.remote();
+        remote.shutdown(std::net::Shutdown::Write).unwrap();
+        let (_, reason) = disconnected(&mut events).await;
+        assert!(matches!(reason, DisconnectReason::Closed), "{:?}", reason);
+        assert!(matches!(state(&manager, address(3)), PeerState::Waiting { failures: 1, .. }));
+        manager.remove(&address(3));
+
+        // 违反协议: 不再重连
+        manager.add(address(4));
+        assert!(matches!(events.recv().await, Some(PeerEvent::Connected(_))));
+        let header = MessageHeader { magic: MAINNET, command: CommandString("block".to_owned()), length: 4_000_001, checksum: [0; 4] };
+        let mut remote = connector.remote();
+        remote.write_all(&serialize(&header)).await.unwrap();
+        let (from, reason) = disconnected(&mut events).await;
+        assert_eq!(from, address(4));
+        assert!(matches!(reason, DisconnectReason::Error(ref e) if matches!(**e, Error::Misbehavior(_))), "{:?}", reason);
+        assert!(matches!(state(&manager, address(4)), PeerState::Dropped(_)));
+    }
+
+    #[tokio::test]
+    async fn stale_generation_is_ignored() {
+        let connector = FakeConnector::default();
+        connector.answer(address(5), Answer::Hang);
+        let (manager, _events) = PeerManager::with_connector(config(), Nonces::new(), AddrMan::new(), connector.clone());
+        manager.add(address(5));
+        until(|| matches!(state(&manager, address(5)), PeerState::Connecting)).await;
+        let old = generation(&manager, address(5));
+
+        manager.remove(&address(5));
+        manager.add(address(5));
+        until(|| matches!(state(&manager, address(5)), PeerState::Connecting)).await;
+        let current = generation(&manager, address(5));
+        assert_ne!(old, current);
+
+        // 上一次 add 的 task 晚到的结果不算数
+        let reason = DisconnectReason::Error(Arc::new(Error::Handshake(HandshakeError::SelfConnection)));
+        manager.shared.finished(address(5), old, &reason);
+        assert!(matches!(state(&manager, address(5)), PeerState::Connecting));
+        manager.shared.finished(address(5), current, &reason);
+        assert!(matches!(state(&manager, address(5)), PeerState::Dropped(_)));
+    }
+
+    #[tokio::test]
+    async fn picks_from_the_address_manager() {
+        let connector = FakeConnector::default();
+        let good = SocketAddr::from(([1, 2, 3, 4], 8333));
+        let refused = SocketAddr::from(([5, 6, 7, 8], 8333));
+        connector.answer(good, Answer::Accept);
+        let time = unix_time();
+        let mut addrman = AddrMan::new();
+        addrman.add_manual(good, ServiceFlags::NONE, time);
+        addrman.add_manual(refused, ServiceFlags::NONE, time);
+        let mut config = config();
+        config.target_outbound = 1;
+        let (manager, mut events) = PeerManager::with_connector(config, Nonces::new(), addrman, connector.clone());
+
+        // 连不上的那个不退避重连, 直接换下一个
+        let mut failures = 0;
+        let info = loop {
+            match events.recv().await {
+                Some(PeerEvent::Connected(info)) => break info,
+                Some(PeerEvent::Disconnected(from, _)) => {
+                    assert_eq!(from, refused);
+                    failures += 1;
+                    assert!(manager.list().iter().all(|status| !matches!(status.state, PeerState::Waiting { .. })));
+                    assert_eq!(manager.addrman().get(&refused).unwrap().attempts, failures);
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+        };
+        assert_eq!(info.address, good);
+        {
+            let addrman = manager.addrman();
+            let entry = addrman.get(&good).unwrap();
+            assert!(entry.is_tried());
+            assert_eq!(entry.services, ServiceFlags::NETWORK);
+            assert_eq!(entry.attempts, 0);
+        }
+
+        // 对方发来的地址进了 addrman, 来源是对方
+        let heard = SocketAddr::from(([9, 9, 9, 9], 8333));
+        let addr = Payload::Addr(Addr(vec![(time, Address::new(&heard, ServiceFlags::NETWORK))]));
+        let message = RawMessage::new(&NetworkParams::mainnet(), addr.command(), addr).combine().unwrap();
+        let mut remote = connector.remote();
+        remote.write_all(&message).await.unwrap();
+        assert!(matches!(events.recv().await, Some(PeerEvent::Message(from, _)) if from == good));
+        let source = manager.addrman().get(&heard).expect("learned from the peer").source.clone();
+        assert_eq!(source, AddrSource::Peer(AddrV2::from_ip(good.ip())));
+
+        // 断开之后也不退避重连, 等 addrman 再挑到它
+        remote.shutdown(std::net::Shutdown::Write).unwrap();
+        let (from, _) = disconnected(&mut events).await;
+        assert_eq!(from, good);
+        assert!(manager.list().iter().all(|status| !matches!(status.state, PeerState::Waiting { .. })));
+    }
+}
@@ -1,18 +1,44 @@
-use bitcoin::consensus::serialize;
+use bitcoin::consensus::{serialize, deserialize, deserialize_partial, encode, Encodable, Decodable};
+use std::io;
+use crate::error::Error;
+use crate::message::command::CommandString;
+use crate::message::registry::MessageRegistry;
+use crate::network::NetworkParams;
 #[macro_use]
 pub mod version;
 pub mod address;
 pub mod command;
 pub mod filterload;
+#[macro_use]
+pub mod inventory;
 pub mod getdata;
+pub mod inv;
+pub mod notfound;
+pub mod merkleblock;
+pub mod getheaders;
+pub mod headers;
+pub mod addr;
+pub mod addrv2;
+pub mod services;
+pub mod registry;
+pub mod limits;
+
+// magic 的数值 和 serialize 之后的字节顺序相反
+// 例如 mainnet 线上是 F9 BE B4 D9, 按 u32 小端读出来就是 0xD9B4BEF9
+pub const MAINNET: u32 = 0xD9B4BEF9;
+pub const TESTNET: u32 = 0x0709110B;
+pub const TESTNET4: u32 = 0x283F161C;
+pub const REGTEST: u32 = 0xDAB5BFFA;
+pub const SIGNET: u32 = 0x40CF030A;
 
-pub const MAINNET: u32 = 0xF9BEB4D9;
-pub const TESTNET: u32 = 0xFABFB5DA;
+/// magic(4) + command(12) + length(4) + checksum(4)
+pub const HEADER_SIZE: usize = 24;
 
 /// 消息最终发出去的形态
 /// https://en.bitcoin.it/wiki/Protocol_documentation#Message_structure
 ///
 /// Message struct
+/// ```text
 ///     magic       NetworkString
 ///     command     ASCII string identifying the packet content
 ///     length      Length of payload in number of bytes
@@ -21,74 +47,243 @@ pub const TESTNET: u32 = 0xFABFB5DA;
 ///
 ///     magic:
 ///             F9 BE B4 D9 mainnet
-///             FA BF B5 DA Testnet
 ///             0B 11 09 07 testnet3
-///             F9 BE B4 FE namecoin
+///             1C 16 3F 28 testnet4
+///             FA BF B5 DA regtest (also the retired original testnet)
+///             0A 03 CF 40 default signet
 ///
 ///     payload: 具体消息
 ///
 ///     magic command payload 这三个为传入属性 其余两个为计算值
-///
+/// ```
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct RawMessage {
     magic: Magic,
     command: command::CommandString,
     payload: Payload,
 }
 
+/// The network a message belongs to
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Magic {
+    /// mainnet
     Main,
+    /// testnet3
     Testnet,
+    /// testnet4 (BIP94)
+    Testnet4,
+    /// regtest
+    Regtest,
+    /// The default signet
+    Signet,
+    /// Any other network, e.g. a custom signet whose magic is derived from its challenge script
+    Custom(u32),
+}
+
+impl Magic {
+    /// 网络类型对应的数字 序列化后就是线上的四个字节
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Magic::Main => MAINNET,
+            Magic::Testnet => TESTNET,
+            Magic::Testnet4 => TESTNET4,
+            Magic::Regtest => REGTEST,
+            Magic::Signet => SIGNET,
+            Magic::Custom(magic) => magic,
+        }
+    }
+
+    /// 从线上读到的数字反查网络类型, 和 `to_u32` 对应
+    ///
+    /// Only the well-known networks are recognised; a `Custom` network has to be named by the caller,
+    /// see `RawMessage::parse_for`.
+    pub fn from_u32(magic: u32) -> Option<Magic> {
+        match magic {
+            MAINNET => Some(Magic::Main),
+            TESTNET => Some(Magic::Testnet),
+            TESTNET4 => Some(Magic::Testnet4),
+            REGTEST => Some(Magic::Regtest),
+            SIGNET => Some(Magic::Signet),
+            _ => None,
+        }
+    }
 }
 
+/// The fixed-size header in front of every message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MessageHeader {
+    /// Network magic, little endian as on the wire
+    pub magic: u32,
+    /// Command the payload belongs to
+    pub command: CommandString,
+    /// Length of the payload in bytes
+    pub length: u32,
+    /// First four bytes of SHA256(SHA256(payload))
+    pub checksum: [u8; 4],
+}
+
+impl_consensus_encoding!(MessageHeader, magic, command, length, checksum);
+
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Payload {
     Version(version::VersionMessage),
     Verack,
     FilterLoad(filterload::FilterLoad),
-    GetData(getdata::GetData)
+    GetData(getdata::GetData),
+    Inv(inv::Inv),
+    NotFound(notfound::NotFound),
+    MerkleBlock(merkleblock::MerkleBlock),
+    /// ping, carrying a random nonce (BIP31)
+    Ping(u64),
+    /// pong, echoing the nonce of the ping it answers
+    Pong(u64),
+    GetHeaders(getheaders::GetHeaders),
+    Headers(headers::Headers),
+    /// block, with witness data if it was requested as `Inventory::WitnessBlock`
+    Block(bitcoin::Block),
+    /// tx, with witness data if it was requested as `Inventory::WitnessTx` or `Inventory::WTx`
+    Tx(bitcoin::Transaction),
+    Addr(addr::Addr),
+    /// getaddr, asks for `addr`/`addrv2`, no payload
+    GetAddr,
+    /// sendaddrv2, sent before verack to ask for `addrv2` instead of `addr` (BIP155), no payload
+    SendAddrV2,
+    AddrV2(addrv2::AddrV2List),
+    /// A message of a type registered in a `registry::MessageRegistry`
+    Custom(registry::CustomPayload),
+    /// A command that is neither built in nor registered, kept as it came in
+    Unknown {
+        command: CommandString,
+        bytes: Vec<u8>,
+    },
 }
 
 
 impl Payload {
+    /// The command this payload is sent with
+    pub fn command(&self) -> CommandString {
+        let command = match self {
+            Payload::Version(_) => "version",
+            Payload::Verack => "verack",
+            Payload::FilterLoad(_) => "filterload",
+            Payload::GetData(_) => "getdata",
+            Payload::Inv(_) => "inv",
+            Payload::NotFound(_) => "notfound",
+            Payload::MerkleBlock(_) => "merkleblock",
+            Payload::Ping(_) => "ping",
+            Payload::Pong(_) => "pong",
+            Payload::GetHeaders(_) => "getheaders",
+            Payload::Headers(_) => "headers",
+            Payload::Block(_) => "block",
+            Payload::Tx(_) => "tx",
+            Payload::Addr(_) => "addr",
+            Payload::GetAddr => "getaddr",
+            Payload::SendAddrV2 => "sendaddrv2",
+            Payload::AddrV2(_) => "addrv2",
+            Payload::Custom(data) => return data.command().clone(),
+            Payload::Unknown { command, .. } => return command.clone(),
+        };
+        CommandString(command.to_owned())
+    }
+
     //计算自己长度, 计算自己的checksum, 序列化自己 有些数据是没有payload的
-    pub fn calc(&self) -> (u32, Vec<u8>, Option<Vec<u8>>) {
-        match self {
-            Payload::Version(data) => {
-                let serialize = serialize(data);
-                let len = serialize.len();
-                let checksum = sha_sha(&serialize);
-                (len as u32, checksum, Some(serialize))
-            }
+    #[allow(clippy::type_complexity)]
+    pub fn calc(&self) -> Result<(u32, Vec<u8>, Option<Vec<u8>>), Error> {
+        let serialize = match self {
+            Payload::Version(data) => to_bytes(data)?,
 
-            Payload::Verack => {
+            Payload::Verack | Payload::GetAddr | Payload::SendAddrV2 => {
                 //Verack 没有长度 checksum按照空算 payload本身没有
                 let checksum = sha_sha("".as_bytes());
-                (0, checksum, None)
+                return Ok((0, checksum, None));
             }
 
-            Payload::FilterLoad(data) => {
-                //先根据固定数据来一个
-                //let serialize = serialize(data);
-                //let len = serialize.len();
-                //let checksum = sha_sha(&serialize);
-                //(len as u32, checksum, Some(serialize))
-                let serialize = &data.0;
-                let len = serialize.len();
-                let checksum = sha_sha(&serialize);
-                (len as u32, checksum, Some(serialize.to_owned()))
+            Payload::FilterLoad(data) => to_bytes(data)?,
+            Payload::GetData(data) => to_bytes(data)?,
+            Payload::Inv(data) => to_bytes(data)?,
+            Payload::NotFound(data) => to_bytes(data)?,
+            Payload::MerkleBlock(data) => to_bytes(data)?,
+            Payload::Ping(nonce) => to_bytes(nonce)?,
+            Payload::Pong(nonce) => to_bytes(nonce)?,
+            Payload::GetHeaders(data) => to_bytes(data)?,
+            Payload::Headers(data) => to_bytes(data)?,
+            Payload::Block(data) => to_bytes(data)?,
+            Payload::Tx(data) => to_bytes(data)?,
+            Payload::Addr(data) => to_bytes(data)?,
+            Payload::AddrV2(data) => to_bytes(data)?,
+            Payload::Custom(data) => data.encode()?,
+            Payload::Unknown { bytes, .. } => bytes.clone(),
+        };
+        let len = serialize.len();
+        let checksum = sha_sha(&serialize);
+        Ok((len as u32, checksum, Some(serialize)))
+    }
 
-            }
+    /// 根据 command 把 payload 的字节反序列化成对应的类型
+    /// 不认识的命令原样放进 `Payload::Unknown`, 自定义的消息要用 `registry::MessageRegistry::decode`
+    ///
+    /// `block` and `tx` may carry witness data, see `decode_requested` to only accept what was asked for.
+    pub fn decode(command: &CommandString, data: &[u8]) -> Result<Payload, Error> {
+        Payload::decode_requested(command, data, true)
+    }
 
-            Payload::GetData(data) => {
-                //固定数据
-                let serialize = &data.0;
-                let len = serialize.len();
-                let checksum = sha_sha(&serialize);
-                (len as u32, checksum, Some(serialize.to_owned()))
-            }
+    /// Like `decode`, for a connection where blocks and transactions are requested with (`witness`) or without
+    /// witness data, see `Inventory::is_witness`. Witness data in an answer to a request without it is
+    /// `Error::ProtocolViolation`.
+    pub fn decode_requested(command: &CommandString, data: &[u8], witness: bool) -> Result<Payload, Error> {
+        let payload = Payload::decode_any(command, data)?;
+        if !witness && payload.has_witness() {
+            return Err(Error::ProtocolViolation(format!("{} with witness data, requested without", command.as_str())));
+        }
+        Ok(payload)
+    }
+
+    /// Whether this is a `block` or `tx` carrying witness data
+    pub fn has_witness(&self) -> bool {
+        match self {
+            Payload::Block(block) => block.txdata.iter().any(tx_has_witness),
+            Payload::Tx(tx) => tx_has_witness(tx),
+            _ => false,
+        }
+    }
+
+    fn decode_any(command: &CommandString, data: &[u8]) -> Result<Payload, Error> {
+        match command.0.as_str() {
+            // 新版本可能在后面加字段 多出来的字节忽略
+            "version" => Ok(Payload::Version(deserialize_partial(data)?.0)),
+            "verack" => Ok(Payload::Verack),
+            "filterload" => Ok(Payload::FilterLoad(deserialize(data)?)),
+            "getdata" => Ok(Payload::GetData(deserialize(data)?)),
+            "inv" => Ok(Payload::Inv(deserialize(data)?)),
+            "notfound" => Ok(Payload::NotFound(deserialize(data)?)),
+            "merkleblock" => Ok(Payload::MerkleBlock(deserialize(data)?)),
+            "ping" => Ok(Payload::Ping(deserialize(data)?)),
+            "pong" => Ok(Payload::Pong(deserialize(data)?)),
+            "getheaders" => Ok(Payload::GetHeaders(deserialize(data)?)),
+            "headers" => Ok(Payload::Headers(deserialize(data)?)),
+            // 有没有 witness 由序列化里的 marker/flag 决定 两种格式都能解析, 要不要接受见 decode_requested
+            "block" => Ok(Payload::Block(deserialize(data)?)),
+            "tx" => Ok(Payload::Tx(deserialize(data)?)),
+            "addr" => Ok(Payload::Addr(deserialize(data)?)),
+            "getaddr" => Ok(Payload::GetAddr),
+            "sendaddrv2" => Ok(Payload::SendAddrV2),
+            "addrv2" => Ok(Payload::AddrV2(deserialize(data)?)),
+            _ => Ok(Payload::Unknown { command: command.clone(), bytes: data.to_vec() }),
         }
     }
 }
 
+fn tx_has_witness(tx: &bitcoin::Transaction) -> bool {
+    tx.input.iter().any(|input| !input.witness.is_empty())
+}
+
+// 和 serialize 一样 但是编码失败的时候返回错误而不是 panic
+fn to_bytes<T: Encodable>(data: &T) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    data.consensus_encode(&mut bytes)?;
+    Ok(bytes)
+}
+
 //计算checksum 的具体过程 SHA256(SHA256(payload)) 取前四个字节
 pub fn sha_sha(input: &[u8]) -> Vec<u8> {
     let hash_m1 = hmac_sha256::Hash::hash(input);
@@ -107,7 +302,13 @@ pub fn sha_sha(input: &[u8]) -> Vec<u8> {
 ///     全体的序列化都在combine中进行
 ///
 impl RawMessage {
-    pub fn new(magic: Magic, command: command::CommandString, payload: Payload) -> Self {
+    /// A message for `network`, carrying its magic
+    pub fn new(network: &NetworkParams, command: command::CommandString, payload: Payload) -> Self {
+        RawMessage::with_magic(network.magic, command, payload)
+    }
+
+    // 连接上的各个部分在创建的时候从 NetworkParams 取一次 magic, 之后用这个
+    pub(crate) fn with_magic(magic: Magic, command: command::CommandString, payload: Payload) -> Self {
         RawMessage {
             magic,
             command,
@@ -115,30 +316,95 @@ impl RawMessage {
         }
     }
 
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    pub fn command(&self) -> &CommandString {
+        &self.command
+    }
+
+    pub fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    /// Parse one message from the front of `data`, returning it together with the number of bytes consumed.
+    ///
+    /// The checksum is verified before the payload is decoded. Trailing bytes after the message are left alone,
+    /// so a buffer holding several messages can be walked by slicing off the consumed length.
+    pub fn parse(data: &[u8]) -> Result<(RawMessage, usize), Error> {
+        RawMessage::parse_inner(data, None, &MessageRegistry::default(), true)
+    }
+
+    /// Like `parse`, but only accepts messages carrying the magic of `network`.
+    /// This is also the way to read messages of a `Magic::Custom` network.
+    pub fn parse_for(network: &NetworkParams, data: &[u8]) -> Result<(RawMessage, usize), Error> {
+        RawMessage::parse_inner(data, Some(network.magic), &MessageRegistry::default(), true)
+    }
+
+    /// Like `parse_for`, decoding the commands registered in `registry` with their own decoders
+    pub fn parse_with(network: &NetworkParams, registry: &MessageRegistry, data: &[u8]) -> Result<(RawMessage, usize), Error> {
+        RawMessage::parse_inner(data, Some(network.magic), registry, true)
+    }
+
+    /// Like `parse_with`, rejecting witness data in `block` and `tx` unless `witness`, see `Payload::decode_requested`
+    pub fn parse_requested(network: &NetworkParams, registry: &MessageRegistry, witness: bool, data: &[u8])
+        -> Result<(RawMessage, usize), Error> {
+        RawMessage::parse_inner(data, Some(network.magic), registry, witness)
+    }
+
+    pub(crate) fn parse_inner(data: &[u8], expected: Option<Magic>, registry: &MessageRegistry, witness: bool)
+        -> Result<(RawMessage, usize), Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::ShortBuffer { needed: HEADER_SIZE, available: data.len() });
+        }
+        let header: MessageHeader = deserialize(&data[..HEADER_SIZE])?;
+        limits::check_length(&header.command, header.length as usize)?;
+        let total = HEADER_SIZE + header.length as usize;
+        if data.len() < total {
+            return Err(Error::ShortBuffer { needed: total, available: data.len() });
+        }
+        let message = RawMessage::from_parts(header, &data[HEADER_SIZE..total], expected, registry, witness)?;
+        Ok((message, total))
+    }
+
+    /// 由已经读出来的 header 和 payload 组装消息 校验 magic 和 checksum
+    fn from_parts(header: MessageHeader, payload: &[u8], expected: Option<Magic>, registry: &MessageRegistry, witness: bool)
+        -> Result<RawMessage, Error> {
+        let magic = match expected {
+            Some(magic) if magic.to_u32() == header.magic => magic,
+            Some(_) => return Err(Error::UnknownMagic(header.magic)),
+            None => Magic::from_u32(header.magic).ok_or(Error::UnknownMagic(header.magic))?,
+        };
+        let mut actual = [0u8; 4];
+        actual.copy_from_slice(&sha_sha(payload));
+        if actual != header.checksum {
+            return Err(Error::ChecksumMismatch { expected: header.checksum, actual });
+        }
+        limits::check_payload(&header.command, payload)?;
+        let payload = registry.decode_requested(&header.command, payload, witness)?;
+        Ok(RawMessage::with_magic(magic, header.command, payload))
+    }
+
     //根据magic 选取对应网络类型对应的数字
     pub fn magic_num(&self) -> u32 {
-        match self.magic {
-            Magic::Main => {
-                0xD9B4BEF9
-            }
-            Magic::Testnet => {
-                0xDAB5BFFA
-            }
-        }
+        self.magic.to_u32()
     }
 
     /// 把序列化好的数据进行拼接 组成完整的需要发送的数据
-    pub fn combine(&self) -> Vec<u8> {
+    ///
+    /// Fails if the command is not a valid command string, see `CommandString::try_from`.
+    pub fn combine(&self) -> Result<Vec<u8>, Error> {
         let mut raw_bytes: Vec<u8> = Vec::new();
 
         let mut magic = serialize(&(self.magic_num()));
-        let mut command = serialize(&(self.command));
+        raw_bytes.append(&mut magic);
+        CommandString::validate(self.command.as_str())?;
+        self.command.consensus_encode(&mut raw_bytes)?;
 
-        let (len, mut checksum, mut payload) = self.payload.calc();
+        let (len, mut checksum, mut payload) = self.payload.calc()?;
         let mut payload_len: Vec<u8> = serialize(&len);
 
-        raw_bytes.append(&mut magic);
-        raw_bytes.append(&mut command);
         raw_bytes.append(&mut payload_len);
         raw_bytes.append(&mut checksum);
 
@@ -146,6 +412,114 @@ impl RawMessage {
             raw_bytes.append(data);
         }
 
-        raw_bytes
+        Ok(raw_bytes)
+    }
+}
+
+impl Encodable for RawMessage {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let raw_bytes = self.combine()?;
+        s.write_all(&raw_bytes)?;
+        Ok(raw_bytes.len())
+    }
+}
+
+impl Decodable for RawMessage {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let header: MessageHeader = Decodable::consensus_decode(&mut d)?;
+        let length = header.length as usize;
+        limits::check_length(&header.command, length).map_err(Error::from)?;
+        let mut payload = vec![0u8; length];
+        d.read_exact(&mut payload)?;
+        Ok(RawMessage::from_parts(header, &payload, None, &MessageRegistry::default(), true)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::{sha256d, Hash};
+    use crate::message::inventory::Inventory;
+
+    fn getdata() -> RawMessage {
+        let inventory = vec![Inventory::Tx(sha256d::Hash::from_inner([0xab; 32]))];
+        RawMessage::new(&NetworkParams::mainnet(), CommandString("getdata".to_owned()), Payload::GetData(getdata::GetData(inventory)))
+    }
+
+    fn segwit_tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![vec![0x30, 0x44]],
+            }],
+            output: vec![TxOut { value: 50_000, script_pubkey: Script::new() }],
+        }
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let message = getdata();
+        let mut bytes = message.combine().unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE + 37);
+        assert_eq!(&bytes[..4], &[0xf9, 0xbe, 0xb4, 0xd9]);
+        // 后面多出来的字节不动
+        bytes.extend_from_slice(&[0xf9, 0xbe]);
+        assert_eq!(RawMessage::parse(&bytes).unwrap(), (message, HEADER_SIZE + 37));
+
+        let verack = RawMessage::new(&NetworkParams::testnet(), CommandString("verack".to_owned()), Payload::Verack);
+        let bytes = verack.combine().unwrap();
+        assert_eq!(RawMessage::parse(&bytes).unwrap(), (verack, HEADER_SIZE));
+    }
+
+    #[test]
+    fn parse_short_buffer() {
+        let bytes = getdata().combine().unwrap();
+        match RawMessage::parse(&bytes[..10]) {
+            Err(Error::ShortBuffer { needed, available }) => assert_eq!((needed, available), (HEADER_SIZE, 10)),
+            other => panic!("expected a short buffer, got {:?}", other),
+        }
+        match RawMessage::parse(&bytes[..bytes.len() - 1]) {
+            Err(Error::ShortBuffer { needed, available }) => assert_eq!((needed, available), (bytes.len(), bytes.len() - 1)),
+            other => panic!("expected a short buffer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_checksum_mismatch() {
+        let mut bytes = getdata().combine().unwrap();
+        let expected = [bytes[20], bytes[21], bytes[22], bytes[23]];
+        *bytes.last_mut().unwrap() ^= 1;
+        match RawMessage::parse(&bytes) {
+            Err(Error::ChecksumMismatch { expected: e, actual }) => {
+                assert_eq!(e, expected);
+                assert_ne!(actual, expected);
+            }
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn witness_only_when_requested() {
+        let tx = segwit_tx();
+        let command = CommandString("tx".to_owned());
+        let bytes = serialize(&tx);
+        assert_eq!(Payload::decode_requested(&command, &bytes, true).unwrap(), Payload::Tx(tx.clone()));
+        match Payload::decode_requested(&command, &bytes, false) {
+            Err(Error::ProtocolViolation(_)) => {}
+            other => panic!("expected a protocol violation, got {:?}", other),
+        }
+
+        let stripped = crate::relay::without_witness(&tx);
+        assert_eq!(Payload::decode_requested(&command, &serialize(&stripped), false).unwrap(), Payload::Tx(stripped));
     }
 }
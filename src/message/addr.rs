@@ -0,0 +1,47 @@
+//! addr, timestamped addresses of other nodes
+//! [https://en.bitcoin.it/wiki/Protocol_documentation#addr]
+//!
+//! Only IPv4 and IPv6 fit into this format; peers that sent `sendaddrv2` get `addrv2` instead, see `addrv2`.
+
+use std::io;
+use bitcoin::consensus::{Encodable, Decodable, encode};
+use bitcoin::VarInt;
+use crate::message::address::Address;
+
+/// Most addresses a single `addr` or `addrv2` message may carry
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+/// The `addr` message: (last seen as unix time, address) pairs
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Addr(pub Vec<(u32, Address)>);
+
+impl Encodable for Addr {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut s)?;
+        for (time, address) in &self.0 {
+            len += time.consensus_encode(&mut s)?;
+            len += address.consensus_encode(&mut s)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Addr {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+        if count > MAX_ADDR_TO_SEND as u64 {
+            return Err(encode::Error::OversizedVectorAllocation { requested: count as usize, max: MAX_ADDR_TO_SEND });
+        }
+        let mut addresses = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let time = Decodable::consensus_decode(&mut d)?;
+            addresses.push((time, Decodable::consensus_decode(&mut d)?));
+        }
+        Ok(Addr(addresses))
+    }
+}
@@ -1,11 +1,13 @@
 use std::net::{SocketAddr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 use std::{io, fmt};
 use bitcoin::consensus::{Encodable, Decodable, encode};
+use crate::error::Error;
+use crate::message::services::ServiceFlags;
 
 /// A message which can be sent on the Bitcoin network
 pub struct Address {
     /// Services provided by the peer whose address this is
-    pub services: u64,
+    pub services: ServiceFlags,
     /// Network byte-order ipv6 address, or ipv4-mapped ipv6 address
     pub address: [u16; 8],
     /// Network port
@@ -16,26 +18,27 @@ const ONION : [u16; 3] = [0xFD87, 0xD87E, 0xEB43];
 
 impl Address {
     /// Create an address message for a socket
-    pub fn new (socket :&SocketAddr, services: u64) -> Address {
+    pub fn new (socket :&SocketAddr, services: ServiceFlags) -> Address {
         let (address, port) = match socket {
-            &SocketAddr::V4(ref addr) => (addr.ip().to_ipv6_mapped().segments(), addr.port()),
-            &SocketAddr::V6(ref addr) => (addr.ip().segments(), addr.port())
+            SocketAddr::V4(addr) => (addr.ip().to_ipv6_mapped().segments(), addr.port()),
+            SocketAddr::V6(addr) => (addr.ip().segments(), addr.port())
         };
-        Address { address: address, port: port, services: services }
+        Address { address, port, services }
     }
 
     /// extract socket address from an address message
-    /// This will return io::Error ErrorKind::AddrNotAvailable if the message contains a Tor address.
-    pub fn socket_addr (&self) -> Result<SocketAddr, io::Error> {
+    /// This will return `Error::UnsupportedAddress` if the message contains a Tor address.
+    pub fn socket_addr (&self) -> Result<SocketAddr, Error> {
         let addr = &self.address;
         if addr[0..3] == ONION[..] {
-            return Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+            return Err(Error::UnsupportedAddress);
         }
         let ipv6 = Ipv6Addr::new(
             addr[0],addr[1],addr[2],addr[3],
             addr[4],addr[5],addr[6],addr[7]
         );
-        if let Some(ipv4) = ipv6.to_ipv4() {
+        // 只认 ::ffff:a.b.c.d, ::1 之类的不能当成 IPv4
+        if let Some(ipv4) = ipv6.to_ipv4_mapped() {
             Ok(SocketAddr::V4(SocketAddrV4::new(ipv4, self.port)))
         }
         else {
@@ -77,9 +80,9 @@ impl Decodable for Address {
 
 impl fmt::Debug for Address {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO: render services and hex-ize address
-        write!(f, "Address {{services: {:?}, address: {:?}, port: {:?}}}",
-               self.services, &self.address[..], self.port)
+        let addr = &self.address;
+        let ipv6 = Ipv6Addr::new(addr[0], addr[1], addr[2], addr[3], addr[4], addr[5], addr[6], addr[7]);
+        write!(f, "Address {{services: {}, address: {}, port: {}}}", self.services, ipv6, self.port)
     }
 }
 
@@ -96,7 +99,7 @@ impl Clone for Address {
 impl PartialEq for Address {
     fn eq(&self, other: &Address) -> bool {
         self.services == other.services &&
-            &self.address[..] == &other.address[..] &&
+            self.address[..] == other.address[..] &&
             self.port == other.port
     }
 }
@@ -0,0 +1,416 @@
+//! addrv2 and sendaddrv2 (BIP155)
+//! [https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki]
+//!
+//! addr 只能放 16 字节的 IPv6 (IPv4 映射进去), Tor v3 和 I2P 的地址有 32 字节放不下
+//! addrv2 每个地址前面带网络编号和长度
+//!     1 IPv4      4 字节
+//!     2 IPv6      16 字节
+//!     4 TorV3     32 字节 (ed25519 公钥)
+//!     5 I2P       32 字节 (目的地的 SHA256)
+//!     6 CJDNS     16 字节 (fc00::/8)
+//!
+//! A peer that sends `sendaddrv2` between `version` and `verack` wants addresses as `addrv2` from then on;
+//! `addr_message` picks the format for it.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use bitcoin::consensus::{Encodable, Decodable, encode};
+use bitcoin::VarInt;
+use crate::message::{RawMessage, Payload};
+use crate::message::addr::{Addr, MAX_ADDR_TO_SEND};
+use crate::message::address::Address;
+use crate::message::command::CommandString;
+use crate::message::services::ServiceFlags;
+use crate::network::NetworkParams;
+
+/// Longest address accepted, whatever its network
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+/// An address tagged with the network it belongs to
+#[derive(PartialEq, Eq, Clone, Debug, Hash)]
+pub enum AddrV2 {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// Tor v3 onion service, its ed25519 public key
+    TorV3([u8; 32]),
+    /// I2P destination, the SHA256 of it
+    I2p([u8; 32]),
+    /// CJDNS address, always in fc00::/8
+    Cjdns(Ipv6Addr),
+    /// A network this implementation does not know (including the retired Tor v2), kept as it was
+    Unknown(u8, Vec<u8>),
+}
+
+impl AddrV2 {
+    /// The BIP155 network id
+    pub fn network_id(&self) -> u8 {
+        match *self {
+            AddrV2::Ipv4(_) => 1,
+            AddrV2::Ipv6(_) => 2,
+            AddrV2::TorV3(_) => 4,
+            AddrV2::I2p(_) => 5,
+            AddrV2::Cjdns(_) => 6,
+            AddrV2::Unknown(id, _) => id,
+        }
+    }
+
+    /// IPv4-mapped IPv6 addresses become `Ipv4`
+    pub fn from_ip(ip: IpAddr) -> AddrV2 {
+        match ip {
+            IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => AddrV2::Ipv4(ip),
+                None => AddrV2::Ipv6(ip),
+            },
+        }
+    }
+
+    /// The IP address, for addresses reachable over plain IPv4 or IPv6
+    pub fn ip(&self) -> Option<IpAddr> {
+        match *self {
+            AddrV2::Ipv4(ip) => Some(IpAddr::V4(ip)),
+            AddrV2::Ipv6(ip) => Some(IpAddr::V6(ip)),
+            _ => None,
+        }
+    }
+}
+
+impl Encodable for AddrV2 {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let bytes = match *self {
+            AddrV2::Ipv4(ip) => ip.octets().to_vec(),
+            AddrV2::Ipv6(ip) | AddrV2::Cjdns(ip) => ip.octets().to_vec(),
+            AddrV2::TorV3(ref key) | AddrV2::I2p(ref key) => key.to_vec(),
+            AddrV2::Unknown(_, ref bytes) => bytes.clone(),
+        };
+        let len = self.network_id().consensus_encode(&mut s)? + bytes.consensus_encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for AddrV2 {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let network_id: u8 = Decodable::consensus_decode(&mut d)?;
+        let VarInt(len) = Decodable::consensus_decode(&mut d)?;
+        if len > MAX_ADDRV2_SIZE as u64 {
+            return Err(encode::Error::OversizedVectorAllocation { requested: len as usize, max: MAX_ADDRV2_SIZE });
+        }
+        let mut bytes = vec![0u8; len as usize];
+        d.read_exact(&mut bytes)?;
+
+        // 已知的网络 长度必须对
+        let expected = match network_id {
+            1 => 4,
+            2 | 6 => 16,
+            4 | 5 => 32,
+            _ => return Ok(AddrV2::Unknown(network_id, bytes)),
+        };
+        if bytes.len() != expected {
+            return Err(encode::Error::ParseFailed("invalid addrv2 address length"));
+        }
+        Ok(match network_id {
+            1 => AddrV2::Ipv4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+            2 => {
+                // 和 Bitcoin Core 一样 IPv4 和 Tor v2 要用自己的网络编号, 不能藏在 IPv6 里
+                if EMBEDDED_PREFIXES.iter().any(|prefix| bytes.starts_with(prefix)) {
+                    return Err(encode::Error::ParseFailed("IPv6 address with an embedded IPv4, Tor v2 or internal address"));
+                }
+                AddrV2::Ipv6(Ipv6Addr::from(ipv6_octets(&bytes)))
+            }
+            4 => AddrV2::TorV3(key(&bytes)),
+            5 => AddrV2::I2p(key(&bytes)),
+            _ => {
+                if bytes[0] != 0xfc {
+                    return Err(encode::Error::ParseFailed("CJDNS address outside fc00::/8"));
+                }
+                AddrV2::Cjdns(Ipv6Addr::from(ipv6_octets(&bytes)))
+            }
+        })
+    }
+}
+
+/// IPv6 prefixes of addresses that belong to another network: IPv4-mapped, Tor v2 and Core's internal names
+const EMBEDDED_PREFIXES: [&[u8]; 3] = [
+    &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff],
+    &[0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43],
+    &[0xfd, 0x6b, 0x88, 0xc0, 0x87, 0x24],
+];
+
+fn ipv6_octets(bytes: &[u8]) -> [u8; 16] {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(bytes);
+    octets
+}
+
+fn key(bytes: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    key
+}
+
+/// One entry of `addrv2`
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AddrV2Entry {
+    /// Last time the node was seen, unix time
+    pub time: u32,
+    /// Services the node offers
+    pub services: ServiceFlags,
+    pub addr: AddrV2,
+    pub port: u16,
+}
+
+impl AddrV2Entry {
+    /// Convert an `addr` entry; `None` for the retired Tor v2 addresses
+    pub fn from_v1(time: u32, address: &Address) -> Option<AddrV2Entry> {
+        let socket = address.socket_addr().ok()?;
+        Some(AddrV2Entry {
+            time,
+            services: address.services,
+            addr: AddrV2::from_ip(socket.ip()),
+            port: socket.port(),
+        })
+    }
+
+    /// Convert to an `addr` entry; `None` unless the address is IPv4 or IPv6
+    pub fn to_v1(&self) -> Option<(u32, Address)> {
+        let socket = self.socket_addr()?;
+        Some((self.time, Address::new(&socket, self.services)))
+    }
+
+    /// Where to connect to, for IPv4 and IPv6 addresses
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.addr.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+impl Encodable for AddrV2Entry {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        // services 是 var-int, 端口是大端序
+        let len = self.time.consensus_encode(&mut s)?
+            + VarInt(self.services.bits()).consensus_encode(&mut s)?
+            + self.addr.consensus_encode(&mut s)?
+            + self.port.to_be().consensus_encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for AddrV2Entry {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let time = Decodable::consensus_decode(&mut d)?;
+        let VarInt(services) = Decodable::consensus_decode(&mut d)?;
+        Ok(AddrV2Entry {
+            time,
+            services: ServiceFlags::from_bits(services),
+            addr: Decodable::consensus_decode(&mut d)?,
+            port: u16::from_be(Decodable::consensus_decode(d)?),
+        })
+    }
+}
+
+/// The `addrv2` message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AddrV2List(pub Vec<AddrV2Entry>);
+
+impl AddrV2List {
+    /// The entries that fit into `addr`, the others are dropped
+    pub fn to_v1(&self) -> Addr {
+        Addr(self.0.iter().filter_map(AddrV2Entry::to_v1).collect())
+    }
+}
+
+impl From<Addr> for AddrV2List {
+    fn from(addr: Addr) -> AddrV2List {
+        AddrV2List(addr.0.iter().filter_map(|(time, address)| AddrV2Entry::from_v1(*time, address)).collect())
+    }
+}
+
+impl Encodable for AddrV2List {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut s)?;
+        for entry in &self.0 {
+            len += entry.consensus_encode(&mut s)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for AddrV2List {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+        if count > MAX_ADDR_TO_SEND as u64 {
+            return Err(encode::Error::OversizedVectorAllocation { requested: count as usize, max: MAX_ADDR_TO_SEND });
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            entries.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(AddrV2List(entries))
+    }
+}
+
+/// `addrv2` for peers that negotiated it (`PeerInfo::addrv2`), otherwise `addr` with the entries that fit
+pub fn addr_message(network: &NetworkParams, entries: AddrV2List, addrv2: bool) -> RawMessage {
+    if addrv2 {
+        RawMessage::new(network, CommandString("addrv2".to_owned()), Payload::AddrV2(entries))
+    } else {
+        RawMessage::new(network, CommandString("addr".to_owned()), Payload::Addr(entries.to_v1()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::{deserialize, serialize};
+
+    fn decode(hex: &str) -> Result<AddrV2, encode::Error> {
+        deserialize(&hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn bip155_addresses() {
+        // Bitcoin Core 的 cnetaddr_unserialize_v2
+        let vectors = [
+            ("010401020304", AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4))),
+            ("021001020304050607080910111213141516",
+                AddrV2::Ipv6("102:304:506:708:910:1112:1314:1516".parse().unwrap())),
+            ("042079bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f",
+                AddrV2::TorV3(key(&hex::decode("79bcc625184b05194975c28b66b66b0469f7f6556fb1ac3189a79b40dda32f1f").unwrap()))),
+            ("0520a2894dabaec08c0051a481a6dac88b64f98232ae42d4b6fd2fa81952dfe36a87",
+                AddrV2::I2p(key(&hex::decode("a2894dabaec08c0051a481a6dac88b64f98232ae42d4b6fd2fa81952dfe36a87").unwrap()))),
+            ("0610fc000001000200030004000500060007", AddrV2::Cjdns("fc00:1:2:3:4:5:6:7".parse().unwrap())),
+            // Tor v2 已经不用了, 和不认识的网络一样原样保留
+            ("030af1f2f3f4f5f6f7f8f9fa", AddrV2::Unknown(3, hex::decode("f1f2f3f4f5f6f7f8f9fa").unwrap())),
+            ("aa020102", AddrV2::Unknown(0xaa, vec![1, 2])),
+        ];
+        for (hex, addr) in vectors.iter() {
+            assert_eq!(&decode(hex).unwrap(), addr, "{}", hex);
+            assert_eq!(hex::encode(serialize(addr)), *hex);
+        }
+    }
+
+    #[test]
+    fn bip155_rejections() {
+        for hex in &[
+            // 长度不对
+            "01050102030405",
+            "0103010203",
+            "020f000102030405060708090a0b0c0d0e",
+            "0400",
+            "051f000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e",
+            "0610fc00000100020003000400050006",
+            // CJDNS 不在 fc00::/8
+            "0610aa000001000200030004000500060007",
+            // IPv6 里藏着 IPv4, Tor v2 或者 Core 内部用的地址
+            "021000000000000000000000ffff01020304",
+            "0210fd87d87eeb430102030405060708090a",
+            "0210fd6b88c08724ca978112ca1bbdcafac2",
+        ] {
+            assert!(decode(hex).is_err(), "{} decoded", hex);
+        }
+        // 超过 512 字节, 不管哪个网络
+        match decode("aafd0102") {
+            Err(encode::Error::OversizedVectorAllocation { requested: 513, max: MAX_ADDRV2_SIZE }) => {}
+            other => panic!("expected an oversized address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let entry = AddrV2Entry {
+            time: 0x6000_0000,
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            addr: AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+            port: 8333,
+        };
+        let bytes = serialize(&entry);
+        // 时间 小端序, 服务 var-int, 地址, 端口 大端序
+        assert_eq!(hex::encode(&bytes), "0000006009010401020304208d");
+        assert_eq!(deserialize::<AddrV2Entry>(&bytes).unwrap(), entry);
+
+        let list = AddrV2List(vec![entry.clone(), AddrV2Entry { addr: AddrV2::Unknown(9, vec![7; 3]), ..entry }]);
+        assert_eq!(deserialize::<AddrV2List>(&serialize(&list)).unwrap(), list);
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let entry = AddrV2Entry {
+            time: 0,
+            services: ServiceFlags::NONE,
+            addr: AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+            port: 8333,
+        };
+        let list = AddrV2List(vec![entry; MAX_ADDR_TO_SEND]);
+        assert!(deserialize::<AddrV2List>(&serialize(&list)).is_ok());
+
+        let mut bytes = serialize(&AddrV2List(vec![list.0[0].clone(); MAX_ADDR_TO_SEND + 1]));
+        match deserialize::<AddrV2List>(&bytes) {
+            Err(encode::Error::OversizedVectorAllocation { requested, max: MAX_ADDR_TO_SEND }) =>
+                assert_eq!(requested, MAX_ADDR_TO_SEND + 1),
+            other => panic!("expected too many entries, got {:?}", other),
+        }
+        // 只看个数就拒绝, 不等读到后面
+        bytes.truncate(3);
+        assert!(matches!(deserialize::<AddrV2List>(&bytes), Err(encode::Error::OversizedVectorAllocation { .. })));
+    }
+
+    #[test]
+    fn v1_to_v2() {
+        let services = ServiceFlags::NETWORK;
+        let v4 = Address::new(&"1.2.3.4:8333".parse().unwrap(), services);
+        let entry = AddrV2Entry::from_v1(5, &v4).unwrap();
+        assert_eq!(entry, AddrV2Entry { time: 5, services, addr: AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), port: 8333 });
+
+        let v6 = Address::new(&"[2001:db8::1]:18333".parse().unwrap(), services);
+        let entry = AddrV2Entry::from_v1(5, &v6).unwrap();
+        assert_eq!(entry.addr, AddrV2::Ipv6("2001:db8::1".parse().unwrap()));
+        assert_eq!(entry.port, 18333);
+
+        // ::1 不是 IPv4 映射的地址
+        let loopback = Address::new(&"[::1]:8333".parse().unwrap(), services);
+        assert_eq!(loopback.socket_addr().unwrap(), "[::1]:8333".parse().unwrap());
+        assert_eq!(AddrV2Entry::from_v1(5, &loopback).unwrap().addr, AddrV2::Ipv6(Ipv6Addr::LOCALHOST));
+
+        // addr 里的 Tor v2 转不过来
+        let onion = Address { services, address: [0xFD87, 0xD87E, 0xEB43, 1, 2, 3, 4, 5], port: 8333 };
+        assert!(AddrV2Entry::from_v1(5, &onion).is_none());
+
+        let list = AddrV2List::from(Addr(vec![(1, v4.clone()), (2, onion), (3, v6.clone())]));
+        assert_eq!(list.0.len(), 2);
+        assert_eq!(list.to_v1(), Addr(vec![(1, v4), (3, v6)]));
+    }
+
+    #[test]
+    fn v2_to_v1() {
+        let entry = |addr| AddrV2Entry { time: 7, services: ServiceFlags::WITNESS, addr, port: 8333 };
+        let (time, address) = entry(AddrV2::Ipv4(Ipv4Addr::new(1, 2, 3, 4))).to_v1().unwrap();
+        assert_eq!(time, 7);
+        // IPv4 在 addr 里是映射到 IPv6 的
+        assert_eq!(address.address, [0, 0, 0, 0, 0, 0xffff, 0x0102, 0x0304]);
+        assert_eq!(address.socket_addr().unwrap(), "1.2.3.4:8333".parse().unwrap());
+        assert_eq!(address.services, ServiceFlags::WITNESS);
+
+        for addr in [AddrV2::TorV3([1; 32]), AddrV2::I2p([2; 32]),
+                     AddrV2::Cjdns("fc00::1".parse().unwrap()), AddrV2::Unknown(9, vec![1])].iter() {
+            assert!(entry(addr.clone()).to_v1().is_none(), "{:?}", addr);
+        }
+        let list = AddrV2List(vec![entry(AddrV2::TorV3([1; 32])), entry(AddrV2::Ipv6("2001:db8::2".parse().unwrap()))]);
+        let addr = list.to_v1();
+        assert_eq!(addr.0.len(), 1);
+        assert_eq!(addr.0[0].1.socket_addr().unwrap(), "[2001:db8::2]:8333".parse().unwrap());
+    }
+}
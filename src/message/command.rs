@@ -1,26 +1,101 @@
+//! The 12 byte command field of the message header
+//!
+//! 线上是 12 个字节, 命令名后面补 NUL
+//! 和 Bitcoin Core 的 IsCommandValid 一样
+//!     只能是可打印的 ASCII (0x20..=0x7e)
+//!     第一个 NUL 之后只能是 NUL, 否则 "ver\0sion" 会被当成 "version"
+
 use bitcoin::consensus::{Encodable, encode, Decodable};
-use std::{io, iter};
+use std::{io, fmt, error};
+use std::convert::TryFrom;
 
+/// Size of the command field on the wire
+pub const COMMAND_SIZE: usize = 12;
 
 /// Serializer for command string
+///
+/// 用法
+///     let command = CommandString::try_from("version")?;
+///
+/// The field is public to keep literals like `CommandString("version".to_owned())` short;
+/// a command built that way is checked when it is encoded.
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct CommandString(pub String);
 
+/// Reasons a string cannot be a command
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum CommandError {
+    /// Longer than `COMMAND_SIZE` bytes
+    TooLong(usize),
+    /// Contains a character outside printable ASCII
+    InvalidChar(char),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::TooLong(len) => write!(f, "command is {} bytes, at most {} allowed", len, COMMAND_SIZE),
+            CommandError::InvalidChar(c) => write!(f, "command contains {:?}", c),
+        }
+    }
+}
+
+impl error::Error for CommandError {}
+
+impl CommandError {
+    fn to_encode_error(&self) -> encode::Error {
+        match *self {
+            CommandError::TooLong(_) => encode::Error::ParseFailed("command string longer than 12 bytes"),
+            CommandError::InvalidChar(_) => encode::Error::ParseFailed("command string not printable ASCII"),
+        }
+    }
+}
+
+impl CommandString {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn validate(command: &str) -> Result<(), CommandError> {
+        if command.len() > COMMAND_SIZE {
+            return Err(CommandError::TooLong(command.len()));
+        }
+        match command.chars().find(|c| !(' '..='~').contains(c)) {
+            Some(c) => Err(CommandError::InvalidChar(c)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<&str> for CommandString {
+    type Error = CommandError;
+
+    fn try_from(command: &str) -> Result<CommandString, CommandError> {
+        CommandString::validate(command)?;
+        Ok(CommandString(command.to_owned()))
+    }
+}
+
+impl TryFrom<String> for CommandString {
+    type Error = CommandError;
+
+    fn try_from(command: String) -> Result<CommandString, CommandError> {
+        CommandString::validate(&command)?;
+        Ok(CommandString(command))
+    }
+}
+
 impl Encodable for CommandString {
     #[inline]
     fn consensus_encode<S: io::Write>(
         &self,
         s: S,
     ) -> Result<usize, encode::Error> {
-        let &CommandString(ref inner_str) = self;
-        let mut rawbytes = [0u8; 12];
+        let CommandString(inner_str) = self;
+        CommandString::validate(inner_str).map_err(|e| e.to_encode_error())?;
+        let mut rawbytes = [0u8; COMMAND_SIZE];
         let strbytes = inner_str.as_bytes();
-        if strbytes.len() > 12 {
-            panic!("Command string longer than 12 bytes");
-        }
-        for x in 0..strbytes.len() {
-            rawbytes[x] = strbytes[x];
-        }
+        rawbytes[..strbytes.len()].copy_from_slice(strbytes);
         rawbytes.consensus_encode(s)
     }
 }
@@ -28,12 +103,58 @@ impl Encodable for CommandString {
 impl Decodable for CommandString {
     #[inline]
     fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
-        let rawbytes: [u8; 12] = Decodable::consensus_decode(d)?;
-        let rv = iter::FromIterator::from_iter(
-            rawbytes
-                .iter()
-                .filter_map(|&u| if u > 0 { Some(u as char) } else { None })
-        );
-        Ok(CommandString(rv))
-    }
-}
\ No newline at end of file
+        let rawbytes: [u8; COMMAND_SIZE] = Decodable::consensus_decode(d)?;
+        let len = rawbytes.iter().position(|&u| u == 0).unwrap_or(COMMAND_SIZE);
+        if rawbytes[len..].iter().any(|&u| u != 0) {
+            return Err(encode::Error::ParseFailed("command string has bytes after NUL"));
+        }
+        let command: String = rawbytes[..len].iter().map(|&u| u as char).collect();
+        CommandString::validate(&command).map_err(|e| e.to_encode_error())?;
+        Ok(CommandString(command))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkParams;
+    use bitcoin::consensus::{deserialize, serialize};
+    use crate::error::Error;
+    use crate::message::{RawMessage, Payload};
+
+    #[test]
+    fn round_trip() {
+        let command = CommandString::try_from("sendaddrv2").unwrap();
+        let bytes = serialize(&command);
+        assert_eq!(&bytes[..], b"sendaddrv2\0\0");
+        assert_eq!(deserialize::<CommandString>(&bytes).unwrap(), command);
+        assert_eq!(deserialize::<CommandString>(b"filterclear\0").unwrap().as_str(), "filterclear");
+    }
+
+    #[test]
+    fn rejects_bytes_after_nul() {
+        assert!(deserialize::<CommandString>(b"ver\0sion\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn rejects_non_printable() {
+        assert!(deserialize::<CommandString>(b"version\x01\0\0\0\0").is_err());
+        assert!(deserialize::<CommandString>(b"tx\xff\0\0\0\0\0\0\0\0\0").is_err());
+        assert_eq!(CommandString::try_from("ping\n"), Err(CommandError::InvalidChar('\n')));
+        assert_eq!(CommandString::try_from("pïng"), Err(CommandError::InvalidChar('ï')));
+    }
+
+    #[test]
+    fn too_long_fails_to_encode() {
+        assert_eq!(CommandString::try_from("thirteen_char"), Err(CommandError::TooLong(13)));
+        let command = CommandString("thirteen_char".to_owned());
+        let mut bytes = Vec::new();
+        assert!(command.consensus_encode(&mut bytes).is_err());
+
+        let message = RawMessage::new(&NetworkParams::mainnet(), command, Payload::Verack);
+        match message.combine() {
+            Err(Error::InvalidCommand(CommandError::TooLong(13))) => {}
+            other => panic!("expected an invalid command, got {:?}", other),
+        }
+    }
+}
@@ -1,24 +1,341 @@
-//! filterload 暂时设定为固定值
+//! filterload and the BIP37 bloom filter behind it
 //!
-//send("filterload",
-//"02"  # ........ Filter bytes: 2
-//+ "b50f" # ....... Filter: 1010 1101 1111 0000
-//+ "0b000000" # ... nHashFuncs: 11
-//+ "00000000" # ... nTweak: 0/none
-//+ "00" # ......... nFlags: BLOOM_UPDATE_NONE
-//)
-
-//pub struct FilterLoad {
-//    pub bytes: u8,
-//    pub filter: String,
-//    pub nHashFuncs: u32,
-//    pub nTweak: u32,
-//    pub nFlags: u8,
-//}
-//
-//impl_consensus_encoding!(FilterLoad,bytes,
-//                         filter, nHashFuncs,
-//                         nTweak, nFlags);
-
-//先根据固定数组组装一个
-pub struct FilterLoad(pub Vec<u8>);
\ No newline at end of file
+//! ```text
+//! send("filterload",
+//! "02"  # ........ Filter bytes: 2
+//! + "b50f" # ....... Filter: 1010 1101 1111 0000
+//! + "0b000000" # ... nHashFuncs: 11
+//! + "00000000" # ... nTweak: 0/none
+//! + "00" # ......... nFlags: BLOOM_UPDATE_NONE
+//! )
+//! ```
+//!
+//! [BIP37](https://github.com/bitcoin/bips/blob/master/bip-0037.mediawiki)
+
+use std::io;
+use bitcoin::consensus::{Encodable, Decodable, encode, serialize};
+use bitcoin::{OutPoint, PublicKey};
+use bitcoin_hashes::hash160;
+
+/// Largest filter a node accepts, in bytes
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+/// Largest number of hash functions a node accepts
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+const LN2SQUARED: f64 = 0.480_453_013_918_201_4;
+const LN2: f64 = std::f64::consts::LN_2;
+
+/// How the remote node updates the filter when a transaction matches (the `BLOOM_UPDATE_*` values)
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum BloomFlags {
+    /// BLOOM_UPDATE_NONE: never update the filter
+    None = 0,
+    /// BLOOM_UPDATE_ALL: add the outpoint of every matched output
+    All = 1,
+    /// BLOOM_UPDATE_P2PUBKEY_ONLY: add outpoints only for matched pay-to-pubkey and multisig outputs
+    P2PubkeyOnly = 2,
+}
+
+impl Encodable for BloomFlags {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        s: S,
+    ) -> Result<usize, encode::Error> {
+        (*self as u8).consensus_encode(s)
+    }
+}
+
+impl Decodable for BloomFlags {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        match u8::consensus_decode(d)? {
+            0 => Ok(BloomFlags::None),
+            1 => Ok(BloomFlags::All),
+            2 => Ok(BloomFlags::P2PubkeyOnly),
+            _ => Err(encode::Error::ParseFailed("unknown bloom filter update flag")),
+        }
+    }
+}
+
+/// The `filterload` message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct FilterLoad {
+    /// The filter itself, at most `MAX_BLOOM_FILTER_SIZE` bytes
+    pub filter: Vec<u8>,
+    /// Number of hash functions, at most `MAX_HASH_FUNCS`
+    pub n_hash_funcs: u32,
+    /// Random value added to the seed of each hash function
+    pub n_tweak: u32,
+    /// How matched transactions update the filter
+    pub n_flags: BloomFlags,
+}
+
+impl Encodable for FilterLoad {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let len = self.filter.consensus_encode(&mut s)?
+            + self.n_hash_funcs.consensus_encode(&mut s)?
+            + self.n_tweak.consensus_encode(&mut s)?
+            + self.n_flags.consensus_encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for FilterLoad {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let filter: Vec<u8> = Decodable::consensus_decode(&mut d)?;
+        if filter.len() > MAX_BLOOM_FILTER_SIZE {
+            return Err(encode::Error::OversizedVectorAllocation { requested: filter.len(), max: MAX_BLOOM_FILTER_SIZE });
+        }
+        let n_hash_funcs: u32 = Decodable::consensus_decode(&mut d)?;
+        if n_hash_funcs > MAX_HASH_FUNCS {
+            return Err(encode::Error::ParseFailed("too many bloom filter hash functions"));
+        }
+        Ok(FilterLoad {
+            filter,
+            n_hash_funcs,
+            n_tweak: Decodable::consensus_decode(&mut d)?,
+            n_flags: Decodable::consensus_decode(d)?,
+        })
+    }
+}
+
+/// Builder for the filter sent in `filterload`
+///
+/// 用法
+///     let mut filter = BloomFilter::new(10, 0.0001, tweak, BloomFlags::All);
+///     filter.insert_pubkey(&key);
+///     Payload::FilterLoad(filter.into())
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    n_hash_funcs: u32,
+    n_tweak: u32,
+    flags: BloomFlags,
+}
+
+impl BloomFilter {
+    /// Size a filter for `elements` entries with false positive rate `fp_rate`, the same way Bitcoin Core does.
+    /// Both the size and the number of hash functions are capped at the protocol limits.
+    /// Like Core there is no lower bound: a rate near 1 gives an empty filter, which matches everything.
+    pub fn new(elements: u32, fp_rate: f64, n_tweak: u32, flags: BloomFlags) -> BloomFilter {
+        let elements = elements as f64;
+        let bits = (-1.0 / LN2SQUARED * elements * fp_rate.ln()) as usize;
+        let bytes = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let n_hash_funcs = ((bytes * 8) as f64 / elements * LN2) as u32;
+        BloomFilter {
+            data: vec![0u8; bytes],
+            n_hash_funcs: n_hash_funcs.min(MAX_HASH_FUNCS),
+            n_tweak,
+            flags,
+        }
+    }
+
+    fn bit_index(&self, hash_num: u32, data: &[u8]) -> usize {
+        let seed = hash_num.wrapping_mul(0xFBA4_C795).wrapping_add(self.n_tweak);
+        murmur3(seed, data) as usize % (self.data.len() * 8)
+    }
+
+    /// Add arbitrary data to the filter
+    pub fn insert(&mut self, data: &[u8]) {
+        // 空的 filter 不能取模 (CVE-2013-5700)
+        if self.data.is_empty() {
+            return;
+        }
+        for i in 0..self.n_hash_funcs {
+            let index = self.bit_index(i, data);
+            self.data[index >> 3] |= 1 << (7 & index);
+        }
+    }
+
+    /// Whether `data` matches the filter (possibly a false positive)
+    pub fn contains(&self, data: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return true;
+        }
+        (0..self.n_hash_funcs).all(|i| {
+            let index = self.bit_index(i, data);
+            self.data[index >> 3] & (1 << (7 & index)) != 0
+        })
+    }
+
+    /// Match transactions spending `outpoint`
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&serialize(outpoint));
+    }
+
+    /// Match transactions with `pubkey` in a script or witness
+    pub fn insert_pubkey(&mut self, pubkey: &PublicKey) {
+        self.insert(&pubkey.to_bytes());
+    }
+
+    /// Match P2SH outputs paying to this script hash. Works the same for the hash160 of a pubkey (P2PKH).
+    pub fn insert_script_hash(&mut self, hash: &hash160::Hash) {
+        self.insert(&hash[..]);
+    }
+}
+
+impl From<BloomFilter> for FilterLoad {
+    fn from(filter: BloomFilter) -> FilterLoad {
+        FilterLoad {
+            filter: filter.data,
+            n_hash_funcs: filter.n_hash_funcs,
+            n_tweak: filter.n_tweak,
+            n_flags: filter.flags,
+        }
+    }
+}
+
+/// MurmurHash3 (x86, 32 bit) as used by BIP37
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h1 = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k1 = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k1 = k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h1 ^= k1;
+        h1 = h1.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    let mut k1 = 0u32;
+    for (i, &byte) in tail.iter().enumerate() {
+        k1 ^= (byte as u32) << (8 * i);
+    }
+    if !tail.is_empty() {
+        k1 = k1.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h1 ^= k1;
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85eb_ca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2_ae35);
+    h1 ^= h1 >> 16;
+    h1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::deserialize;
+
+    #[test]
+    fn murmur3_vectors() {
+        // Bitcoin Core 的 hash_tests
+        let vectors: &[(u32, u32, &str)] = &[
+            (0x0000_0000, 0x0000_0000, ""),
+            (0x6a39_6f08, 0xFBA4_C795, ""),
+            (0x81f1_6f39, 0xffff_ffff, ""),
+            (0x514e_28b7, 0x0000_0000, "00"),
+            (0xea3f_0b17, 0xFBA4_C795, "00"),
+            (0xfd6c_f10d, 0x0000_0000, "ff"),
+            (0x16c6_b7ab, 0x0000_0000, "0011"),
+            (0x8eb5_1c3d, 0x0000_0000, "001122"),
+            (0xb447_1bf8, 0x0000_0000, "00112233"),
+            (0xe230_1fa8, 0x0000_0000, "0011223344"),
+            (0xfc2e_4a15, 0x0000_0000, "001122334455"),
+            (0xb074_502c, 0x0000_0000, "00112233445566"),
+            (0x8034_d2a0, 0x0000_0000, "0011223344556677"),
+            (0xb469_8def, 0x0000_0000, "001122334455667788"),
+        ];
+        for &(expected, seed, data) in vectors {
+            assert_eq!(murmur3(seed, &hex::decode(data).unwrap()), expected, "seed {:#x} data {}", seed, data);
+        }
+    }
+
+    fn core_filter(n_tweak: u32) -> BloomFilter {
+        let mut filter = BloomFilter::new(3, 0.01, n_tweak, BloomFlags::All);
+        let first = hex::decode("99108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap();
+        filter.insert(&first);
+        assert!(filter.contains(&first));
+        // 第一个字节差一位
+        assert!(!filter.contains(&hex::decode("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+        for data in &["b5a2c786d9ef4658287ced5914b37a1b4aa32eee", "b9300670b4c5366e95b2699e8b18bc75e5f729c5"] {
+            let data = hex::decode(data).unwrap();
+            filter.insert(&data);
+            assert!(filter.contains(&data));
+        }
+        filter
+    }
+
+    #[test]
+    fn create_insert_serialize() {
+        // Bitcoin Core 的 bloom_create_insert_serialize 和 bloom_create_insert_serialize_with_tweak
+        let filterload: FilterLoad = core_filter(0).into();
+        assert_eq!(hex::encode(serialize(&filterload)), "03614e9b050000000000000001");
+        let filterload: FilterLoad = core_filter(2_147_483_649).into();
+        assert_eq!(hex::encode(serialize(&filterload)), "03ce4299050000000100008001");
+    }
+
+    #[test]
+    fn hash_funcs_capped_like_core() {
+        // 元素很少 误报率很低的时候只受 MAX_HASH_FUNCS 限制
+        let filter: FilterLoad = BloomFilter::new(1, 1e-30, 0, BloomFlags::None).into();
+        assert_eq!(filter.n_hash_funcs, MAX_HASH_FUNCS);
+        assert!(filter.filter.len() <= MAX_BLOOM_FILTER_SIZE);
+
+        // 误报率接近 1 时 filter 是空的, 什么都匹配
+        let mut empty = BloomFilter::new(10, 0.99, 0, BloomFlags::None);
+        empty.insert(b"anything");
+        assert!(empty.contains(b"something else"));
+        assert!(FilterLoad::from(empty).filter.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let filterload = FilterLoad {
+            filter: vec![0xb5, 0x0f],
+            n_hash_funcs: 11,
+            n_tweak: 0,
+            n_flags: BloomFlags::None,
+        };
+        let bytes = serialize(&filterload);
+        // BIP37 文档里的例子
+        assert_eq!(hex::encode(&bytes), "02b50f0b0000000000000000");
+        assert_eq!(deserialize::<FilterLoad>(&bytes).unwrap(), filterload);
+
+        let filterload: FilterLoad = core_filter(5).into();
+        assert_eq!(deserialize::<FilterLoad>(&serialize(&filterload)).unwrap(), filterload);
+    }
+
+    #[test]
+    fn decode_rejects_limits() {
+        let mut filterload = FilterLoad {
+            filter: vec![0; MAX_BLOOM_FILTER_SIZE],
+            n_hash_funcs: MAX_HASH_FUNCS,
+            n_tweak: 0,
+            n_flags: BloomFlags::All,
+        };
+        assert!(deserialize::<FilterLoad>(&serialize(&filterload)).is_ok());
+
+        filterload.filter.push(0);
+        match deserialize::<FilterLoad>(&serialize(&filterload)) {
+            Err(encode::Error::OversizedVectorAllocation { requested, max }) => {
+                assert_eq!(requested, MAX_BLOOM_FILTER_SIZE + 1);
+                assert_eq!(max, MAX_BLOOM_FILTER_SIZE);
+            }
+            other => panic!("expected an oversized filter error, got {:?}", other),
+        }
+
+        filterload.filter.pop();
+        filterload.n_hash_funcs = MAX_HASH_FUNCS + 1;
+        match deserialize::<FilterLoad>(&serialize(&filterload)) {
+            Err(encode::Error::ParseFailed(_)) => {}
+            other => panic!("expected too many hash functions, got {:?}", other),
+        }
+
+        let mut bytes = serialize(&FilterLoad { n_hash_funcs: 1, ..filterload });
+        *bytes.last_mut().unwrap() = 3;
+        assert!(deserialize::<FilterLoad>(&bytes).is_err(), "unknown update flag");
+    }
+}
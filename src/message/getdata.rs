@@ -5,4 +5,7 @@
 //   + "ad7331c6e8f9eef231b7000000000000" # ... Block header hash
 //)
 
-pub struct GetData(pub Vec<u8>);
\ No newline at end of file
+impl_inventory_message!(
+    /// The `getdata` message, asks the peer for the listed objects
+    GetData
+);
@@ -0,0 +1,68 @@
+//! getheaders, asks for the headers following the first locator hash the peer knows
+//! [https://en.bitcoin.it/wiki/Protocol_documentation#getheaders]
+//!
+//! The peer answers with a `headers` message of at most `MAX_HEADERS_RESULTS` headers,
+//! stopping early at `stop_hash` (all zeros = as many as possible).
+
+use std::io;
+use bitcoin::consensus::{Encodable, Decodable, encode};
+use bitcoin::VarInt;
+use crate::message::inventory::BlockHash;
+use crate::message::version::PROTOCOL_VERSION;
+
+/// Most locator hashes a node accepts
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// The `getheaders` message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct GetHeaders {
+    /// Protocol version of the sender, ignored by current nodes
+    pub version: u32,
+    /// Block hashes from our tip back to genesis, dense at first and sparse further back
+    pub locator_hashes: Vec<BlockHash>,
+    /// Last header wanted, all zeros for no limit
+    pub stop_hash: BlockHash,
+}
+
+impl GetHeaders {
+    pub fn new(locator_hashes: Vec<BlockHash>, stop_hash: BlockHash) -> GetHeaders {
+        GetHeaders {
+            version: PROTOCOL_VERSION,
+            locator_hashes,
+            stop_hash,
+        }
+    }
+}
+
+impl Encodable for GetHeaders {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let mut len = self.version.consensus_encode(&mut s)?;
+        len += self.locator_hashes.consensus_encode(&mut s)?;
+        len += self.stop_hash.consensus_encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for GetHeaders {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let version = Decodable::consensus_decode(&mut d)?;
+        let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+        if count > MAX_LOCATOR_SIZE as u64 {
+            return Err(encode::Error::OversizedVectorAllocation { requested: count as usize, max: MAX_LOCATOR_SIZE });
+        }
+        let mut locator_hashes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            locator_hashes.push(Decodable::consensus_decode(&mut d)?);
+        }
+        Ok(GetHeaders {
+            version,
+            locator_hashes,
+            stop_hash: Decodable::consensus_decode(d)?,
+        })
+    }
+}
@@ -0,0 +1,50 @@
+//! headers, the reply to getheaders
+//! [https://en.bitcoin.it/wiki/Protocol_documentation#headers]
+//!
+//! 每个 header 后面跟一个交易数量 (var-int), 固定是 0
+
+use std::io;
+use bitcoin::BlockHeader;
+use bitcoin::consensus::{Encodable, Decodable, encode};
+use bitcoin::VarInt;
+
+/// Most headers a single `headers` message may carry
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// The `headers` message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Headers(pub Vec<BlockHeader>);
+
+impl Encodable for Headers {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.0.len() as u64).consensus_encode(&mut s)?;
+        for header in &self.0 {
+            len += header.consensus_encode(&mut s)?;
+            len += VarInt(0).consensus_encode(&mut s)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Headers {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+        if count > MAX_HEADERS_RESULTS as u64 {
+            return Err(encode::Error::OversizedVectorAllocation { requested: count as usize, max: MAX_HEADERS_RESULTS });
+        }
+        let mut headers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            headers.push(Decodable::consensus_decode(&mut d)?);
+            let VarInt(tx_count) = Decodable::consensus_decode(&mut d)?;
+            if tx_count != 0 {
+                return Err(encode::Error::ParseFailed("headers message with transactions"));
+            }
+        }
+        Ok(Headers(headers))
+    }
+}
@@ -0,0 +1,4 @@
+impl_inventory_message!(
+    /// The `inv` message, announces objects the peer has
+    Inv
+);
@@ -0,0 +1,227 @@
+//! inventory vectors shared by `inv`, `getdata` and `notfound`
+//! [https://en.bitcoin.it/wiki/Protocol_documentation#Inventory_Vectors]
+
+use std::io;
+use bitcoin::consensus::{Encodable, Decodable, encode};
+use bitcoin::VarInt;
+use bitcoin_hashes::sha256d;
+
+/// Hash of a block header
+pub type BlockHash = sha256d::Hash;
+/// Hash of a transaction without witness data
+pub type Txid = sha256d::Hash;
+/// Hash of a transaction including witness data (BIP141)
+pub type Wtxid = sha256d::Hash;
+
+/// Most entries a single inventory message may carry
+pub const MAX_INV_SIZE: usize = 50_000;
+
+// 高位的 witness flag (BIP144)
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+/// One entry of an inventory message: what kind of object, and its hash
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub enum Inventory {
+    /// MSG_TX
+    Tx(Txid),
+    /// MSG_BLOCK
+    Block(BlockHash),
+    /// MSG_FILTERED_BLOCK, answered with `merkleblock` (BIP37)
+    FilteredBlock(BlockHash),
+    /// MSG_CMPCT_BLOCK (BIP152)
+    CompactBlock(BlockHash),
+    /// MSG_WITNESS_TX (BIP144)
+    WitnessTx(Txid),
+    /// MSG_WITNESS_BLOCK (BIP144)
+    WitnessBlock(BlockHash),
+    /// MSG_WTX, a transaction announced by wtxid (BIP339)
+    WTx(Wtxid),
+    /// Any other type, kept as it was so it can be sent back unchanged
+    Unknown(u32, sha256d::Hash),
+}
+
+impl Inventory {
+    /// The numeric type written on the wire
+    pub fn inv_type(&self) -> u32 {
+        match *self {
+            Inventory::Tx(_) => 1,
+            Inventory::Block(_) => 2,
+            Inventory::FilteredBlock(_) => 3,
+            Inventory::CompactBlock(_) => 4,
+            Inventory::WTx(_) => 5,
+            Inventory::WitnessTx(_) => 1 | MSG_WITNESS_FLAG,
+            Inventory::WitnessBlock(_) => 2 | MSG_WITNESS_FLAG,
+            Inventory::Unknown(inv_type, _) => inv_type,
+        }
+    }
+
+    pub fn hash(&self) -> &sha256d::Hash {
+        match *self {
+            Inventory::Tx(ref hash)
+            | Inventory::Block(ref hash)
+            | Inventory::FilteredBlock(ref hash)
+            | Inventory::CompactBlock(ref hash)
+            | Inventory::WitnessTx(ref hash)
+            | Inventory::WitnessBlock(ref hash)
+            | Inventory::WTx(ref hash)
+            | Inventory::Unknown(_, ref hash) => hash,
+        }
+    }
+
+    /// Whether the object is wanted, or was announced, with its witness data
+    pub fn is_witness(&self) -> bool {
+        matches!(*self, Inventory::WitnessTx(_) | Inventory::WitnessBlock(_) | Inventory::WTx(_))
+    }
+
+    /// The same request including witness data, for peers offering NODE_WITNESS.
+    /// `Tx` becomes `WitnessTx` and `Block` becomes `WitnessBlock`, everything else is unchanged.
+    pub fn witness(self) -> Inventory {
+        match self {
+            Inventory::Tx(hash) => Inventory::WitnessTx(hash),
+            Inventory::Block(hash) => Inventory::WitnessBlock(hash),
+            other => other,
+        }
+    }
+}
+
+impl Encodable for Inventory {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let len = self.inv_type().consensus_encode(&mut s)?
+            + self.hash().consensus_encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Inventory {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let inv_type: u32 = Decodable::consensus_decode(&mut d)?;
+        let hash: sha256d::Hash = Decodable::consensus_decode(d)?;
+        Ok(match inv_type {
+            1 => Inventory::Tx(hash),
+            2 => Inventory::Block(hash),
+            3 => Inventory::FilteredBlock(hash),
+            4 => Inventory::CompactBlock(hash),
+            5 => Inventory::WTx(hash),
+            t if t == 1 | MSG_WITNESS_FLAG => Inventory::WitnessTx(hash),
+            t if t == 2 | MSG_WITNESS_FLAG => Inventory::WitnessBlock(hash),
+            t => Inventory::Unknown(t, hash),
+        })
+    }
+}
+
+/// Write a var-int count followed by the entries
+pub fn encode_inventory<S: io::Write>(inventory: &[Inventory], mut s: S) -> Result<usize, encode::Error> {
+    let mut len = VarInt(inventory.len() as u64).consensus_encode(&mut s)?;
+    for inv in inventory {
+        len += inv.consensus_encode(&mut s)?;
+    }
+    Ok(len)
+}
+
+/// Read a var-int count followed by the entries, refusing more than `MAX_INV_SIZE`
+/// before anything is allocated
+pub fn decode_inventory<D: io::Read>(mut d: D) -> Result<Vec<Inventory>, encode::Error> {
+    let VarInt(count) = Decodable::consensus_decode(&mut d)?;
+    if count > MAX_INV_SIZE as u64 {
+        return Err(encode::Error::OversizedVectorAllocation { requested: count as usize, max: MAX_INV_SIZE });
+    }
+    let mut inventory = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        inventory.push(Decodable::consensus_decode(&mut d)?);
+    }
+    Ok(inventory)
+}
+
+//inv, getdata 和 notfound 都只是一串 Inventory, 用法把文档和结构体名传进去
+macro_rules! impl_inventory_message {
+    ($(#[$attr:meta])* $thing:ident) => (
+        $(#[$attr])*
+        #[derive(PartialEq, Eq, Clone, Debug)]
+        pub struct $thing(pub Vec<$crate::message::inventory::Inventory>);
+
+        impl bitcoin::consensus::Encodable for $thing {
+            #[inline]
+            fn consensus_encode<S: ::std::io::Write>(
+                &self,
+                s: S,
+            ) -> Result<usize, bitcoin::consensus::encode::Error> {
+                $crate::message::inventory::encode_inventory(&self.0, s)
+            }
+        }
+
+        impl bitcoin::consensus::Decodable for $thing {
+            #[inline]
+            fn consensus_decode<D: ::std::io::Read>(
+                d: D,
+            ) -> Result<$thing, bitcoin::consensus::encode::Error> {
+                Ok($thing($crate::message::inventory::decode_inventory(d)?))
+            }
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin_hashes::Hash;
+    use crate::message::getdata::GetData;
+
+    fn hash(n: u8) -> sha256d::Hash {
+        sha256d::Hash::from_slice(&[n; 32]).unwrap()
+    }
+
+    #[test]
+    fn type_codes() {
+        let h = hash(7);
+        let cases = [
+            (Inventory::Tx(h), 1),
+            (Inventory::Block(h), 2),
+            (Inventory::FilteredBlock(h), 3),
+            (Inventory::CompactBlock(h), 4),
+            (Inventory::WTx(h), 5),
+            (Inventory::WitnessTx(h), 0x4000_0001),
+            (Inventory::WitnessBlock(h), 0x4000_0002),
+        ];
+        for &(inv, code) in cases.iter() {
+            assert_eq!(inv.inv_type(), code, "{:?}", inv);
+            let bytes = serialize(&inv);
+            assert_eq!(bytes[..4], code.to_le_bytes(), "{:?}", inv);
+            assert_eq!(bytes[4..], h[..]);
+            assert_eq!(deserialize::<Inventory>(&bytes).unwrap(), inv);
+        }
+        assert_eq!(Inventory::Tx(h).witness(), Inventory::WitnessTx(h));
+        assert_eq!(Inventory::Block(h).witness(), Inventory::WitnessBlock(h));
+        assert_eq!(Inventory::FilteredBlock(h).witness(), Inventory::FilteredBlock(h));
+        assert!(Inventory::WTx(h).is_witness() && !Inventory::Tx(h).is_witness());
+    }
+
+    #[test]
+    fn unknown_type_round_trip() {
+        // 没见过的类型, 包括带 witness flag 的, 原样收原样发
+        for &code in [0u32, 6, 0x4000_0003, 0xffff_ffff].iter() {
+            let inv = Inventory::Unknown(code, hash(1));
+            let bytes = serialize(&inv);
+            assert_eq!(bytes[..4], code.to_le_bytes());
+            assert_eq!(deserialize::<Inventory>(&bytes).unwrap(), inv);
+        }
+    }
+
+    #[test]
+    fn too_many_entries() {
+        let getdata = GetData(vec![Inventory::Tx(hash(2)); MAX_INV_SIZE]);
+        assert_eq!(deserialize::<GetData>(&serialize(&getdata)).unwrap(), getdata);
+
+        // 只有个数, 后面什么都没有, 也要在分配之前就拒绝
+        let bytes = serialize(&VarInt(MAX_INV_SIZE as u64 + 1));
+        match deserialize::<GetData>(&bytes) {
+            Err(encode::Error::OversizedVectorAllocation { requested, max }) => assert_eq!((requested, max), (MAX_INV_SIZE + 1, MAX_INV_SIZE)),
+            other => panic!("expected an oversized vector, got {:?}", other),
+        }
+    }
+}
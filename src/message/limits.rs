@@ -0,0 +1,189 @@
+//! Limits on what a peer may send
+//!
+//! 和 Bitcoin Core 一样
+//!     整条消息的 payload 最多 4,000,000 字节, 在分配内存之前就检查 header 里的长度
+//!     inv/getdata/notfound 最多 50,000 项, headers 最多 2000 个, addr/addrv2 最多 1000 个
+//!     getheaders 的 locator 最多 101 个, user agent 最多 256 字节
+//!
+//! The counts are read from the front of the payload before anything is decoded, so a peer cannot
+//! make us allocate more than the limits allow. A violation is reported as a `Misbehavior`.
+
+use std::{fmt, error};
+use bitcoin::consensus::deserialize_partial;
+use bitcoin::VarInt;
+use crate::message::command::CommandString;
+use crate::message::addr::MAX_ADDR_TO_SEND;
+use crate::message::getheaders::MAX_LOCATOR_SIZE;
+use crate::message::headers::MAX_HEADERS_RESULTS;
+use crate::message::inventory::MAX_INV_SIZE;
+use crate::message::version::ADDR_FROM_VERSION;
+
+/// Largest payload of any message
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4_000_000;
+/// Longest user agent in `version`
+pub const MAX_SUBVERSION_LENGTH: usize = 256;
+
+/// version(4) + services(8) + timestamp(8) + receiver(26) + sender(26) + nonce(8)
+const USER_AGENT_OFFSET: usize = 80;
+
+/// A message that breaks the limits of the protocol
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Misbehavior {
+    /// The header announces a payload larger than `MAX_PROTOCOL_MESSAGE_LENGTH`
+    OversizedMessage {
+        command: CommandString,
+        /// Announced payload size
+        size: usize,
+        max: usize,
+    },
+    /// A list in the message has more entries than its command allows
+    TooManyEntries {
+        command: CommandString,
+        /// Announced number of entries
+        count: u64,
+        max: usize,
+    },
+    /// The user agent of `version` is longer than `MAX_SUBVERSION_LENGTH`
+    UserAgentTooLong(u64),
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Misbehavior::OversizedMessage { ref command, size, max } =>
+                write!(f, "{:?} message of {} bytes, at most {} allowed", command.0, size, max),
+            Misbehavior::TooManyEntries { ref command, count, max } =>
+                write!(f, "{:?} message with {} entries, at most {} allowed", command.0, count, max),
+            Misbehavior::UserAgentTooLong(len) =>
+                write!(f, "user agent of {} bytes, at most {} allowed", len, MAX_SUBVERSION_LENGTH),
+        }
+    }
+}
+
+impl error::Error for Misbehavior {}
+
+/// Check the size a message header announces, before its payload is read
+pub fn check_length(command: &CommandString, length: usize) -> Result<(), Misbehavior> {
+    if length > MAX_PROTOCOL_MESSAGE_LENGTH {
+        return Err(Misbehavior::OversizedMessage { command: command.clone(), size: length, max: MAX_PROTOCOL_MESSAGE_LENGTH });
+    }
+    Ok(())
+}
+
+/// Check the per-command limits of a payload, before it is decoded.
+/// A payload too short to hold the counted fields passes, decoding it fails anyway.
+pub fn check_payload(command: &CommandString, payload: &[u8]) -> Result<(), Misbehavior> {
+    let max = match command.as_str() {
+        "inv" | "getdata" | "notfound" => MAX_INV_SIZE,
+        "headers" => MAX_HEADERS_RESULTS,
+        "addr" | "addrv2" => MAX_ADDR_TO_SEND,
+        "getheaders" => {
+            // 前面是 4 字节的版本
+            return check_count(command, payload.get(4..), MAX_LOCATOR_SIZE);
+        }
+        "version" => return check_user_agent(payload),
+        _ => return Ok(()),
+    };
+    check_count(command, Some(payload), max)
+}
+
+fn read_count(data: Option<&[u8]>) -> Option<u64> {
+    data.and_then(|data| deserialize_partial::<VarInt>(data).ok()).map(|(VarInt(count), _)| count)
+}
+
+fn check_count(command: &CommandString, data: Option<&[u8]>, max: usize) -> Result<(), Misbehavior> {
+    match read_count(data) {
+        Some(count) if count > max as u64 => Err(Misbehavior::TooManyEntries { command: command.clone(), count, max }),
+        _ => Ok(()),
+    }
+}
+
+fn check_user_agent(payload: &[u8]) -> Result<(), Misbehavior> {
+    let version = match payload.get(..4) {
+        Some(bytes) => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        None => return Ok(()),
+    };
+    if version < ADDR_FROM_VERSION {
+        return Ok(());
+    }
+    match read_count(payload.get(USER_AGENT_OFFSET..)) {
+        Some(len) if len > MAX_SUBVERSION_LENGTH as u64 => Err(Misbehavior::UserAgentTooLong(len)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkParams;
+    use bitcoin::consensus::serialize;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use crate::codec::MessageCodec;
+    use crate::error::Error;
+    use crate::message::{sha_sha, MessageHeader, RawMessage, MAINNET};
+
+    fn command(name: &str) -> CommandString {
+        CommandString(name.to_owned())
+    }
+
+    // 手工拼一条消息, checksum 是对的
+    fn frame(name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut checksum = [0u8; 4];
+        checksum.copy_from_slice(&sha_sha(payload));
+        let header = MessageHeader { magic: MAINNET, command: command(name), length: payload.len() as u32, checksum };
+        let mut bytes = serialize(&header);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn oversized_length_from_header() {
+        let header = MessageHeader { magic: MAINNET, command: command("block"), length: 4_000_001, checksum: [0; 4] };
+        let bytes = serialize(&header);
+        let expected = Misbehavior::OversizedMessage { command: command("block"), size: 4_000_001, max: MAX_PROTOCOL_MESSAGE_LENGTH };
+        match RawMessage::parse(&bytes) {
+            Err(Error::Misbehavior(e)) => assert_eq!(e, expected),
+            other => panic!("expected misbehavior, got {:?}", other),
+        }
+
+        // codec 只看了 header 就拒绝, 不会为 payload 分配内存
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let mut src = BytesMut::from(&bytes[..]);
+        match codec.decode(&mut src) {
+            Err(e @ Error::Misbehavior(_)) => assert!(e.is_fatal()),
+            other => panic!("expected misbehavior, got {:?}", other),
+        }
+        assert!(src.capacity() < MAX_PROTOCOL_MESSAGE_LENGTH);
+        assert_eq!(check_length(&command("block"), MAX_PROTOCOL_MESSAGE_LENGTH), Ok(()));
+    }
+
+    #[test]
+    fn too_many_entries() {
+        for &(name, max) in &[("inv", MAX_INV_SIZE), ("getdata", MAX_INV_SIZE), ("headers", MAX_HEADERS_RESULTS),
+                              ("addr", MAX_ADDR_TO_SEND), ("addrv2", MAX_ADDR_TO_SEND)] {
+            // 只有一个数量, 后面的条目根本不用发
+            let payload = serialize(&VarInt(max as u64 + 1));
+            let expected = Misbehavior::TooManyEntries { command: command(name), count: max as u64 + 1, max };
+            assert_eq!(check_payload(&command(name), &payload), Err(expected.clone()));
+            match RawMessage::parse(&frame(name, &payload)) {
+                Err(Error::Misbehavior(e)) => assert_eq!(e, expected),
+                other => panic!("expected misbehavior for {}, got {:?}", name, other),
+            }
+            // 刚好到上限的数量不算, 数据不够是解码的事
+            assert_eq!(check_payload(&command(name), &serialize(&VarInt(max as u64))), Ok(()));
+        }
+    }
+
+    #[test]
+    fn locator_and_user_agent() {
+        let mut payload = serialize(&70016u32);
+        payload.extend(serialize(&VarInt(MAX_LOCATOR_SIZE as u64 + 1)));
+        assert!(matches!(check_payload(&command("getheaders"), &payload), Err(Misbehavior::TooManyEntries { .. })));
+
+        let mut payload = vec![0u8; USER_AGENT_OFFSET];
+        payload[..4].copy_from_slice(&serialize(&70016u32));
+        payload.extend(serialize(&VarInt(MAX_SUBVERSION_LENGTH as u64 + 1)));
+        assert_eq!(check_payload(&command("version"), &payload), Err(Misbehavior::UserAgentTooLong(257)));
+    }
+}
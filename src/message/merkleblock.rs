@@ -0,0 +1,232 @@
+//! merkleblock, the reply to a getdata for a filtered block (BIP37)
+//!
+//! The peer sends the block header plus a partial merkle tree: just enough hashes to recompute
+//! the merkle root, and one flag bit per visited node saying whether a matched transaction is below it.
+//! `MerkleBlock::extract_matches` walks the tree depth first, the same way Bitcoin Core does.
+
+use std::{fmt, error};
+use bitcoin::BlockHeader;
+use bitcoin_hashes::{sha256d, Hash, HashEngine};
+use crate::message::inventory::Txid;
+
+// 4_000_000 / 240 一个区块最多能放下的交易数
+const MAX_TRANSACTIONS: u32 = 4_000_000 / 240;
+
+/// The `merkleblock` message
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct MerkleBlock {
+    /// Header of the filtered block
+    pub header: BlockHeader,
+    /// Number of transactions in the full block
+    pub total_transactions: u32,
+    /// Hashes of the partial merkle tree in depth-first order
+    pub hashes: Vec<sha256d::Hash>,
+    /// Flag bits in depth-first order, packed least significant bit first
+    pub flags: Vec<u8>,
+}
+
+impl_consensus_encoding!(MerkleBlock, header, total_transactions, hashes, flags);
+
+/// Reasons a partial merkle tree is rejected
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum MerkleBlockError {
+    /// The block claims to have no transactions
+    NoTransactions,
+    /// More transactions than fit in a block
+    TooManyTransactions(u32),
+    /// More hashes than transactions
+    TooManyHashes,
+    /// Fewer flag bits than hashes
+    NotEnoughBits,
+    /// The walk needed more flag bits than were sent
+    BitsExhausted,
+    /// The walk needed more hashes than were sent
+    HashesExhausted,
+    /// Flag bits were left over after the walk
+    UnusedBits,
+    /// Hashes were left over after the walk
+    UnusedHashes,
+    /// A node has two identical children (CVE-2012-2459)
+    IdenticalHashes,
+    /// The calculated root differs from the one in the header
+    MerkleRootMismatch {
+        /// Root in the block header
+        expected: sha256d::Hash,
+        /// Root calculated from the partial tree
+        actual: sha256d::Hash,
+    },
+}
+
+impl fmt::Display for MerkleBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MerkleBlockError::NoTransactions => write!(f, "merkle block has no transactions"),
+            MerkleBlockError::TooManyTransactions(n) => write!(f, "merkle block claims {} transactions", n),
+            MerkleBlockError::TooManyHashes => write!(f, "more hashes than transactions"),
+            MerkleBlockError::NotEnoughBits => write!(f, "fewer flag bits than hashes"),
+            MerkleBlockError::BitsExhausted => write!(f, "ran out of flag bits"),
+            MerkleBlockError::HashesExhausted => write!(f, "ran out of hashes"),
+            MerkleBlockError::UnusedBits => write!(f, "not all flag bits were used"),
+            MerkleBlockError::UnusedHashes => write!(f, "not all hashes were used"),
+            MerkleBlockError::IdenticalHashes => write!(f, "identical left and right hashes (CVE-2012-2459)"),
+            MerkleBlockError::MerkleRootMismatch { ref expected, ref actual } =>
+                write!(f, "merkle root mismatch: expected {}, actual {}", expected, actual),
+        }
+    }
+}
+
+impl error::Error for MerkleBlockError {}
+
+impl MerkleBlock {
+    /// Check the partial merkle tree against the header and return the txids of the matched transactions,
+    /// in the order they appear in the block
+    pub fn extract_matches(&self) -> Result<Vec<Txid>, MerkleBlockError> {
+        if self.total_transactions == 0 {
+            return Err(MerkleBlockError::NoTransactions);
+        }
+        if self.total_transactions > MAX_TRANSACTIONS {
+            return Err(MerkleBlockError::TooManyTransactions(self.total_transactions));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(MerkleBlockError::TooManyHashes);
+        }
+        if self.flags.len() * 8 < self.hashes.len() {
+            return Err(MerkleBlockError::NotEnoughBits);
+        }
+
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+
+        let mut walker = Walker { block: self, bits_used: 0, hashes_used: 0, matches: Vec::new() };
+        let root = walker.traverse(height, 0)?;
+
+        // 所有 flag 字节和 hash 都必须刚好用完
+        if walker.bits_used.div_ceil(8) != self.flags.len() {
+            return Err(MerkleBlockError::UnusedBits);
+        }
+        if walker.hashes_used != self.hashes.len() {
+            return Err(MerkleBlockError::UnusedHashes);
+        }
+        if root != self.header.merkle_root {
+            return Err(MerkleBlockError::MerkleRootMismatch { expected: self.header.merkle_root, actual: root });
+        }
+        Ok(walker.matches)
+    }
+
+    /// Number of nodes at `height` (0 = leaves)
+    fn tree_width(&self, height: u32) -> u32 {
+        (self.total_transactions + (1 << height) - 1) >> height
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.flags[index / 8] & (1 << (index % 8)) != 0
+    }
+}
+
+struct Walker<'a> {
+    block: &'a MerkleBlock,
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<Txid>,
+}
+
+impl<'a> Walker<'a> {
+    fn traverse(&mut self, height: u32, pos: u32) -> Result<sha256d::Hash, MerkleBlockError> {
+        if self.bits_used >= self.block.flags.len() * 8 {
+            return Err(MerkleBlockError::BitsExhausted);
+        }
+        let parent_of_match = self.block.bit(self.bits_used);
+        self.bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            // 叶子节点或者下面没有匹配的交易 直接用给出的 hash
+            let hash = *self.block.hashes.get(self.hashes_used).ok_or(MerkleBlockError::HashesExhausted)?;
+            self.hashes_used += 1;
+            if height == 0 && parent_of_match {
+                self.matches.push(hash);
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse(height - 1, pos * 2)?;
+        let right = if pos * 2 + 1 < self.block.tree_width(height - 1) {
+            let right = self.traverse(height - 1, pos * 2 + 1)?;
+            if right == left {
+                return Err(MerkleBlockError::IdenticalHashes);
+            }
+            right
+        } else {
+            left
+        };
+
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&left[..]);
+        engine.input(&right[..]);
+        Ok(sha256d::Hash::from_engine(engine))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::BitcoinHash;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin_hashes::hex::FromHex;
+
+    // mainnet 区块 0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af 里的一笔交易
+    // bitcoin-cli gettxoutproof '["220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a"]'
+    const MAINNET_MERKLEBLOCK: &str = "\
+        0100000090f0a9f110702f808219ebea1173056042a714bad51b916cb6800000000000005275289558f51c\
+        9966699404ae2294730c3c9f9bda53523ce50e9b95e558da2fdb261b4d4c86041b1ab1bf930900000005fac\
+        7708a6e81b2a986dea60db2663840ed141130848162eb1bd1dee54f309a1b2ee1e12587e497ada70d9bd10d\
+        31e83f0a924825b96cb8d04e8936d793fb60db7ad8b910d0c7ba2369bc7f18bb53d80e1869ba2c32274996c\
+        ebe1ae264bc0e2289189ff0316cdc10511da71da757e553cada9f3b5b1434f3923673adb57d83caac392c38\
+        af156d6fc30b55fad4112df2b95531e68114e9ad10011e72f7b7cfdb025700";
+
+    fn mainnet() -> MerkleBlock {
+        deserialize(&Vec::<u8>::from_hex(MAINNET_MERKLEBLOCK).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn mainnet_vector() {
+        let block = mainnet();
+        assert_eq!(block.header.bitcoin_hash(),
+                   sha256d::Hash::from_hex("0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af").unwrap());
+        assert_eq!(block.total_transactions, 9);
+        assert_eq!(block.extract_matches().unwrap(),
+                   vec![Txid::from_hex("220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a").unwrap()]);
+        assert_eq!(serialize(&block), Vec::<u8>::from_hex(MAINNET_MERKLEBLOCK).unwrap());
+    }
+
+    #[test]
+    fn rejects_identical_hashes() {
+        // 两笔一样的交易算出来的根和一笔交易复制一次一样 (CVE-2012-2459)
+        let leaf = sha256d::Hash::hash(b"tx");
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&leaf[..]);
+        engine.input(&leaf[..]);
+        let mut header = mainnet().header;
+        header.merkle_root = sha256d::Hash::from_engine(engine);
+        let block = MerkleBlock { header, total_transactions: 2, hashes: vec![leaf, leaf], flags: vec![0b111] };
+        assert_eq!(block.extract_matches(), Err(MerkleBlockError::IdenticalHashes));
+    }
+
+    #[test]
+    fn rejects_unused_bits() {
+        let mut block = mainnet();
+        block.flags.push(0);
+        assert_eq!(block.extract_matches(), Err(MerkleBlockError::UnusedBits));
+    }
+
+    #[test]
+    fn rejects_wrong_root() {
+        let mut block = mainnet();
+        block.hashes.swap(0, 1);
+        match block.extract_matches() {
+            Err(MerkleBlockError::MerkleRootMismatch { expected, .. }) => assert_eq!(expected, block.header.merkle_root),
+            other => panic!("expected a root mismatch, got {:?}", other),
+        }
+    }
+}
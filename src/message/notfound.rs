@@ -0,0 +1,4 @@
+impl_inventory_message!(
+    /// The `notfound` message, the reply to the part of a `getdata` the peer could not serve
+    NotFound
+);
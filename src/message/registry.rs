@@ -0,0 +1,257 @@
+//! Message types defined outside this crate
+//!
+//! `Payload` 是封闭的 enum, 别的 crate 没法往里面加消息类型 (murmel 的 NetworkMessage 也是这个问题)
+//!     自定义的消息实现 `MessagePayload`, 命令名 + Encodable/Decodable
+//!     注册到 `MessageRegistry`, 交给 `MessageCodec::with_registry`
+//!     收到的时候解码成 `Payload::Custom`, 用 `downcast_ref` 取回原来的类型
+//!
+//! Commands that are neither built in nor registered arrive as `Payload::Unknown` with their raw bytes.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use bitcoin::consensus::{Encodable, Decodable, deserialize};
+use crate::error::Error;
+use crate::message::Payload;
+use crate::message::command::{CommandString, CommandError};
+
+/// A message type that can be sent and received next to the built-in ones
+///
+/// 用法
+///     #[derive(PartialEq, Eq, Clone, Debug)]
+///     struct SendTxRcncl { version: u32, salt: u64 }
+///     impl_consensus_encoding!(SendTxRcncl, version, salt);
+///     impl MessagePayload for SendTxRcncl {
+///         const COMMAND: &'static str = "sendtxrcncl";
+///     }
+///     let mut registry = MessageRegistry::new();
+///     registry.register::<SendTxRcncl>()?;
+///     let framed = Framed::new(stream, MessageCodec::with_registry(&network, registry));
+pub trait MessagePayload: Encodable + Decodable + fmt::Debug + Send + Sync + 'static {
+    /// Command of the message, at most 12 printable ASCII characters
+    const COMMAND: &'static str;
+}
+
+// Payload 要能 Clone/Debug/PartialEq, 只能把具体类型藏在 trait object 后面
+trait AnyPayload: fmt::Debug + Send + Sync {
+    fn encode(&self) -> Result<Vec<u8>, Error>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: MessagePayload> AnyPayload for T {
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.consensus_encode(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A decoded message of a registered `MessagePayload` type
+#[derive(Clone)]
+pub struct CustomPayload {
+    command: CommandString,
+    payload: Arc<dyn AnyPayload>,
+}
+
+impl CustomPayload {
+    pub fn new<T: MessagePayload>(payload: T) -> CustomPayload {
+        CustomPayload {
+            command: CommandString(T::COMMAND.to_owned()),
+            payload: Arc::new(payload),
+        }
+    }
+
+    pub fn command(&self) -> &CommandString {
+        &self.command
+    }
+
+    /// The message as `T`, if it is one
+    pub fn downcast_ref<T: MessagePayload>(&self) -> Option<&T> {
+        self.payload.as_any().downcast_ref()
+    }
+
+    /// The serialized payload
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        self.payload.encode()
+    }
+}
+
+impl fmt::Debug for CustomPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.payload, f)
+    }
+}
+
+// 具体类型不一定能比较 按命令和序列化后的字节比
+impl PartialEq for CustomPayload {
+    fn eq(&self, other: &CustomPayload) -> bool {
+        self.command == other.command && match (self.encode(), other.encode()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for CustomPayload {}
+
+type PayloadDecoder = fn(&[u8]) -> Result<Payload, Error>;
+
+fn decode_custom<T: MessagePayload>(data: &[u8]) -> Result<Payload, Error> {
+    Ok(Payload::Custom(CustomPayload::new(deserialize::<T>(data)?)))
+}
+
+/// Decoders of the registered message types, by command
+///
+/// A registered command takes precedence over the built-in decoder of the same name.
+#[derive(Clone, Default, Debug)]
+pub struct MessageRegistry {
+    decoders: HashMap<String, PayloadDecoder>,
+}
+
+impl MessageRegistry {
+    /// A registry knowing only the built-in messages
+    pub fn new() -> MessageRegistry {
+        MessageRegistry::default()
+    }
+
+    /// Decode messages with the command of `T` as `T`.
+    /// Fails, registering nothing, if `T::COMMAND` could never be sent as a command.
+    pub fn register<T: MessagePayload>(&mut self) -> Result<&mut MessageRegistry, CommandError> {
+        CommandString::validate(T::COMMAND)?;
+        self.decoders.insert(T::COMMAND.to_owned(), decode_custom::<T>);
+        Ok(self)
+    }
+
+    pub fn is_registered(&self, command: &CommandString) -> bool {
+        self.decoders.contains_key(command.as_str())
+    }
+
+    /// Decode a payload with the decoder registered for `command`, or else like `Payload::decode`
+    pub fn decode(&self, command: &CommandString, data: &[u8]) -> Result<Payload, Error> {
+        self.decode_requested(command, data, true)
+    }
+
+    /// Like `decode`, the built-in messages decoded like `Payload::decode_requested`
+    pub fn decode_requested(&self, command: &CommandString, data: &[u8], witness: bool) -> Result<Payload, Error> {
+        match self.decoders.get(command.as_str()) {
+            Some(decode) => decode(data),
+            None => Payload::decode_requested(command, data, witness),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkParams;
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+    use crate::codec::MessageCodec;
+    use crate::message::RawMessage;
+
+    #[derive(PartialEq, Eq, Clone, Debug)]
+    struct SendTxRcncl {
+        version: u32,
+        salt: u64,
+    }
+    impl_consensus_encoding!(SendTxRcncl, version, salt);
+
+    impl MessagePayload for SendTxRcncl {
+        const COMMAND: &'static str = "sendtxrcncl";
+    }
+
+    #[derive(PartialEq, Eq, Clone, Debug)]
+    struct TooLong {
+        value: u8,
+    }
+    impl_consensus_encoding!(TooLong, value);
+
+    impl MessagePayload for TooLong {
+        const COMMAND: &'static str = "much_too_long";
+    }
+
+    #[derive(PartialEq, Eq, Clone, Debug)]
+    struct NotAscii {
+        value: u8,
+    }
+    impl_consensus_encoding!(NotAscii, value);
+
+    impl MessagePayload for NotAscii {
+        const COMMAND: &'static str = "ping\n";
+    }
+
+    const RCNCL: SendTxRcncl = SendTxRcncl { version: 1, salt: 0x0102_0304_0506_0708 };
+
+    fn message() -> RawMessage {
+        RawMessage::new(&NetworkParams::mainnet(), CommandString(SendTxRcncl::COMMAND.to_owned()), Payload::Custom(CustomPayload::new(RCNCL)))
+    }
+
+    fn registry() -> MessageRegistry {
+        let mut registry = MessageRegistry::new();
+        registry.register::<SendTxRcncl>().unwrap();
+        registry
+    }
+
+    fn assert_custom(message: &RawMessage) {
+        assert_eq!(message.command().as_str(), "sendtxrcncl");
+        match message.payload() {
+            Payload::Custom(payload) => assert_eq!(payload.downcast_ref::<SendTxRcncl>(), Some(&RCNCL)),
+            other => panic!("expected a custom payload, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn custom_payload_through_raw_message() {
+        let bytes = message().combine().unwrap();
+        let (parsed, consumed) = RawMessage::parse_with(&NetworkParams::mainnet(), &registry(), &bytes).unwrap();
+        assert_eq!(consumed, bytes.len());
+        assert_custom(&parsed);
+        assert_eq!(parsed, message());
+    }
+
+    #[test]
+    fn custom_payload_through_codec() {
+        let mut codec = MessageCodec::with_registry(&NetworkParams::mainnet(), registry());
+        let mut buffer = BytesMut::new();
+        codec.encode(message(), &mut buffer).unwrap();
+        codec.encode(message(), &mut buffer).unwrap();
+        for _ in 0..2 {
+            assert_custom(&codec.decode(&mut buffer).unwrap().expect("a whole frame"));
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn unregistered_command_stays_unknown() {
+        let bytes = message().combine().unwrap();
+        let mut codec = MessageCodec::new(&NetworkParams::mainnet());
+        let parsed = codec.decode(&mut BytesMut::from(&bytes[..])).unwrap().expect("a whole frame");
+        match parsed.payload() {
+            Payload::Unknown { command, bytes } => {
+                assert_eq!(command.as_str(), "sendtxrcncl");
+                // version 小端序, 然后是 salt
+                assert_eq!(hex::encode(bytes), "010000000807060504030201");
+            }
+            other => panic!("expected an unknown payload, got {:?}", other),
+        }
+        // 原样发出去的字节一样
+        assert_eq!(parsed.combine().unwrap(), bytes);
+    }
+
+    #[test]
+    fn register_checks_the_command() {
+        let mut registry = MessageRegistry::new();
+        assert_eq!(registry.register::<TooLong>().err(), Some(CommandError::TooLong(13)));
+        assert_eq!(registry.register::<NotAscii>().err(), Some(CommandError::InvalidChar('\n')));
+        assert!(!registry.is_registered(&CommandString(TooLong::COMMAND.to_owned())));
+        assert!(!registry.is_registered(&CommandString(NotAscii::COMMAND.to_owned())));
+
+        registry.register::<SendTxRcncl>().unwrap();
+        assert!(registry.is_registered(&CommandString("sendtxrcncl".to_owned())));
+    }
+}
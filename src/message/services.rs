@@ -0,0 +1,231 @@
+//! Service bits of `version`, `addr` and `addrv2`
+//! [https://github.com/bitcoin/bitcoin/blob/master/src/protocol.h]
+//!
+//! 每一位代表节点提供的一种服务
+//!     NETWORK             1       完整的区块链
+//!     GETUTXO             2       BIP64, 已经没人用了
+//!     BLOOM               4       BIP111, 支持 filterload
+//!     WITNESS             8       BIP144, 能发带 witness 的区块和交易
+//!     COMPACT_FILTERS     64      BIP157
+//!     NETWORK_LIMITED     1024    BIP159, 只有最近 288 个区块
+//!     P2P_V2              2048    BIP324
+//!
+//! Bits without a name here are kept as they are, so relaying an address does not lose what its node announced.
+
+use std::{io, fmt, ops};
+use bitcoin::consensus::{Encodable, Decodable, encode};
+
+/// A set of service bits
+#[derive(PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct ServiceFlags(u64);
+
+const NAMES: [(ServiceFlags, &str); 7] = [
+    (ServiceFlags::NETWORK, "NETWORK"),
+    (ServiceFlags::GETUTXO, "GETUTXO"),
+    (ServiceFlags::BLOOM, "BLOOM"),
+    (ServiceFlags::WITNESS, "WITNESS"),
+    (ServiceFlags::COMPACT_FILTERS, "COMPACT_FILTERS"),
+    (ServiceFlags::NETWORK_LIMITED, "NETWORK_LIMITED"),
+    (ServiceFlags::P2P_V2, "P2P_V2"),
+];
+
+impl ServiceFlags {
+    /// No services, what an SPV client announces
+    pub const NONE: ServiceFlags = ServiceFlags(0);
+    /// NODE_NETWORK: serves the full block chain
+    pub const NETWORK: ServiceFlags = ServiceFlags(1 << 0);
+    /// NODE_GETUTXO: answers `getutxos` (BIP64)
+    pub const GETUTXO: ServiceFlags = ServiceFlags(1 << 1);
+    /// NODE_BLOOM: accepts bloom filters (BIP111)
+    pub const BLOOM: ServiceFlags = ServiceFlags(1 << 2);
+    /// NODE_WITNESS: serves blocks and transactions with witness data (BIP144)
+    pub const WITNESS: ServiceFlags = ServiceFlags(1 << 3);
+    /// NODE_COMPACT_FILTERS: serves compact block filters (BIP157)
+    pub const COMPACT_FILTERS: ServiceFlags = ServiceFlags(1 << 6);
+    /// NODE_NETWORK_LIMITED: serves the last 288 blocks only (BIP159)
+    pub const NETWORK_LIMITED: ServiceFlags = ServiceFlags(1 << 10);
+    /// NODE_P2P_V2: speaks the encrypted transport (BIP324)
+    pub const P2P_V2: ServiceFlags = ServiceFlags(1 << 11);
+
+    /// The flags of a raw bitmask, unknown bits included
+    pub const fn from_bits(bits: u64) -> ServiceFlags {
+        ServiceFlags(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether every flag of `other` is set in `self`
+    pub fn has(self, other: ServiceFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn add(&mut self, other: ServiceFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: ServiceFlags) {
+        self.0 &= !other.0;
+    }
+
+    /// The flags of `self` missing from `other`
+    pub fn difference(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & !other.0)
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(bits: u64) -> ServiceFlags {
+        ServiceFlags(bits)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(flags: ServiceFlags) -> u64 {
+        flags.0
+    }
+}
+
+impl ops::BitOr for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitor(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 | other.0)
+    }
+}
+
+impl ops::BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, other: ServiceFlags) {
+        self.0 |= other.0;
+    }
+}
+
+impl ops::BitAnd for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitand(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & other.0)
+    }
+}
+
+impl ops::BitAndAssign for ServiceFlags {
+    fn bitand_assign(&mut self, other: ServiceFlags) {
+        self.0 &= other.0;
+    }
+}
+
+impl ops::BitXor for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitxor(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 ^ other.0)
+    }
+}
+
+impl ops::BitXorAssign for ServiceFlags {
+    fn bitxor_assign(&mut self, other: ServiceFlags) {
+        self.0 ^= other.0;
+    }
+}
+
+/// Names joined by `|`, unknown bits as one hex number at the end, e.g. `NETWORK|WITNESS|0x1000000`
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "NONE");
+        }
+        let mut rest = *self;
+        let mut first = true;
+        for &(flag, name) in NAMES.iter() {
+            if rest.has(flag) {
+                if !first {
+                    write!(f, "|")?;
+                }
+                write!(f, "{}", name)?;
+                rest.remove(flag);
+                first = false;
+            }
+        }
+        if !rest.is_empty() {
+            if !first {
+                write!(f, "|")?;
+            }
+            write!(f, "{:#x}", rest.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServiceFlags({})", self)
+    }
+}
+
+impl Encodable for ServiceFlags {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        s: S,
+    ) -> Result<usize, encode::Error> {
+        self.0.consensus_encode(s)
+    }
+}
+
+impl Decodable for ServiceFlags {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        Ok(ServiceFlags(Decodable::consensus_decode(d)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::{deserialize, serialize};
+
+    #[test]
+    fn display() {
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+        let all = NAMES.iter().fold(ServiceFlags::NONE, |all, &(flag, _)| all | flag);
+        assert_eq!(all.to_string(), "NETWORK|GETUTXO|BLOOM|WITNESS|COMPACT_FILTERS|NETWORK_LIMITED|P2P_V2");
+        assert_eq!((ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS).to_string(), "WITNESS|NETWORK_LIMITED");
+        assert_eq!(format!("{:?}", ServiceFlags::BLOOM), "ServiceFlags(BLOOM)");
+    }
+
+    #[test]
+    fn unknown_bits_survive() {
+        let flags = ServiceFlags::from_bits(1 | 8 | (1 << 24) | (1 << 63));
+        let bytes = serialize(&flags);
+        assert_eq!(bytes, flags.bits().to_le_bytes());
+        let decoded: ServiceFlags = deserialize(&bytes).unwrap();
+        assert_eq!(decoded, flags);
+        assert_eq!(decoded.bits(), 0x8000_0000_0100_0009);
+        assert_eq!(decoded.to_string(), "NETWORK|WITNESS|0x8000000001000000");
+        assert_eq!(ServiceFlags::from_bits(1 << 24).to_string(), "0x1000000");
+    }
+
+    #[test]
+    fn has_and_difference() {
+        let offered = ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::from_bits(1 << 30);
+        assert!(offered.has(ServiceFlags::NETWORK));
+        assert!(offered.has(ServiceFlags::NETWORK | ServiceFlags::WITNESS));
+        assert!(offered.has(ServiceFlags::NONE));
+        assert!(!offered.has(ServiceFlags::NETWORK | ServiceFlags::BLOOM));
+
+        let required = ServiceFlags::NETWORK | ServiceFlags::BLOOM | ServiceFlags::COMPACT_FILTERS;
+        assert_eq!(required.difference(offered), ServiceFlags::BLOOM | ServiceFlags::COMPACT_FILTERS);
+        assert!(offered.difference(offered).is_empty());
+        assert_eq!(offered.difference(ServiceFlags::NONE), offered);
+
+        let mut flags = offered;
+        flags.remove(ServiceFlags::WITNESS);
+        flags.add(ServiceFlags::P2P_V2);
+        assert_eq!(flags, ServiceFlags::NETWORK | ServiceFlags::P2P_V2 | ServiceFlags::from_bits(1 << 30));
+    }
+}
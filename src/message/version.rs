@@ -1,13 +1,28 @@
+use std::io;
+use bitcoin::consensus::{Encodable, Decodable, encode};
 use crate::message::address::Address;
+use crate::message::services::ServiceFlags;
+
+/// The protocol version we speak
+pub const PROTOCOL_VERSION: u32 = 70016;
+/// From this version on `version` carries `sender`, `nonce`, `user_agent` and `start_height`
+pub const ADDR_FROM_VERSION: u32 = 106;
+/// From this version on `version` carries `relay` (BIP37)
+pub const RELAY_VERSION: u32 = 70001;
+/// `sendaddrv2` is only sent to peers with at least this version (BIP155)
+pub const ADDRV2_VERSION: u32 = 70016;
 
 /// The `version` message
-/// 添加了一个bytes 属性
+/// [https://en.bitcoin.it/wiki/Protocol_documentation#version]
+///
+/// 以前多了一个 bytes 属性 其实就是 user_agent 的长度 String 序列化的时候已经带上了
+/// older peers leave out the trailing fields, see `ADDR_FROM_VERSION` and `RELAY_VERSION`
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct VersionMessage {
     /// The P2P network protocol version
     pub version: u32,
-    /// A bitmask describing the services supported by this node
-    pub services: u64,
+    /// The services supported by this node
+    pub services: ServiceFlags,
     /// The time at which the `version` message was sent
     pub timestamp: i64,
     /// The network address of the peer receiving the message
@@ -16,9 +31,6 @@ pub struct VersionMessage {
     pub sender: Address,
     /// A random nonce used to detect loops in the network
     pub nonce: u64,
-    /// bytes in version string
-    /// 没有出现在murmel中，也没有出现在bitcoin wiki中，但是出现在了[https://bitcoin.org/en/developer-reference#version]
-    pub bytes: u8,
     /// A string describing the peer's software
     pub user_agent: String,
     /// The height of the maximum-work blockchain that the peer is aware of
@@ -31,29 +43,90 @@ pub struct VersionMessage {
 
 impl VersionMessage {
     /// Constructs a new `version` message
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        services: u64,
+        version: u32,
+        services: ServiceFlags,
         timestamp: i64,
         receiver: Address,
         sender: Address,
         nonce: u64,
-        bytes:u8,
         user_agent: String,
         start_height: i32,
+        relay: bool,
     ) -> VersionMessage {
         VersionMessage {
-            //固定值
-            version: 70001,
-            services: services,
-            timestamp: timestamp,
-            receiver: receiver,
-            sender: sender,
-            nonce: nonce,
-            bytes,
-            user_agent: user_agent,
-            start_height: start_height,
-            relay: false,
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            user_agent,
+            start_height,
+            relay,
+        }
+    }
+}
+
+impl Encodable for VersionMessage {
+    #[inline]
+    fn consensus_encode<S: io::Write>(
+        &self,
+        mut s: S,
+    ) -> Result<usize, encode::Error> {
+        let mut len = self.version.consensus_encode(&mut s)?
+            + self.services.consensus_encode(&mut s)?
+            + self.timestamp.consensus_encode(&mut s)?
+            + self.receiver.consensus_encode(&mut s)?;
+        if self.version >= ADDR_FROM_VERSION {
+            len += self.sender.consensus_encode(&mut s)?
+                + self.nonce.consensus_encode(&mut s)?
+                + self.user_agent.consensus_encode(&mut s)?
+                + self.start_height.consensus_encode(&mut s)?;
+        }
+        if self.version >= RELAY_VERSION {
+            len += self.relay.consensus_encode(&mut s)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for VersionMessage {
+    #[inline]
+    fn consensus_decode<D: io::Read>(mut d: D) -> Result<Self, encode::Error> {
+        let version: u32 = Decodable::consensus_decode(&mut d)?;
+        let services: ServiceFlags = Decodable::consensus_decode(&mut d)?;
+        let timestamp: i64 = Decodable::consensus_decode(&mut d)?;
+        let receiver: Address = Decodable::consensus_decode(&mut d)?;
+
+        // 106 之前没有后面这些字段 给默认值
+        let mut message = VersionMessage {
+            version,
+            services,
+            timestamp,
+            receiver,
+            sender: Address { services: ServiceFlags::NONE, address: [0; 8], port: 0 },
+            nonce: 0,
+            user_agent: String::new(),
+            start_height: 0,
+            relay: true,
+        };
+        if version >= ADDR_FROM_VERSION {
+            message.sender = Decodable::consensus_decode(&mut d)?;
+            message.nonce = Decodable::consensus_decode(&mut d)?;
+            message.user_agent = Decodable::consensus_decode(&mut d)?;
+            message.start_height = Decodable::consensus_decode(&mut d)?;
+        }
+        if version >= RELAY_VERSION {
+            // Bitcoin Core also accepts a missing relay byte from 70001 peers
+            match bool::consensus_decode(&mut d) {
+                Ok(relay) => message.relay = relay,
+                Err(encode::Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                Err(e) => return Err(e),
+            }
         }
+        Ok(message)
     }
 }
 
@@ -86,7 +159,57 @@ macro_rules! impl_consensus_encoding {
     );
 }
 
-impl_consensus_encoding!(VersionMessage, version, services, timestamp,
-                         receiver, sender, nonce, bytes,
-                         user_agent, start_height, relay);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::{deserialize, serialize};
+    use crate::message::Payload;
+    use crate::message::command::CommandString;
 
+    fn address(port: u16) -> Address {
+        Address { services: ServiceFlags::NETWORK, address: [0, 0, 0, 0, 0, 0xffff, 0x0a00, 0x0001], port }
+    }
+
+    fn version(version: u32) -> VersionMessage {
+        VersionMessage::new(version, ServiceFlags::NETWORK, 1_600_000_000, address(8333), address(18333), 0x1234_5678,
+                            "/Satoshi:0.3.19/".to_owned(), 100_000, false)
+    }
+
+    #[test]
+    fn decodes_before_addr_from() {
+        let bytes = serialize(&version(ADDR_FROM_VERSION - 1));
+        // version + services + timestamp + receiver
+        assert_eq!(bytes.len(), 4 + 8 + 8 + 26);
+        let decoded: VersionMessage = deserialize(&bytes).unwrap();
+        assert_eq!(decoded.receiver, address(8333));
+        assert_eq!(decoded.sender, Address { services: ServiceFlags::NONE, address: [0; 8], port: 0 });
+        assert_eq!((decoded.nonce, decoded.user_agent.as_str(), decoded.start_height), (0, "", 0));
+        assert!(decoded.relay);
+    }
+
+    #[test]
+    fn decodes_before_relay() {
+        let message = version(60002);
+        let bytes = serialize(&message);
+        assert_eq!(bytes.len(), 4 + 8 + 8 + 26 + 26 + 8 + 17 + 4);
+        let decoded: VersionMessage = deserialize(&bytes).unwrap();
+        assert_eq!(decoded, VersionMessage { relay: true, ..message });
+    }
+
+    #[test]
+    fn missing_relay_byte() {
+        let message = version(RELAY_VERSION);
+        let mut bytes = serialize(&message);
+        assert_eq!(deserialize::<VersionMessage>(&bytes).unwrap(), message);
+        bytes.pop();
+        assert_eq!(deserialize::<VersionMessage>(&bytes).unwrap(), VersionMessage { relay: true, ..message });
+    }
+
+    #[test]
+    fn ignores_trailing_fields() {
+        let message = version(PROTOCOL_VERSION);
+        let mut bytes = serialize(&message);
+        bytes.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(Payload::decode(&CommandString("version".to_owned()), &bytes).unwrap(), Payload::Version(message));
+    }
+}
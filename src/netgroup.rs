@@ -0,0 +1,138 @@
+//! Network groups of IP addresses
+//!
+//! 和 Bitcoin Core 的 GetGroup 一样, 同一个组的地址多半在同一个运营商手里
+//!     IPv4 按 /16 分组, 6to4 / Teredo / IPv4-mapped 里面的 IPv4 地址也是
+//!     IPv6 按 /32 分组, Hurricane Electric (2001:470::/32) 按 /36
+//!     Tor / I2P / CJDNS 的地址本身是随机的 (公钥), 按前 4 位分组
+//!     本机地址一个组, 其它不可路由的地址 (内网, 文档用的网段 ...) 一个组
+//!     DNS seed 之类用名字表示的来源, 每个名字一个组
+//!
+//! Eviction protects peers from many different groups, so an attacker controlling a few ranges cannot take over
+//! all inbound slots.
+
+use std::net::{IpAddr, Ipv4Addr};
+use crate::message::addrv2::AddrV2;
+
+const NET_UNROUTABLE: u8 = 0;
+const NET_IPV4: u8 = 1;
+const NET_IPV6: u8 = 2;
+// 3 到 6 是 BIP155 的网络编号, 见 addr_netgroup
+const NET_INTERNAL: u8 = 7;
+const NET_LOCAL: u8 = 255;
+
+/// The network group of `ip`; addresses with equal groups are considered to be run by the same party
+pub fn netgroup(ip: &IpAddr) -> Vec<u8> {
+    if is_local(ip) {
+        return vec![NET_LOCAL];
+    }
+    if !is_routable(ip) {
+        return vec![NET_UNROUTABLE];
+    }
+    if let Some(v4) = embedded_ipv4(ip) {
+        let octets = v4.octets();
+        return vec![NET_IPV4, octets[0], octets[1]];
+    }
+    match *ip {
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            let mut group = vec![NET_IPV6, octets[0], octets[1], octets[2], octets[3]];
+            // he.net 把 /48 分给个人 多看 4 位
+            if octets[..4] == [0x20, 0x01, 0x04, 0x70] {
+                group.push(octets[4] & 0xf0);
+            }
+            group
+        }
+        IpAddr::V4(_) => unreachable!("IPv4 addresses are handled above"),
+    }
+}
+
+/// The network group of an address of any network
+pub fn addr_netgroup(addr: &AddrV2) -> Vec<u8> {
+    match *addr {
+        AddrV2::Ipv4(ip) => netgroup(&IpAddr::V4(ip)),
+        AddrV2::Ipv6(ip) => netgroup(&IpAddr::V6(ip)),
+        AddrV2::TorV3(ref key) | AddrV2::I2p(ref key) => vec![addr.network_id(), key[0] | 0x0f],
+        // 第一个字节总是 0xfc
+        AddrV2::Cjdns(ip) => vec![addr.network_id(), ip.octets()[0], ip.octets()[1] | 0x0f],
+        AddrV2::Unknown(..) => vec![NET_UNROUTABLE],
+    }
+}
+
+/// The group of a source known by name, like a DNS seed
+pub fn name_netgroup(name: &str) -> Vec<u8> {
+    // 和 Bitcoin Core 的内部地址一样 取名字哈希的前 10 个字节
+    let hash = hmac_sha256::Hash::hash(name.as_bytes());
+    let mut group = vec![NET_INTERNAL];
+    group.extend_from_slice(&hash[..10]);
+    group
+}
+
+/// Whether peers elsewhere could reach `addr`: routable IP addresses and the overlay networks
+pub fn addr_is_routable(addr: &AddrV2) -> bool {
+    match *addr {
+        AddrV2::Ipv4(ip) => is_routable(&IpAddr::V4(ip)),
+        AddrV2::Ipv6(ip) => is_routable(&IpAddr::V6(ip)),
+        AddrV2::TorV3(_) | AddrV2::I2p(_) | AddrV2::Cjdns(_) => true,
+        AddrV2::Unknown(..) => false,
+    }
+}
+
+/// Loopback and unspecified addresses
+pub fn is_local(ip: &IpAddr) -> bool {
+    match embedded_ipv4(ip) {
+        Some(v4) => v4.is_loopback() || v4.octets()[0] == 0,
+        None => ip.is_loopback() || ip.is_unspecified(),
+    }
+}
+
+/// Whether peers elsewhere on the internet could reach `ip`
+pub fn is_routable(ip: &IpAddr) -> bool {
+    if is_local(ip) {
+        return false;
+    }
+    if let Some(v4) = embedded_ipv4(ip) {
+        let o = v4.octets();
+        return !(v4.is_private()
+            || v4.is_link_local()
+            || v4.is_broadcast()
+            || v4.is_documentation()
+            || v4.is_multicast()
+            // RFC6598 100.64.0.0/10
+            || (o[0] == 100 && o[1] & 0xc0 == 64)
+            // RFC2544 198.18.0.0/15
+            || (o[0] == 198 && o[1] & 0xfe == 18));
+    }
+    match *ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            // RFC4193 fc00::/7, RFC4862 fe80::/64, RFC3849 2001:db8::/32, RFC4843 2001:10::/28, RFC7343 2001:20::/28
+            !((s[0] & 0xfe00) == 0xfc00
+                || (s[0] & 0xffc0) == 0xfe80
+                || (s[0] == 0x2001 && s[1] == 0x0db8)
+                || (s[0] == 0x2001 && (s[1] & 0xfff0) == 0x0010)
+                || (s[0] == 0x2001 && (s[1] & 0xfff0) == 0x0020)
+                || v6.is_multicast())
+        }
+        IpAddr::V4(_) => true,
+    }
+}
+
+/// The IPv4 address in an IPv4 address, an IPv4-mapped address, a 6to4 (RFC3964) or a Teredo (RFC4380) address
+fn embedded_ipv4(ip: &IpAddr) -> Option<Ipv4Addr> {
+    let v6 = match *ip {
+        IpAddr::V4(v4) => return Some(v4),
+        IpAddr::V6(v6) => v6,
+    };
+    let o = v6.octets();
+    if o[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+        return Some(Ipv4Addr::new(o[12], o[13], o[14], o[15]));
+    }
+    if o[..2] == [0x20, 0x02] {
+        return Some(Ipv4Addr::new(o[2], o[3], o[4], o[5]));
+    }
+    // Teredo 里的地址是取反存的
+    if o[..4] == [0x20, 0x01, 0, 0] {
+        return Some(Ipv4Addr::new(!o[12], !o[13], !o[14], !o[15]));
+    }
+    None
+}
@@ -0,0 +1,311 @@
+//! Everything that differs between the bitcoin networks, not only the magic
+//!
+//! 用法
+//!     let network = NetworkParams::mainnet();
+//!     RawMessage::new(&network, ...)
+//!     TcpStream::connect((ip, network.default_port))
+//!
+//! Values follow Bitcoin Core's chainparams.cpp.
+
+use bitcoin::BlockHeader;
+use bitcoin::util::uint::Uint256;
+use bitcoin_hashes::sha256d;
+use bitcoin_hashes::hex::FromHex;
+use crate::message::Magic;
+
+/// Parameters of one network
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct NetworkParams {
+    /// Magic put in front of every message
+    pub magic: Magic,
+    /// Port nodes of this network listen on by default
+    pub default_port: u16,
+    /// Header of the genesis block
+    pub genesis_header: BlockHeader,
+    /// Hash of the genesis block
+    pub genesis_hash: sha256d::Hash,
+    /// DNS seeds to bootstrap peer discovery
+    pub dns_seeds: Vec<String>,
+    /// Height from which the coinbase must contain the block height
+    pub bip34_height: u32,
+    /// Height from which OP_CHECKLOCKTIMEVERIFY is enforced
+    pub bip65_height: u32,
+    /// Height from which strict DER signatures are enforced
+    pub bip66_height: u32,
+    /// Height from which BIP68, BIP112 and BIP113 (CSV) are enforced
+    pub csv_height: u32,
+    /// Height from which segwit (BIP141, BIP143, BIP147) is enforced
+    pub segwit_height: u32,
+    /// Highest (easiest) target a block may have
+    pub pow_limit: Uint256,
+    /// Expected time between two blocks in seconds
+    pub pow_target_spacing: u32,
+    /// Time a difficulty period is expected to take in seconds
+    pub pow_target_timespan: u32,
+    /// Whether a block more than 20 minutes after its parent may use `pow_limit` (testnet rule)
+    pub allow_min_difficulty_blocks: bool,
+    /// Whether the difficulty never changes (regtest)
+    pub no_pow_retargeting: bool,
+    /// Whether retargeting starts from the first block of the period and the timewarp fix applies (BIP94, testnet4)
+    pub enforce_bip94: bool,
+    /// Known good (height, block hash) pairs
+    pub checkpoints: Vec<(u32, sha256d::Hash)>,
+}
+
+// 2^224 - 1, the limit of mainnet, testnet3 and testnet4
+const POW_LIMIT: Uint256 = Uint256([0xffffffffffffffff, 0xffffffffffffffff, 0xffffffffffffffff, 0x00000000ffffffff]);
+
+impl NetworkParams {
+    /// Parameters of a well-known network, `None` for `Magic::Custom`
+    pub fn from_magic(magic: Magic) -> Option<NetworkParams> {
+        match magic {
+            Magic::Main => Some(NetworkParams::mainnet()),
+            Magic::Testnet => Some(NetworkParams::testnet()),
+            Magic::Testnet4 => Some(NetworkParams::testnet4()),
+            Magic::Regtest => Some(NetworkParams::regtest()),
+            Magic::Signet => Some(NetworkParams::signet()),
+            Magic::Custom(_) => None,
+        }
+    }
+
+    pub fn mainnet() -> NetworkParams {
+        let genesis_header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"),
+            time: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+        };
+        NetworkParams {
+            magic: Magic::Main,
+            default_port: 8333,
+            genesis_header,
+            genesis_hash: hash("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            dns_seeds: seeds(&[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+            ]),
+            bip34_height: 227931,
+            bip65_height: 388381,
+            bip66_height: 363725,
+            csv_height: 419328,
+            segwit_height: 481824,
+            pow_limit: POW_LIMIT,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: false,
+            no_pow_retargeting: false,
+            enforce_bip94: false,
+            checkpoints: checkpoints(&[
+                (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+                (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+                (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+                (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+                (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+                (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+                (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+                (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+                (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+                (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+                (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+                (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+                (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+            ]),
+        }
+    }
+
+    /// testnet3
+    pub fn testnet() -> NetworkParams {
+        let genesis_header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"),
+            time: 1296688602,
+            bits: 0x1d00ffff,
+            nonce: 414098458,
+        };
+        NetworkParams {
+            magic: Magic::Testnet,
+            default_port: 18333,
+            genesis_header,
+            genesis_hash: hash("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            dns_seeds: seeds(&[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ]),
+            bip34_height: 21111,
+            bip65_height: 581885,
+            bip66_height: 330776,
+            csv_height: 770112,
+            segwit_height: 834624,
+            pow_limit: POW_LIMIT,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: true,
+            no_pow_retargeting: false,
+            enforce_bip94: false,
+            checkpoints: checkpoints(&[
+                (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+            ]),
+        }
+    }
+
+    /// testnet4 (BIP94), every soft fork is active from the start
+    pub fn testnet4() -> NetworkParams {
+        let genesis_header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: hash("7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e"),
+            time: 1714777860,
+            bits: 0x1d00ffff,
+            nonce: 393743547,
+        };
+        NetworkParams {
+            magic: Magic::Testnet4,
+            default_port: 48333,
+            genesis_header,
+            genesis_hash: hash("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+            dns_seeds: seeds(&[
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ]),
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 1,
+            pow_limit: POW_LIMIT,
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: true,
+            no_pow_retargeting: false,
+            enforce_bip94: true,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn regtest() -> NetworkParams {
+        let genesis_header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"),
+            time: 1296688602,
+            bits: 0x207fffff,
+            nonce: 2,
+        };
+        NetworkParams {
+            magic: Magic::Regtest,
+            default_port: 18444,
+            genesis_header,
+            genesis_hash: hash("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+            dns_seeds: Vec::new(),
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 0,
+            pow_limit: Uint256([0xffffffffffffffff, 0xffffffffffffffff, 0xffffffffffffffff, 0x7fffffffffffffff]),
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: true,
+            no_pow_retargeting: true,
+            enforce_bip94: false,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// The default signet
+    pub fn signet() -> NetworkParams {
+        let genesis_header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: hash("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"),
+            time: 1598918400,
+            bits: 0x1e0377ae,
+            nonce: 52613770,
+        };
+        NetworkParams {
+            magic: Magic::Signet,
+            default_port: 38333,
+            genesis_header,
+            genesis_hash: hash("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            dns_seeds: seeds(&[
+                "seed.signet.bitcoin.sprovoost.nl",
+            ]),
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 1,
+            pow_limit: Uint256([0, 0, 0, 0x00000377ae000000]),
+            pow_target_spacing: 10 * 60,
+            pow_target_timespan: 14 * 24 * 60 * 60,
+            allow_min_difficulty_blocks: false,
+            no_pow_retargeting: false,
+            enforce_bip94: false,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    /// A custom signet: same genesis and rules as the default signet, but its own magic and no seeds
+    pub fn custom_signet(magic: u32) -> NetworkParams {
+        NetworkParams {
+            magic: Magic::Custom(magic),
+            dns_seeds: Vec::new(),
+            ..NetworkParams::signet()
+        }
+    }
+
+    /// Number of blocks between two difficulty adjustments
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+
+    /// The checkpointed hash at `height`, if there is one
+    pub fn checkpoint(&self, height: u32) -> Option<sha256d::Hash> {
+        self.checkpoints.iter().find(|&&(h, _)| h == height).map(|&(_, hash)| hash)
+    }
+}
+
+// 下面都是写死的常量 解析失败就是代码写错了
+fn hash(hex: &str) -> sha256d::Hash {
+    sha256d::Hash::from_hex(hex).expect("valid hash constant")
+}
+
+fn seeds(seeds: &[&str]) -> Vec<String> {
+    seeds.iter().map(|seed| seed.to_string()).collect()
+}
+
+fn checkpoints(checkpoints: &[(u32, &str)]) -> Vec<(u32, sha256d::Hash)> {
+    checkpoints.iter().map(|&(height, hex)| (height, hash(hex))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::BitcoinHash;
+
+    #[test]
+    fn genesis_hashes() {
+        for params in &[NetworkParams::mainnet(), NetworkParams::testnet(), NetworkParams::testnet4(),
+                        NetworkParams::signet(), NetworkParams::regtest()] {
+            assert_eq!(params.genesis_header.bitcoin_hash(), params.genesis_hash, "{:?}", params.magic);
+            // 创世块满足自己的难度
+            assert!(params.genesis_header.target() <= params.pow_limit, "{:?}", params.magic);
+            assert_eq!(NetworkParams::from_magic(params.magic).as_ref(), Some(params));
+        }
+        let custom = NetworkParams::custom_signet(0x1234_5678);
+        assert_eq!(custom.genesis_hash, NetworkParams::signet().genesis_hash);
+        assert_eq!(NetworkParams::from_magic(custom.magic), None);
+    }
+}
@@ -0,0 +1,590 @@
+//! One connection to a peer, driven by its own tasks
+//!
+//! 握手完成之后连接拆成两半, 各自一个 tokio task
+//!     reader  从 socket 读消息, ping/pong 交给 keepalive, 其它的放进 inbound 队列
+//!     writer  从 outbound 队列取 Payload, 编码之后写进 socket, ping/pong 走单独的 control 队列, 先发
+//!
+//! Both queues are bounded. A peer that reads slowly fills the outbound queue and `send` waits
+//! instead of buffering without limit; a caller that reads slowly stops the reader and with it the socket.
+//! Keepalive keeps running either way, so a connection nobody reads from still times out.
+//! Whichever task stops first records why and stops the other one, see `DisconnectReason`.
+
+use std::{fmt, error};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use futures::future::{poll_fn, AbortHandle, Abortable};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use log::debug;
+use crate::codec::MessageCodec;
+use crate::error::Error;
+use crate::handshake::{handshake, respond, HandshakeConfig, Nonces, PeerInfo};
+use crate::keepalive::{Keepalive, KeepaliveConfig, PingStats};
+use crate::message::{RawMessage, Payload, Magic};
+use crate::message::registry::MessageRegistry;
+use crate::network::NetworkParams;
+
+/// Room for pings and pongs on their way to the writer
+const CONTROL_QUEUE: usize = 4;
+
+/// How to run a connection
+#[derive(Clone, Debug)]
+pub struct PeerConfig {
+    pub handshake: HandshakeConfig,
+    pub keepalive: KeepaliveConfig,
+    /// Custom message types to decode, see `MessageRegistry`
+    pub registry: MessageRegistry,
+    /// Messages waiting to be written before `send` waits
+    pub outbound_queue: usize,
+    /// Messages received but not yet taken before the reader stops reading
+    pub inbound_queue: usize,
+}
+
+impl PeerConfig {
+    pub fn new(network: &NetworkParams) -> PeerConfig {
+        PeerConfig {
+            handshake: HandshakeConfig::new(network),
+            keepalive: KeepaliveConfig::default(),
+            registry: MessageRegistry::new(),
+            outbound_queue: 64,
+            inbound_queue: 64,
+        }
+    }
+}
+
+/// Why a connection ended
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    /// We asked for it, with `Peer::disconnect` or by dropping the `Peer`
+    Requested,
+    /// The peer closed the connection
+    Closed,
+    /// The connection failed, timed out or the peer misbehaved
+    Error(Arc<Error>),
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Requested => write!(f, "disconnected by us"),
+            DisconnectReason::Closed => write!(f, "closed by the peer"),
+            DisconnectReason::Error(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for DisconnectReason {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            DisconnectReason::Error(ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// State shared by the `Peer` and its two tasks
+struct Shared {
+    reason: Mutex<Option<DisconnectReason>>,
+    /// copied from the reader's `Keepalive` after every pong
+    ping: Mutex<PingStats>,
+    reader: AbortHandle,
+    writer: AbortHandle,
+}
+
+impl Shared {
+    /// Record `reason` unless an earlier one is there, and stop both tasks
+    fn finish(&self, reason: DisconnectReason) {
+        {
+            let mut current = self.reason.lock().unwrap();
+            if current.is_none() {
+                debug!("connection ends: {}", reason);
+                *current = Some(reason);
+            }
+        }
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+/// A connected peer after a successful handshake
+///
+/// 用法
+///     let mut peer = Peer::connect(remote, &PeerConfig::new(&network), &nonces).await?;
+///     peer.send(Payload::GetAddr).await?;
+///     while let Some(message) = peer.next().await {
+///         ...
+///     }
+///     info!("disconnected: {:?}", peer.disconnect_reason());
+///
+/// `Peer` is a `Stream` of the received messages other than ping and pong. The stream ends when the
+/// connection does; dropping the `Peer` closes the connection.
+/// Blocks and transactions are accepted with witness data only if they were requested with it,
+/// e.g. with `Inventory::witness` from peers offering `ServiceFlags::WITNESS`, see `MessageCodec`.
+pub struct Peer {
+    info: PeerInfo,
+    outbound: mpsc::Sender<Payload>,
+    inbound: mpsc::Receiver<RawMessage>,
+    shared: Arc<Shared>,
+}
+
+impl Peer {
+    /// Open a TCP connection to `remote` and run the handshake on it
+    pub async fn connect(remote: SocketAddr, config: &PeerConfig, nonces: &Nonces) -> Result<Peer, Error> {
+        let stream = TcpStream::connect(&remote).await?;
+        Peer::start(stream, remote, config, nonces).await
+    }
+
+    /// Run the handshake on an established connection to `remote` and start the tasks
+    pub async fn start<T>(stream: T, remote: SocketAddr, config: &PeerConfig, nonces: &Nonces) -> Result<Peer, Error>
+        where T: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        let codec = MessageCodec::with_registry(&config.handshake.network, config.registry.clone());
+        let mut framed = Framed::new(stream, codec);
+        let info = handshake(&mut framed, remote, &config.handshake, nonces).await?;
+        Ok(Peer::spawn(framed, info, config))
+    }
+
+    /// Run the responder side of the handshake on a connection `remote` opened to us and start the tasks
+    pub async fn accept<T>(stream: T, remote: SocketAddr, config: &PeerConfig, nonces: &Nonces) -> Result<Peer, Error>
+        where T: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        let codec = MessageCodec::with_registry(&config.handshake.network, config.registry.clone());
+        let mut framed = Framed::new(stream, codec);
+        let info = respond(&mut framed, remote, &config.handshake, nonces).await?;
+        Ok(Peer::spawn(framed, info, config))
+    }
+
+    /// Start the tasks on a connection whose handshake is done
+    pub(crate) fn spawn<S>(framed: S, info: PeerInfo, config: &PeerConfig) -> Peer
+        where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Send + Unpin + 'static
+    {
+        let (sink, stream) = framed.split();
+        let (outbound, outbound_rx) = mpsc::channel(config.outbound_queue.max(1));
+        let (control, control_rx) = mpsc::channel(CONTROL_QUEUE);
+        let (inbound_tx, inbound) = mpsc::channel(config.inbound_queue.max(1));
+        let (reader, reader_registration) = AbortHandle::new_pair();
+        let (writer, writer_registration) = AbortHandle::new_pair();
+        let shared = Arc::new(Shared { reason: Mutex::new(None), ping: Mutex::new(PingStats::default()), reader, writer });
+
+        let magic = config.handshake.network.magic;
+        let keepalive = Keepalive::new(&config.handshake.network, config.keepalive.clone());
+        let reader = {
+            let shared = shared.clone();
+            async move {
+                let mut inbound_tx = inbound_tx;
+                let reason = read(stream, keepalive, &shared, control, &mut inbound_tx).await;
+                // 先记下原因再关 inbound, 不然看到流结束的一方抢先 disconnect 会把原因盖成 Requested
+                shared.finish(reason);
+                drop(inbound_tx);
+            }
+        };
+        let writer = {
+            let shared = shared.clone();
+            async move {
+                let reason = write(sink, magic, control_rx, outbound_rx).await;
+                shared.finish(reason);
+            }
+        };
+        tokio::spawn(Abortable::new(reader, reader_registration));
+        tokio::spawn(Abortable::new(writer, writer_registration));
+
+        Peer { info, outbound, inbound, shared }
+    }
+
+    /// What we learned about the peer during the handshake
+    pub fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.info.address
+    }
+
+    /// Queue `payload` for sending, waiting while the outbound queue is full.
+    /// Fails with `Error::Disconnected` once the connection is gone.
+    pub async fn send(&mut self, payload: Payload) -> Result<(), Error> {
+        self.outbound.send(payload).await.map_err(|_| Error::Disconnected)
+    }
+
+    /// Another handle on the outbound queue, e.g. for a task of its own
+    pub fn sender(&self) -> mpsc::Sender<Payload> {
+        self.outbound.clone()
+    }
+
+    /// Close the connection; messages still queued are dropped
+    pub fn disconnect(&self) {
+        self.shared.finish(DisconnectReason::Requested);
+    }
+
+    /// Why the connection ended, `None` while it is still up
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.shared.reason.lock().unwrap().clone()
+    }
+
+    /// Round-trip times of the pings so far
+    pub fn ping_stats(&self) -> PingStats {
+        self.shared.ping.lock().unwrap().clone()
+    }
+
+    /// A handle that can watch and close the connection while something else owns the `Peer`
+    pub(crate) fn monitor(&self) -> PeerMonitor {
+        PeerMonitor(self.shared.clone())
+    }
+}
+
+/// See `Peer::monitor`
+#[derive(Clone)]
+pub(crate) struct PeerMonitor(Arc<Shared>);
+
+impl PeerMonitor {
+    pub fn ping_stats(&self) -> PingStats {
+        self.0.ping.lock().unwrap().clone()
+    }
+
+    pub fn disconnect(&self) {
+        self.0.finish(DisconnectReason::Requested);
+    }
+}
+
+impl Stream for Peer {
+    type Item = RawMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<RawMessage>> {
+        self.inbound.poll_recv(cx)
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("address", &self.info.address)
+            .field("disconnect_reason", &self.disconnect_reason())
+            .finish()
+    }
+}
+
+async fn read<S>(mut stream: S, mut keepalive: Keepalive, shared: &Shared, mut control: mpsc::Sender<Payload>,
+                 inbound: &mut mpsc::Sender<RawMessage>) -> DisconnectReason
+    where S: Stream<Item = Result<RawMessage, Error>> + Unpin
+{
+    loop {
+        if let Err(reason) = tick(&mut keepalive, &mut control) {
+            return reason;
+        }
+        let message = match tokio::time::timeout_at(keepalive.deadline(), stream.next()).await {
+            // 到点了 回去发 ping 或者判断超时
+            Err(_) => continue,
+            Ok(Some(Ok(message))) => message,
+            Ok(Some(Err(ref e))) if !e.is_fatal() => {
+                debug!("skipping message: {}", e);
+                continue;
+            }
+            Ok(Some(Err(e))) => return DisconnectReason::Error(Arc::new(e)),
+            Ok(None) => return DisconnectReason::Closed,
+        };
+        match message.payload() {
+            Payload::Ping(_) | Payload::Pong(_) => {
+                if let Some(reply) = keepalive.handle(&message, Instant::now()) {
+                    if let Err(reason) = queue_control(&mut control, reply.payload().clone()) {
+                        return reason;
+                    }
+                } else {
+                    *shared.ping.lock().unwrap() = keepalive.stats().clone();
+                }
+            }
+            _ => {
+                // 队列满了就在这里等, 不再从 socket 读, 但 keepalive 照常
+                if let Err(reason) = reserve(inbound, &mut keepalive, &mut control).await {
+                    return reason;
+                }
+                if inbound.try_send(message).is_err() {
+                    return DisconnectReason::Requested;
+                }
+            }
+        }
+    }
+}
+
+/// Send a ping if one is due; fails once the pending pong is overdue
+fn tick(keepalive: &mut Keepalive, control: &mut mpsc::Sender<Payload>) -> Result<(), DisconnectReason> {
+    match keepalive.poll(Instant::now()) {
+        Ok(Some(ping)) => queue_control(control, ping.payload().clone()),
+        Ok(None) => Ok(()),
+        Err(e) => Err(DisconnectReason::Error(Arc::new(e))),
+    }
+}
+
+/// Hand a ping or pong to the writer without waiting.
+/// A full control queue means the writer is stuck on the socket; the message is dropped and the pong timeout
+/// takes care of the connection.
+fn queue_control(control: &mut mpsc::Sender<Payload>, payload: Payload) -> Result<(), DisconnectReason> {
+    match control.try_send(payload) {
+        Ok(()) => Ok(()),
+        Err(mpsc::error::TrySendError::Full(payload)) => {
+            debug!("control queue full, dropping {}", payload.command().0);
+            Ok(())
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => Err(DisconnectReason::Requested),
+    }
+}
+
+/// Wait for room in `queue`, keeping the keepalive going meanwhile
+async fn reserve<T>(queue: &mut mpsc::Sender<T>, keepalive: &mut Keepalive, control: &mut mpsc::Sender<Payload>)
+    -> Result<(), DisconnectReason>
+{
+    loop {
+        match tokio::time::timeout_at(keepalive.deadline(), poll_fn(|cx| queue.poll_ready(cx))).await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(_)) => return Err(DisconnectReason::Requested),
+            Err(_) => tick(keepalive, control)?,
+        }
+    }
+}
+
+async fn write<S>(mut sink: S, magic: Magic, mut control: mpsc::Receiver<Payload>, mut outbound: mpsc::Receiver<Payload>)
+    -> DisconnectReason
+    where S: Sink<RawMessage, Error = Error> + Unpin
+{
+    loop {
+        // ping/pong 不排在用户消息后面
+        let next = poll_fn(|cx| match control.poll_recv(cx) {
+            Poll::Ready(Some(payload)) => Poll::Ready(Some(payload)),
+            _ => outbound.poll_recv(cx),
+        });
+        let payload = match next.await {
+            Some(payload) => payload,
+            None => return DisconnectReason::Requested,
+        };
+        let message = RawMessage::with_magic(magic, payload.command(), payload);
+        match sink.send(message).await {
+            Ok(()) => {}
+            Err(ref e) if !e.is_fatal() => debug!("not sending message: {}", e),
+            Err(e) => return DisconnectReason::Error(Arc::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::services::ServiceFlags;
+    use std::task::Waker;
+    use std::time::Duration;
+    use bitcoin::consensus::serialize;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::UnixStream;
+    use crate::message::{MessageHeader, MAINNET};
+    use crate::message::address::Address;
+    use crate::message::command::CommandString;
+    use crate::message::inv::Inv;
+    use crate::message::limits::Misbehavior;
+    use crate::message::version::{VersionMessage, PROTOCOL_VERSION};
+
+    /// 内存里的连接: 对面发来的从 `incoming` 读, 写出去的记在 `Written` 里, 没 open 的时候写不出去
+    struct Pipe {
+        incoming: mpsc::Receiver<Result<RawMessage, Error>>,
+        written: Arc<Mutex<Written>>,
+    }
+
+    #[derive(Default)]
+    struct Written {
+        open: bool,
+        waker: Option<Waker>,
+        payloads: Vec<Payload>,
+    }
+
+    impl Stream for Pipe {
+        type Item = Result<RawMessage, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.incoming.poll_recv(cx)
+        }
+    }
+
+    impl Sink<RawMessage> for Pipe {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            let mut written = self.written.lock().unwrap();
+            if written.open {
+                Poll::Ready(Ok(()))
+            } else {
+                written.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: RawMessage) -> Result<(), Error> {
+            self.written.lock().unwrap().payloads.push(item.payload().clone());
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn pipe(open: bool) -> (Pipe, mpsc::Sender<Result<RawMessage, Error>>, Arc<Mutex<Written>>) {
+        let (remote, incoming) = mpsc::channel(1);
+        let written = Arc::new(Mutex::new(Written { open, ..Written::default() }));
+        (Pipe { incoming, written: written.clone() }, remote, written)
+    }
+
+    fn open(written: &Mutex<Written>) {
+        let mut written = written.lock().unwrap();
+        written.open = true;
+        if let Some(waker) = written.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn info() -> PeerInfo {
+        let address: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        let remote = VersionMessage::new(PROTOCOL_VERSION, ServiceFlags::NETWORK, 0, Address::new(&address, ServiceFlags::NONE),
+                                         Address::new(&address, ServiceFlags::NETWORK), 1, "/test/".to_owned(), 0, true);
+        PeerInfo { address, version: PROTOCOL_VERSION, remote, addrv2: false, inbound: false }
+    }
+
+    fn message(payload: Payload) -> RawMessage {
+        RawMessage::new(&NetworkParams::mainnet(), payload.command(), payload)
+    }
+
+    async fn until<F: Fn() -> bool>(condition: F) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    // 多线程才能让 reader 和消费者真的同时跑
+    #[tokio::test(threaded_scheduler)]
+    async fn misbehaving_peer_reports_misbehavior() {
+        let (local, mut remote) = UnixStream::pair().unwrap();
+        let mut peer = Peer::spawn(Framed::new(local, MessageCodec::new(&NetworkParams::mainnet())), info(), &PeerConfig::new(&NetworkParams::mainnet()));
+        // 只发一个声称 4 MB 以上的 header
+        let command = CommandString("block".to_owned());
+        let header = MessageHeader { magic: MAINNET, command, length: 4_000_001, checksum: [0; 4] };
+        remote.write_all(&serialize(&header)).await.unwrap();
+
+        assert_eq!(peer.next().await, None);
+        // 流结束之后再 disconnect 不能把原因盖掉
+        peer.disconnect();
+        match peer.disconnect_reason() {
+            Some(DisconnectReason::Error(ref e)) => match **e {
+                Error::Misbehavior(Misbehavior::OversizedMessage { ref command, size: 4_000_001, .. }) =>
+                    assert_eq!(command.as_str(), "block"),
+                ref other => panic!("expected misbehavior, got {:?}", other),
+            },
+            other => panic!("expected misbehavior, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_and_requested() {
+        let (local, remote) = UnixStream::pair().unwrap();
+        let mut peer = Peer::spawn(Framed::new(local, MessageCodec::new(&NetworkParams::mainnet())), info(), &PeerConfig::new(&NetworkParams::mainnet()));
+        drop(remote);
+        assert_eq!(peer.next().await, None);
+        peer.disconnect();
+        assert!(matches!(peer.disconnect_reason(), Some(DisconnectReason::Closed)));
+
+        let (pipe, _remote, _) = pipe(true);
+        let mut peer = Peer::spawn(pipe, info(), &PeerConfig::new(&NetworkParams::mainnet()));
+        assert!(peer.disconnect_reason().is_none());
+        peer.disconnect();
+        assert_eq!(peer.next().await, None);
+        assert!(matches!(peer.disconnect_reason(), Some(DisconnectReason::Requested)));
+        assert!(matches!(peer.send(Payload::GetAddr).await, Err(Error::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn full_inbound_queue_stops_reading() {
+        let (pipe, mut remote, written) = pipe(true);
+        let mut config = PeerConfig::new(&NetworkParams::mainnet());
+        config.inbound_queue = 2;
+        config.keepalive.timeout = Duration::from_millis(500);
+        let mut peer = Peer::spawn(pipe, info(), &config);
+
+        let mut sent = 0;
+        while sent < 10 {
+            let send = remote.send(Ok(message(Payload::GetAddr)));
+            if tokio::time::timeout(Duration::from_millis(50), send).await.is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        assert!((2..10).contains(&sent), "{} messages accepted", sent);
+
+        // 读走一条之后又能往里送
+        assert_eq!(peer.next().await, Some(message(Payload::GetAddr)));
+        let send = remote.send(Ok(message(Payload::GetAddr)));
+        assert!(tokio::time::timeout(Duration::from_millis(500), send).await.is_ok());
+
+        // 没人读 inbound, keepalive 也照样超时
+        until(|| peer.disconnect_reason().is_some()).await;
+        assert!(matches!(peer.disconnect_reason(), Some(DisconnectReason::Error(ref e)) if matches!(**e, Error::Timeout)));
+        assert!(matches!(written.lock().unwrap().payloads[0], Payload::Ping(_)));
+    }
+
+    #[tokio::test]
+    async fn full_outbound_queue_waits() {
+        let (pipe, _remote, written) = pipe(false);
+        let mut config = PeerConfig::new(&NetworkParams::mainnet());
+        config.outbound_queue = 2;
+        let mut peer = Peer::spawn(pipe, info(), &config);
+
+        let mut sent = 0;
+        while sent < 10 {
+            let send = peer.send(Payload::Inv(Inv(vec![])));
+            if tokio::time::timeout(Duration::from_millis(50), send).await.is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        assert!((2..10).contains(&sent), "{} messages accepted", sent);
+
+        open(&written);
+        until(|| written.lock().unwrap().payloads.len() == sent + 1).await;
+        assert!(peer.disconnect_reason().is_none());
+    }
+
+    #[tokio::test]
+    async fn pong_overtakes_queued_messages() {
+        let (pipe, mut remote, written) = pipe(false);
+        let mut config = PeerConfig::new(&NetworkParams::mainnet());
+        config.outbound_queue = 4;
+        let mut peer = Peer::spawn(pipe, info(), &config);
+        for _ in 0..4 {
+            peer.send(Payload::GetAddr).await.unwrap();
+        }
+        remote.send(Ok(message(Payload::Ping(7)))).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        open(&written);
+        until(|| written.lock().unwrap().payloads.len() == 6).await;
+        let payloads = written.lock().unwrap().payloads.clone();
+        let pong = payloads.iter().position(|p| *p == Payload::Pong(7)).unwrap();
+        // 最多有一条在 pong 进队列之前已经交给了 socket
+        assert!(payloads[..pong].iter().filter(|p| **p == Payload::GetAddr).count() <= 1, "{:?}", payloads);
+        assert_eq!(payloads.iter().filter(|p| matches!(p, Payload::Ping(_))).count(), 1);
+    }
+}
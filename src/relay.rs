@@ -0,0 +1,176 @@
+//! Sending our own transactions
+//!
+//! 和 Bitcoin Core 一样先发 inv 通知, 对方感兴趣会回 getdata, 再把 tx 发过去
+//!     getdata 里是 MSG_TX 就发不带 witness 的序列化
+//!     MSG_WITNESS_TX 或者 MSG_WTX 发完整的
+//!     不是我们通知过的交易回 notfound
+//!
+//! One `TxRelay` is shared by all connections, so a transaction announced to one peer
+//! can be served to any peer that asks for it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use bitcoin::{BitcoinHash, Transaction};
+use crate::message::{RawMessage, Payload, Magic};
+use crate::message::command::CommandString;
+use crate::message::getdata::GetData;
+use crate::message::inv::Inv;
+use crate::message::inventory::{Inventory, Txid, Wtxid};
+use crate::message::notfound::NotFound;
+use crate::network::NetworkParams;
+
+#[derive(Default, Debug)]
+struct Announced {
+    by_txid: HashMap<Txid, Transaction>,
+    /// wtxid -> txid
+    by_wtxid: HashMap<Wtxid, Txid>,
+}
+
+/// Transactions we announced and are ready to hand out
+///
+/// 用法
+///     let relay = TxRelay::new(&network);
+///     framed.send(relay.announce(tx)).await?;
+///     // 收到 getdata 的时候
+///     for reply in relay.handle_getdata(&getdata) {
+///         framed.send(reply).await?;
+///     }
+#[derive(Clone, Debug)]
+pub struct TxRelay {
+    magic: Magic,
+    announced: Arc<Mutex<Announced>>,
+}
+
+impl TxRelay {
+    pub fn new(network: &NetworkParams) -> TxRelay {
+        TxRelay {
+            magic: network.magic,
+            announced: Arc::new(Mutex::new(Announced::default())),
+        }
+    }
+
+    /// Remember `tx` and return the `inv` announcing it by txid
+    pub fn announce(&self, tx: Transaction) -> RawMessage {
+        let txid = tx.txid();
+        let mut announced = self.announced.lock().unwrap();
+        announced.by_wtxid.insert(tx.bitcoin_hash(), txid);
+        announced.by_txid.insert(txid, tx);
+        RawMessage::with_magic(self.magic, CommandString("inv".to_owned()), Payload::Inv(Inv(vec![Inventory::Tx(txid)])))
+    }
+
+    /// Stop serving a transaction, e.g. once it is confirmed
+    pub fn remove(&self, txid: &Txid) -> Option<Transaction> {
+        let mut announced = self.announced.lock().unwrap();
+        let tx = announced.by_txid.remove(txid)?;
+        announced.by_wtxid.remove(&tx.bitcoin_hash());
+        Some(tx)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.announced.lock().unwrap().by_txid.contains_key(txid)
+    }
+
+    /// The replies to a peer's `getdata`: one `tx` per announced transaction, followed by a `notfound`
+    /// for the requested transactions we do not have. Requests for blocks are not ours to answer and are left out.
+    pub fn handle_getdata(&self, getdata: &GetData) -> Vec<RawMessage> {
+        let announced = self.announced.lock().unwrap();
+        let mut replies = Vec::new();
+        let mut missing = Vec::new();
+        for inv in &getdata.0 {
+            let tx = match *inv {
+                Inventory::Tx(ref txid) | Inventory::WitnessTx(ref txid) => announced.by_txid.get(txid),
+                Inventory::WTx(ref wtxid) => announced.by_wtxid.get(wtxid).and_then(|txid| announced.by_txid.get(txid)),
+                _ => continue,
+            };
+            match tx {
+                Some(tx) => {
+                    let tx = if inv.is_witness() { tx.clone() } else { without_witness(tx) };
+                    replies.push(RawMessage::with_magic(self.magic, CommandString("tx".to_owned()), Payload::Tx(tx)));
+                }
+                None => missing.push(*inv),
+            }
+        }
+        if !missing.is_empty() {
+            replies.push(RawMessage::with_magic(self.magic, CommandString("notfound".to_owned()), Payload::NotFound(NotFound(missing))));
+        }
+        replies
+    }
+}
+
+/// `tx` without witness data, as sent to peers that asked with MSG_TX
+pub fn without_witness(tx: &Transaction) -> Transaction {
+    let mut tx = tx.clone();
+    for input in &mut tx.input {
+        input.witness.clear();
+    }
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{OutPoint, Script, TxIn, TxOut};
+    use bitcoin_hashes::{sha256d, Hash};
+
+    fn segwit_tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![vec![0x30, 0x44], vec![0x02, 0x21]],
+            }],
+            output: vec![TxOut { value: 50_000, script_pubkey: Script::new() }],
+        }
+    }
+
+    fn replies(relay: &TxRelay, inventory: Vec<Inventory>) -> Vec<Payload> {
+        relay.handle_getdata(&GetData(inventory)).into_iter().map(|message| message.payload().clone()).collect()
+    }
+
+    #[test]
+    fn tx_is_sent_without_witness() {
+        let relay = TxRelay::new(&NetworkParams::regtest());
+        let tx = segwit_tx();
+        relay.announce(tx.clone());
+        let replies = replies(&relay, vec![Inventory::Tx(tx.txid())]);
+        assert_eq!(replies, vec![Payload::Tx(without_witness(&tx))]);
+        assert!(!replies[0].has_witness());
+    }
+
+    #[test]
+    fn witness_tx_is_sent_whole() {
+        let relay = TxRelay::new(&NetworkParams::regtest());
+        let tx = segwit_tx();
+        relay.announce(tx.clone());
+        assert_eq!(replies(&relay, vec![Inventory::WitnessTx(tx.txid())]), vec![Payload::Tx(tx)]);
+    }
+
+    #[test]
+    fn wtx_is_found_by_wtxid() {
+        let relay = TxRelay::new(&NetworkParams::regtest());
+        let tx = segwit_tx();
+        relay.announce(tx.clone());
+        assert_ne!(tx.bitcoin_hash(), tx.txid());
+        assert_eq!(replies(&relay, vec![Inventory::WTx(tx.bitcoin_hash())]), vec![Payload::Tx(tx.clone())]);
+        // 按 txid 要 MSG_WTX 是找不到的
+        assert_eq!(replies(&relay, vec![Inventory::WTx(tx.txid())]),
+                   vec![Payload::NotFound(NotFound(vec![Inventory::WTx(tx.txid())]))]);
+    }
+
+    #[test]
+    fn unknown_tx_is_notfound() {
+        let relay = TxRelay::new(&NetworkParams::regtest());
+        let tx = segwit_tx();
+        relay.announce(tx.clone());
+        let unknown = Inventory::Tx(sha256d::Hash::hash(b"unknown"));
+        let block = Inventory::Block(sha256d::Hash::hash(b"block"));
+        assert_eq!(replies(&relay, vec![unknown, Inventory::Tx(tx.txid()), block]), vec![Payload::Tx(without_witness(&tx)), Payload::NotFound(NotFound(vec![unknown]))]);
+
+        relay.remove(&tx.txid());
+        assert_eq!(replies(&relay, vec![Inventory::Tx(tx.txid())]),
+                   vec![Payload::NotFound(NotFound(vec![Inventory::Tx(tx.txid())]))]);
+    }
+}
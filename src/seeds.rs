@@ -0,0 +1,169 @@
+//! Finding peers through DNS seeds
+//!
+//! 第一次启动的时候 addrman 是空的, 只能问 DNS seed
+//!     每个网络的 seed 在 `NetworkParams::dns_seeds`
+//!     要求的服务不为空时问 `x<服务位的十六进制>.<seed>`, 比如 x9 是 NETWORK|WITNESS, seed 只返回提供这些服务的节点
+//!     返回的 IP 配上网络的默认端口, 以 seed 为来源放进 `AddrMan`
+//!
+//! Lookups go through the `Resolver` trait, so tests and setups without DNS can answer with a `StaticResolver`.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::time::Duration;
+use futures::future::join_all;
+use rand::Rng;
+use log::{info, warn};
+use crate::addrman::{AddrMan, AddrSource};
+use crate::error::Error;
+use crate::message::addrv2::{AddrV2, AddrV2Entry};
+use crate::message::services::ServiceFlags;
+use crate::network::NetworkParams;
+
+/// Give up on a seed after this time
+const SEED_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub type ResolveFuture<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>>;
+
+/// Looks up the IP addresses of a host name
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a>;
+}
+
+/// The resolver of the operating system, run on tokio's blocking threads
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        let host = host.to_owned();
+        Box::pin(async move {
+            let lookup = tokio::task::spawn_blocking(move || (host.as_str(), 0).to_socket_addrs());
+            let addrs = lookup.await.map_err(|e| io::Error::other(e.to_string()))??;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Fixed answers, e.g. for tests
+///
+/// 用法
+///     let mut resolver = StaticResolver::new();
+///     resolver.insert("x9.seed.example", vec!["1.2.3.4".parse().unwrap()]);
+///     bootstrap(&resolver, &params, ServiceFlags::NETWORK | ServiceFlags::WITNESS, &mut addrman, now).await;
+///
+/// Unknown names fail with `io::ErrorKind::NotFound`.
+#[derive(Clone, Default, Debug)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    pub fn insert(&mut self, host: &str, addrs: Vec<IpAddr>) -> &mut StaticResolver {
+        self.hosts.insert(host.to_owned(), addrs);
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> ResolveFuture<'a> {
+        let result = self.hosts.get(host).cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("unknown host {}", host)));
+        Box::pin(async move { result })
+    }
+}
+
+/// The name to ask `seed` for nodes offering `services`
+pub fn seed_host(seed: &str, services: ServiceFlags) -> String {
+    if services.is_empty() {
+        seed.to_owned()
+    } else {
+        format!("x{:x}.{}", services.bits(), seed)
+    }
+}
+
+/// Ask one seed for nodes offering `services`; the addresses get `port`
+pub async fn query_seed<R>(resolver: &R, seed: &str, services: ServiceFlags, port: u16) -> Result<Vec<SocketAddr>, Error>
+    where R: Resolver + ?Sized
+{
+    let host = seed_host(seed, services);
+    match tokio::time::timeout(SEED_TIMEOUT, resolver.resolve(&host)).await {
+        Ok(addrs) => Ok(addrs?.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+/// Ask all seeds of `params` at once and add their answers to `addrman`, each with its seed as source.
+/// Seeds that fail are logged and skipped. Returns how many addresses were added.
+pub async fn bootstrap<R>(resolver: &R, params: &NetworkParams, services: ServiceFlags, addrman: &mut AddrMan, now: u32)
+    -> usize
+    where R: Resolver + ?Sized
+{
+    let queries = params.dns_seeds.iter().map(|seed| query_seed(resolver, seed, services, params.default_port));
+    let results = join_all(queries).await;
+
+    let mut rng = rand::thread_rng();
+    let mut added = 0;
+    for (seed, result) in params.dns_seeds.iter().zip(results) {
+        let addrs = match result {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                warn!("DNS seed {} failed: {}", seed, e);
+                continue;
+            }
+        };
+        // 和 Bitcoin Core 一样 当成 3 到 7 天前见过的
+        let entries: Vec<AddrV2Entry> = addrs.iter().map(|addr| AddrV2Entry {
+            time: now.saturating_sub(3 * 24 * 60 * 60 + rng.gen_range(0, 4 * 24 * 60 * 60)),
+            services,
+            addr: AddrV2::from_ip(addr.ip()),
+            port: addr.port(),
+        }).collect();
+        let count = addrman.add(&entries, &AddrSource::DnsSeed(seed.clone()), now);
+        info!("DNS seed {} returned {} addresses, {} added", seed, addrs.len(), count);
+        added += count;
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u32 = 1_700_000_000;
+    const DAY: u32 = 24 * 60 * 60;
+
+    #[test]
+    fn host_names() {
+        assert_eq!(seed_host("seed.example", ServiceFlags::NETWORK | ServiceFlags::WITNESS), "x9.seed.example");
+        assert_eq!(seed_host("seed.example", ServiceFlags::NETWORK), "x1.seed.example");
+        assert_eq!(seed_host("seed.example", ServiceFlags::NONE), "seed.example");
+    }
+
+    #[tokio::test]
+    async fn bootstrap_from_static_resolver() {
+        let mut params = NetworkParams::mainnet();
+        params.dns_seeds = vec!["seed.example".to_owned(), "down.example".to_owned()];
+        let ips: Vec<IpAddr> = vec!["1.2.3.4".parse().unwrap(), "2001:4860::8888".parse().unwrap()];
+        let mut resolver = StaticResolver::new();
+        // 没带 x9. 前缀的名字不会被问到
+        resolver.insert("x9.seed.example", ips.clone()).insert("seed.example", vec!["9.9.9.9".parse().unwrap()]);
+
+        let mut addrman = AddrMan::new();
+        let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+        assert_eq!(bootstrap(&resolver, &params, services, &mut addrman, NOW).await, 2);
+        assert_eq!(addrman.len(), 2);
+        for ip in ips {
+            let info = addrman.get(&SocketAddr::new(ip, 8333)).expect("added with the default port");
+            assert_eq!(info.source, AddrSource::DnsSeed("seed.example".to_owned()));
+            assert_eq!(info.services, services);
+            assert!(info.time <= NOW - 3 * DAY && info.time > NOW - 7 * DAY, "time {}", info.time);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Storage for validated block headers
+//!
+//! A `HeaderStore` keeps every header it is given together with its height and chain work,
+//! and knows which of them form the best chain. `HeaderChain` does the validation and hands
+//! the store only headers whose parent is already stored.
+//!
+//! 两种实现
+//!     MemoryStore     只在内存里 程序退出就没了
+//!     FileStore       内存索引 + 只追加的文件, 重启后从文件恢复
+//!
+//! When a header with more work than the current tip arrives on another branch, the store switches
+//! branches and reports what changed as `ChainEvent`s: first the blocks leaving the best chain, tip first,
+//! then the blocks joining it, lowest first.
+
+use std::{io, fmt, error};
+use std::collections::HashMap;
+use crate::chain::ChainEntry;
+use crate::message::inventory::BlockHash;
+
+pub mod file;
+
+pub use self::file::FileStore;
+
+/// A change of the best chain
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum ChainEvent {
+    /// The block became part of the best chain
+    Connected(ChainEntry),
+    /// The block left the best chain because of a reorg
+    Disconnected(ChainEntry),
+}
+
+/// Errors of a persistent store
+#[derive(Debug)]
+pub enum StoreError {
+    /// Reading or writing the file failed
+    Io(io::Error),
+    /// The file is not of the expected kind, or it is corrupt
+    BadFormat,
+    /// The file was written in a format version this build does not know
+    UnsupportedVersion(u32),
+    /// The file belongs to another network
+    WrongNetwork {
+        /// Magic of the network we opened the file for
+        expected: u32,
+        /// Magic stored in the file
+        actual: u32,
+    },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Io(ref e) => write!(f, "store I/O error: {}", e),
+            StoreError::BadFormat => write!(f, "store file has an unknown format or is corrupt"),
+            StoreError::UnsupportedVersion(version) => write!(f, "store file format version {} is not supported", version),
+            StoreError::WrongNetwork { expected, actual } =>
+                write!(f, "store file is for network {:#010x}, expected {:#010x}", actual, expected),
+        }
+    }
+}
+
+impl error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            StoreError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+/// Headers with their height and chain work, and which of them form the best chain
+pub trait HeaderStore {
+    /// Tip of the chain with the most work
+    fn tip(&self) -> &ChainEntry;
+
+    /// Any stored header, on the best chain or not
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<&ChainEntry>;
+
+    /// The header at `height` on the best chain
+    fn get_by_height(&self, height: u32) -> Option<&ChainEntry>;
+
+    /// Store `entry`, whose parent must already be stored. If it has more work than the tip, the best
+    /// chain switches to it and the returned events say which blocks left and joined the best chain.
+    fn insert(&mut self, entry: ChainEntry) -> Vec<ChainEvent>;
+
+    /// Make everything inserted so far durable. Errors of earlier inserts are reported here.
+    fn flush(&mut self) -> Result<(), StoreError>;
+
+    /// Whether `hash` is part of the best chain
+    fn is_active(&self, hash: &BlockHash) -> bool {
+        match self.get_by_hash(hash) {
+            Some(entry) => self.get_by_height(entry.height).map(|active| active.hash) == Some(*hash),
+            None => false,
+        }
+    }
+
+    /// The ancestor of `entry` at `height`, which must not be above `entry`
+    fn ancestor<'a>(&'a self, mut entry: &'a ChainEntry, height: u32) -> &'a ChainEntry {
+        while entry.height > height {
+            // 在主链上就可以直接按高度取
+            if self.is_active(&entry.hash) {
+                return self.get_by_height(height).expect("active chain has all heights below the tip");
+            }
+            entry = self.get_by_hash(&entry.header.prev_blockhash).expect("parents are stored before children");
+        }
+        entry
+    }
+
+    /// Block locator for the tip of the best chain
+    fn locator(&self) -> Vec<BlockHash> {
+        self.locator_from(&self.tip().hash)
+    }
+
+    /// Block locator starting at `hash`: the last 11 blocks, then exponentially fewer, always ending at genesis.
+    /// Empty if `hash` is unknown.
+    fn locator_from(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        let mut entry = match self.get_by_hash(hash) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let mut locator = Vec::new();
+        let mut step = 1;
+        loop {
+            locator.push(entry.hash);
+            if entry.height == 0 {
+                break;
+            }
+            let height = entry.height.saturating_sub(step);
+            entry = self.ancestor(entry, height);
+            if locator.len() > 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+}
+
+/// Keeps everything in memory
+#[derive(Clone, Debug)]
+pub struct MemoryStore {
+    entries: HashMap<BlockHash, ChainEntry>,
+    /// hashes of the best chain, index = height
+    active: Vec<BlockHash>,
+}
+
+impl MemoryStore {
+    /// A store holding only the genesis block
+    pub fn new(genesis: ChainEntry) -> MemoryStore {
+        let mut entries = HashMap::new();
+        let active = vec![genesis.hash];
+        entries.insert(genesis.hash, genesis);
+        MemoryStore { entries, active }
+    }
+
+    /// Number of stored headers, including side chains
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl HeaderStore for MemoryStore {
+    fn tip(&self) -> &ChainEntry {
+        &self.entries[self.active.last().expect("the genesis block is always there")]
+    }
+
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<&ChainEntry> {
+        self.entries.get(hash)
+    }
+
+    fn get_by_height(&self, height: u32) -> Option<&ChainEntry> {
+        self.active.get(height as usize).map(|hash| &self.entries[hash])
+    }
+
+    fn insert(&mut self, entry: ChainEntry) -> Vec<ChainEvent> {
+        if self.entries.contains_key(&entry.hash) {
+            return Vec::new();
+        }
+        let hash = entry.hash;
+        let better = entry.chain_work > self.tip().chain_work;
+        self.entries.insert(hash, entry);
+        if !better {
+            return Vec::new();
+        }
+
+        // 从新的 tip 往回走到和当前主链的分叉点
+        let mut branch = Vec::new();
+        let mut entry = &self.entries[&hash];
+        while !self.is_active(&entry.hash) {
+            branch.push(entry.hash);
+            entry = &self.entries[&entry.header.prev_blockhash];
+        }
+        let fork = entry.height as usize;
+
+        let mut events = Vec::new();
+        for hash in self.active.drain(fork + 1..).rev() {
+            events.push(ChainEvent::Disconnected(self.entries[&hash].clone()));
+        }
+        for hash in branch.into_iter().rev() {
+            self.active.push(hash);
+            events.push(ChainEvent::Connected(self.entries[&hash].clone()));
+        }
+        events
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{BitcoinHash, BlockHeader};
+    use crate::network::NetworkParams;
+
+    /// 不做校验的子区块, `nonce` 区分同一个父区块下的分支
+    pub(crate) fn child(parent: &ChainEntry, nonce: u32) -> ChainEntry {
+        let header = BlockHeader { prev_blockhash: parent.hash, time: parent.header.time + 600, nonce, ..parent.header };
+        ChainEntry { header, hash: header.bitcoin_hash(), height: parent.height + 1, chain_work: parent.chain_work + header.work() }
+    }
+
+    fn extend(store: &mut MemoryStore, from: &ChainEntry, count: u32, nonce: u32) -> Vec<ChainEntry> {
+        let mut entries = vec![from.clone()];
+        for _ in 0..count {
+            let entry = child(entries.last().unwrap(), nonce);
+            store.insert(entry.clone());
+            entries.push(entry);
+        }
+        entries.remove(0);
+        entries
+    }
+
+    #[test]
+    fn reorg_events() {
+        let genesis = ChainEntry::genesis(&NetworkParams::regtest());
+        let mut store = MemoryStore::new(genesis.clone());
+        let a = extend(&mut store, &genesis, 3, 0);
+        assert_eq!(store.tip(), &a[2]);
+
+        // 从 a1 分叉, b3 和 a3 一样多的工作量, 还不切换
+        let b2 = child(&a[0], 1);
+        let b3 = child(&b2, 1);
+        assert!(store.insert(b2.clone()).is_empty());
+        assert!(store.insert(b3.clone()).is_empty());
+        assert_eq!(store.tip(), &a[2]);
+        assert!(!store.is_active(&b2.hash));
+
+        let b4 = child(&b3, 1);
+        assert_eq!(store.insert(b4.clone()), vec![
+            ChainEvent::Disconnected(a[2].clone()),
+            ChainEvent::Disconnected(a[1].clone()),
+            ChainEvent::Connected(b2.clone()),
+            ChainEvent::Connected(b3.clone()),
+            ChainEvent::Connected(b4.clone()),
+        ]);
+        assert_eq!(store.tip(), &b4);
+        assert_eq!(store.get_by_height(2), Some(&b2));
+        assert!(store.is_active(&a[0].hash) && !store.is_active(&a[1].hash));
+        // 离开主链的区块还在
+        assert_eq!(store.get_by_hash(&a[2].hash), Some(&a[2]));
+        assert_eq!(store.ancestor(&a[2], 1), &a[0]);
+        assert_eq!(store.len(), 7);
+
+        // 重复的不算
+        assert!(store.insert(b4).is_empty());
+        assert_eq!(store.len(), 7);
+    }
+
+    #[test]
+    fn locator_spacing() {
+        let genesis = ChainEntry::genesis(&NetworkParams::regtest());
+        let mut store = MemoryStore::new(genesis.clone());
+        let chain = extend(&mut store, &genesis, 100, 0);
+
+        // 和 Bitcoin Core 一样: 前 11 个一个挨一个, 之后间隔翻倍, 最后是 genesis
+        let heights: Vec<u32> = store.locator().iter().map(|hash| store.get_by_hash(hash).unwrap().height).collect();
+        assert_eq!(heights, vec![100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 89, 87, 83, 75, 59, 27, 0]);
+        assert_eq!(store.locator().last(), Some(&genesis.hash));
+
+        // 从侧链开始的 locator 先沿着侧链往回走
+        let side = extend(&mut store, &chain[49], 3, 1);
+        let locator = store.locator_from(&side[2].hash);
+        assert_eq!(&locator[..4], &[side[2].hash, side[1].hash, side[0].hash, chain[49].hash]);
+        assert_eq!(locator.last(), Some(&genesis.hash));
+
+        assert!(store.locator_from(&Default::default()).is_empty());
+        assert_eq!(MemoryStore::new(genesis.clone()).locator(), vec![genesis.hash]);
+    }
+}
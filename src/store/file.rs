@@ -0,0 +1,302 @@
+//! `HeaderStore` backed by an append-only file
+//!
+//! 文件格式
+//!     "BHDR" + 格式版本(u32) + 网络 magic(u32)
+//!     然后是一条条定长记录: header(80) + height(4) + chain_work(32) + checksum(4)
+//!     checksum 是前面 116 个字节的 SHA256(SHA256()) 的前四个字节
+//!
+//! Records are only ever appended, parents before children, and `flush` syncs them to disk.
+//! If the process dies in the middle of a write, the torn record at the end fails its checksum
+//! and is cut off the next time the file is opened, so the store falls back to the last flushed state.
+//! The best chain is not written down: replaying the records in order rebuilds it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use bitcoin::{BitcoinHash, BlockHeader};
+use bitcoin::consensus::{serialize, deserialize};
+use bitcoin::util::uint::Uint256;
+use log::warn;
+use crate::chain::ChainEntry;
+use crate::message::sha_sha;
+use crate::message::inventory::BlockHash;
+use crate::network::NetworkParams;
+use crate::store::{HeaderStore, MemoryStore, ChainEvent, StoreError};
+
+const FILE_MAGIC: &[u8; 4] = b"BHDR";
+const FORMAT_VERSION: u32 = 1;
+const FILE_HEADER_SIZE: u64 = 12;
+const RECORD_SIZE: usize = 80 + 4 + 32 + 4;
+
+/// Headers kept in memory and appended to a file
+///
+/// 用法
+///     let store = FileStore::open("headers.dat", &network)?;
+///     let mut chain = HeaderChain::with_store(network, store);
+///     ... chain.accept(header, now)? ...
+///     chain.flush()?;
+pub struct FileStore {
+    index: MemoryStore,
+    file: File,
+    /// records inserted but not yet written
+    pending: Vec<u8>,
+    /// length of the file up to the last successful flush
+    committed: u64,
+}
+
+impl FileStore {
+    /// Open the store at `path`, creating it if it does not exist or is empty, and load every intact record.
+    /// A non-empty file too short for the file header is `StoreError::BadFormat`.
+    pub fn open<P: AsRef<Path>>(path: P, params: &NetworkParams) -> Result<FileStore, StoreError> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let magic = params.magic.to_u32();
+        let len = file.metadata()?.len();
+        if len == 0 {
+            // 新文件
+            file.write_all(FILE_MAGIC)?;
+            file.write_all(&serialize(&FORMAT_VERSION))?;
+            file.write_all(&serialize(&magic))?;
+            file.sync_all()?;
+        } else if len < FILE_HEADER_SIZE {
+            // 不是我们的文件, 或者文件头都没写完, 都不能当成空文件覆盖掉
+            return Err(StoreError::BadFormat);
+        } else {
+            let mut header = [0u8; FILE_HEADER_SIZE as usize];
+            file.read_exact(&mut header)?;
+            let version: u32 = deserialize(&header[4..8]).map_err(|_| StoreError::BadFormat)?;
+            if &header[..4] != FILE_MAGIC {
+                return Err(StoreError::BadFormat);
+            }
+            if version != FORMAT_VERSION {
+                return Err(StoreError::UnsupportedVersion(version));
+            }
+            let actual: u32 = deserialize(&header[8..12]).map_err(|_| StoreError::BadFormat)?;
+            if actual != magic {
+                return Err(StoreError::WrongNetwork { expected: magic, actual });
+            }
+        }
+
+        let mut index = MemoryStore::new(ChainEntry::genesis(params));
+        let mut committed = FILE_HEADER_SIZE;
+        {
+            let mut reader = BufReader::new(&mut file);
+            reader.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
+            let mut record = [0u8; RECORD_SIZE];
+            loop {
+                match reader.read_exact(&mut record) {
+                    Ok(()) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e.into()),
+                }
+                match decode_record(&record, &index) {
+                    Some(entry) => {
+                        index.insert(entry);
+                        committed += RECORD_SIZE as u64;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        let len = file.metadata()?.len();
+        if len > committed {
+            warn!("dropping {} bytes of incomplete or corrupt headers at the end of the header store", len - committed);
+            file.set_len(committed)?;
+            file.sync_all()?;
+        }
+        Ok(FileStore { index, file, pending: Vec::new(), committed })
+    }
+}
+
+fn encode_record(entry: &ChainEntry) -> Vec<u8> {
+    let mut record = serialize(&entry.header);
+    record.extend(serialize(&entry.height));
+    record.extend(serialize(&entry.chain_work));
+    let checksum = sha_sha(&record);
+    record.extend(checksum);
+    record
+}
+
+/// `None` if the record is torn or does not fit onto the headers before it
+fn decode_record(record: &[u8; RECORD_SIZE], index: &MemoryStore) -> Option<ChainEntry> {
+    if sha_sha(&record[..RECORD_SIZE - 4])[..] != record[RECORD_SIZE - 4..] {
+        return None;
+    }
+    let header: BlockHeader = deserialize(&record[..80]).ok()?;
+    let height: u32 = deserialize(&record[80..84]).ok()?;
+    let chain_work: Uint256 = deserialize(&record[84..116]).ok()?;
+    let parent = index.get_by_hash(&header.prev_blockhash)?;
+    if parent.height + 1 != height {
+        return None;
+    }
+    Some(ChainEntry { hash: header.bitcoin_hash(), header, height, chain_work })
+}
+
+impl HeaderStore for FileStore {
+    fn tip(&self) -> &ChainEntry {
+        self.index.tip()
+    }
+
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<&ChainEntry> {
+        self.index.get_by_hash(hash)
+    }
+
+    fn get_by_height(&self, height: u32) -> Option<&ChainEntry> {
+        self.index.get_by_height(height)
+    }
+
+    fn insert(&mut self, entry: ChainEntry) -> Vec<ChainEvent> {
+        if self.index.get_by_hash(&entry.hash).is_some() {
+            return Vec::new();
+        }
+        self.pending.extend(encode_record(&entry));
+        self.index.insert(entry)
+    }
+
+    fn flush(&mut self) -> Result<(), StoreError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        // 上次写失败的话文件末尾可能有半条记录 从最后一次成功的位置重新写
+        self.file.seek(SeekFrom::Start(self.committed))?;
+        self.file.write_all(&self.pending)?;
+        self.file.sync_data()?;
+        self.committed += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to flush header store: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::store::tests::child;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bitcoin_p2p-{}-{}.dat", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// 主链 4 个区块, 从高度 1 分出去一条 2 个区块的侧链
+    fn fill(store: &mut FileStore) -> Vec<ChainEntry> {
+        let mut main = vec![store.tip().clone()];
+        for _ in 0..4 {
+            let entry = child(main.last().unwrap(), 0);
+            store.insert(entry.clone());
+            main.push(entry);
+        }
+        let side = child(&main[1], 1);
+        store.insert(side.clone());
+        store.insert(child(&side, 1));
+        main
+    }
+
+    #[test]
+    fn short_file_is_not_overwritten() {
+        let path = std::env::temp_dir().join(format!("bitcoin_p2p-short-{}.dat", std::process::id()));
+        fs::write(&path, b"not ours").unwrap();
+        assert!(matches!(FileStore::open(&path, &NetworkParams::regtest()), Err(StoreError::BadFormat)));
+        assert_eq!(fs::read(&path).unwrap(), b"not ours");
+
+        fs::write(&path, b"").unwrap();
+        assert!(FileStore::open(&path, &NetworkParams::regtest()).is_ok());
+        assert_eq!(fs::metadata(&path).unwrap().len(), FILE_HEADER_SIZE);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_gives_the_same_chain() {
+        let path = temp_path("reload");
+        let params = NetworkParams::regtest();
+        let (tip, locator) = {
+            let mut store = FileStore::open(&path, &params).unwrap();
+            let main = fill(&mut store);
+            store.flush().unwrap();
+            assert_eq!(store.tip(), &main[4]);
+            (store.tip().clone(), store.locator())
+        };
+        assert_eq!(fs::metadata(&path).unwrap().len(), FILE_HEADER_SIZE + 6 * RECORD_SIZE as u64);
+
+        let store = FileStore::open(&path, &params).unwrap();
+        assert_eq!(store.tip(), &tip);
+        assert_eq!(store.locator(), locator);
+        assert_eq!(store.index.len(), 7);
+        // 文件没有被截断
+        assert_eq!(fs::metadata(&path).unwrap().len(), FILE_HEADER_SIZE + 6 * RECORD_SIZE as u64);
+        drop(store);
+
+        assert!(matches!(FileStore::open(&path, &NetworkParams::mainnet()), Err(StoreError::WrongNetwork { .. })));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_record_is_cut_off() {
+        let path = temp_path("torn");
+        let params = NetworkParams::regtest();
+        let main = {
+            let mut store = FileStore::open(&path, &params).unwrap();
+            let main = fill(&mut store);
+            store.flush().unwrap();
+            main
+        };
+        let len = fs::metadata(&path).unwrap().len();
+
+        // 写了半条记录进程就死了
+        let next = encode_record(&child(&main[4], 0));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&next[..RECORD_SIZE / 2]).unwrap();
+        drop(file);
+
+        let mut store = FileStore::open(&path, &params).unwrap();
+        assert_eq!(store.tip(), &main[4]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // 截断之后还能接着写
+        let entry = child(&main[4], 0);
+        store.insert(entry.clone());
+        store.flush().unwrap();
+        drop(store);
+        assert_eq!(FileStore::open(&path, &params).unwrap().tip(), &entry);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_is_cut_off() {
+        let path = temp_path("corrupt");
+        let params = NetworkParams::regtest();
+        let main = {
+            let mut store = FileStore::open(&path, &params).unwrap();
+            let mut main = vec![store.tip().clone()];
+            for _ in 0..3 {
+                let entry = child(main.last().unwrap(), 0);
+                store.insert(entry.clone());
+                main.push(entry);
+            }
+            main
+        };
+
+        // 最后一条记录的 checksum 对不上
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - RECORD_SIZE;
+        bytes[last + 10] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let store = FileStore::open(&path, &params).unwrap();
+        assert_eq!(store.tip(), &main[2]);
+        assert!(store.get_by_hash(&main[3].hash).is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), last as u64);
+        drop(store);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+//! Headers-first synchronization
+//!
+//! 流程
+//!     发 getheaders, locator 从我们的 tip 开始
+//!     对方回最多 2000 个 header, 逐个校验后加进 HeaderChain
+//!     刚好 2000 个说明后面还有, 从这一批的最后一个继续要
+//!     不足 2000 个就同步完了
+//!     每一批处理完都 flush 一次 header store
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bitcoin::{BitcoinHash, BlockHeader};
+use futures::{Sink, SinkExt, Stream};
+use log::debug;
+use crate::chain::{HeaderChain, Accepted};
+use crate::error::Error;
+use crate::store::{HeaderStore, ChainEvent};
+use crate::keepalive::Keepalive;
+use crate::message::{RawMessage, Payload};
+use crate::message::command::CommandString;
+use crate::message::getheaders::GetHeaders;
+use crate::message::headers::MAX_HEADERS_RESULTS;
+use crate::message::inventory::BlockHash;
+use crate::network::NetworkParams;
+
+/// Download headers from the peer behind `framed` until it has nothing more than we do.
+/// Returns the number of new headers; the best chain of `chain` is updated as they arrive
+/// and every change of it is passed to `on_event`.
+///
+/// Messages other than `headers`, ping and pong received meanwhile are dropped.
+///
+/// 用法
+///     let mut chain = HeaderChain::new(network.clone());
+///     sync_headers(&mut framed, &mut keepalive, &mut chain, Duration::from_secs(60), |event| ...).await?;
+///     info!("tip {} at {}", chain.tip().hash, chain.height());
+pub async fn sync_headers<S, H, F>(framed: &mut S, keepalive: &mut Keepalive, chain: &mut HeaderChain<H>, timeout: Duration,
+                                   mut on_event: F) -> Result<usize, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin,
+          H: HeaderStore,
+          F: FnMut(&ChainEvent)
+{
+    let mut locator = chain.locator();
+    let mut added = 0;
+    loop {
+        framed.send(getheaders(chain.params(), locator)).await?;
+        let headers = match tokio::time::timeout(timeout, next_headers(framed, keepalive)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Error::Timeout),
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+        let mut last: Option<BlockHash> = None;
+        for header in &headers {
+            if let Some(last) = last {
+                if header.prev_blockhash != last {
+                    return Err(Error::ProtocolViolation(format!("header {} does not follow the previous one", header.bitcoin_hash())));
+                }
+            }
+            match chain.accept(*header, now)? {
+                Accepted::Duplicate => {}
+                Accepted::SideChain => added += 1,
+                Accepted::BestChain(events) => {
+                    added += 1;
+                    events.iter().for_each(&mut on_event);
+                }
+            }
+            last = Some(header.bitcoin_hash());
+        }
+        chain.flush()?;
+        debug!("received {} headers, best height {}", headers.len(), chain.height());
+
+        match last {
+            // 对方可能在侧链上 从这一批的最后一个接着要
+            Some(last) if headers.len() == MAX_HEADERS_RESULTS => locator = chain.locator_from(&last),
+            _ => return Ok(added),
+        }
+    }
+}
+
+fn getheaders(network: &NetworkParams, locator: Vec<BlockHash>) -> RawMessage {
+    RawMessage::new(network, CommandString("getheaders".to_owned()), Payload::GetHeaders(GetHeaders::new(locator, Default::default())))
+}
+
+async fn next_headers<S>(framed: &mut S, keepalive: &mut Keepalive) -> Result<Vec<BlockHeader>, Error>
+    where S: Stream<Item = Result<RawMessage, Error>> + Sink<RawMessage, Error = Error> + Unpin
+{
+    loop {
+        match keepalive.next(framed).await {
+            Ok(Some(message)) => match message.payload() {
+                Payload::Headers(headers) => return Ok(headers.0.clone()),
+                _ => debug!("skipping {:?} during header sync", message.command().0),
+            },
+            Ok(None) => return Err(Error::Disconnected),
+            Err(ref e) if !e.is_fatal() => debug!("skipping message during header sync: {}", e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use crate::keepalive::KeepaliveConfig;
+    use crate::message::headers::Headers;
+    use crate::network::NetworkParams;
+
+    /// 内存里的对端, 按 locator 从 `headers` 里回 header
+    struct FakePeer {
+        genesis: BlockHash,
+        headers: Vec<BlockHeader>,
+        replies: VecDeque<RawMessage>,
+        locators: Vec<Vec<BlockHash>>,
+    }
+
+    impl FakePeer {
+        fn answer(&mut self, locator: &[BlockHash]) -> Vec<BlockHeader> {
+            let start = locator.iter().find_map(|hash| {
+                if *hash == self.genesis {
+                    Some(0)
+                } else {
+                    self.headers.iter().position(|header| header.bitcoin_hash() == *hash).map(|i| i + 1)
+                }
+            }).unwrap_or(0);
+            self.headers[start..].iter().take(MAX_HEADERS_RESULTS).cloned().collect()
+        }
+    }
+
+    impl Stream for FakePeer {
+        type Item = Result<RawMessage, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match self.replies.pop_front() {
+                Some(reply) => Poll::Ready(Some(Ok(reply))),
+                // 回复都是在 send 的时候放进去的, 这里不会再有新的了
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl Sink<RawMessage> for FakePeer {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: RawMessage) -> Result<(), Error> {
+            if let Payload::GetHeaders(ref getheaders) = *item.payload() {
+                self.locators.push(getheaders.locator_hashes.clone());
+                let headers = self.answer(&getheaders.locator_hashes);
+                let reply = Payload::Headers(Headers(headers));
+                self.replies.push_back(RawMessage::with_magic(item.magic(), reply.command(), reply));
+            }
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// `count` 个 regtest 的 header, 接在 genesis 后面
+    fn regtest_headers(params: &NetworkParams, count: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(count);
+        let (mut prev, mut time) = (params.genesis_hash, params.genesis_header.time);
+        for _ in 0..count {
+            time += 60;
+            let mut header = BlockHeader { version: 4, prev_blockhash: prev, merkle_root: Default::default(), time, bits: 0x207fffff, nonce: 0 };
+            while header.validate_pow(&header.target()).is_err() {
+                header.nonce += 1;
+            }
+            prev = header.bitcoin_hash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn continues_after_a_full_batch() {
+        let params = NetworkParams::regtest();
+        let headers = regtest_headers(&params, MAX_HEADERS_RESULTS + 5);
+        let mut peer = FakePeer { genesis: params.genesis_hash, headers: headers.clone(), replies: VecDeque::new(), locators: Vec::new() };
+        let mut keepalive = Keepalive::new(&params, KeepaliveConfig::default());
+        let mut chain = HeaderChain::new(params);
+
+        let mut connected = 0;
+        let added = sync_headers(&mut peer, &mut keepalive, &mut chain, Duration::from_secs(5), |event| {
+            assert!(matches!(event, ChainEvent::Connected(_)));
+            connected += 1;
+        }).await.unwrap();
+        assert_eq!((added, connected), (MAX_HEADERS_RESULTS + 5, MAX_HEADERS_RESULTS + 5));
+        assert_eq!(chain.tip().hash, headers.last().unwrap().bitcoin_hash());
+        // 第二次从第一批的最后一个接着要, 第二批不满就停
+        assert_eq!(peer.locators.len(), 2);
+        assert_eq!(peer.locators[1][0], headers[MAX_HEADERS_RESULTS - 1].bitcoin_hash());
+
+        // 再同步一次什么都没有
+        let added = sync_headers(&mut peer, &mut keepalive, &mut chain, Duration::from_secs(5), |_| {}).await.unwrap();
+        assert_eq!(added, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_headers_that_do_not_connect() {
+        let params = NetworkParams::regtest();
+        let mut headers = regtest_headers(&params, 3);
+        headers.remove(1);
+        let mut peer = FakePeer { genesis: params.genesis_hash, headers, replies: VecDeque::new(), locators: Vec::new() };
+        let mut keepalive = Keepalive::new(&params, KeepaliveConfig::default());
+        let mut chain = HeaderChain::new(params);
+        match sync_headers(&mut peer, &mut keepalive, &mut chain, Duration::from_secs(5), |_| {}).await {
+            Err(Error::ProtocolViolation(_)) => {}
+            other => panic!("expected a protocol violation, got {:?}", other),
+        }
+        assert_eq!(chain.height(), 1);
+    }
+}